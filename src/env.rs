@@ -1,9 +1,12 @@
-use crate::secrets::client::AwsClient;
+use crate::secrets::appconfig::{APPCONFIG_PREFIX, AppConfigRef};
+use crate::secrets::client::{AwsClient, AwsConfig};
+use crate::secrets::error::Error;
+use crate::secrets::paramstore::{parameter_selector, validate_selector};
 use crate::secrets::{MAX_LOOKUP_LEN, PARAM_STORE_SERVICE, SECRETS_MANAGER_SERVICE};
 use regex::Regex;
 use rotel::aws_api::arn::AwsArn;
-use rotel::aws_api::creds::AwsCreds;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use tokio::time::Instant;
 use tower::BoxError;
 use tracing::{debug, warn};
@@ -11,43 +14,169 @@ use tracing::{debug, warn};
 pub struct EnvArnParser {
     arn_sub_re: Regex,
     secret_prefix_re: Regex,
+    param_prefix_re: Regex,
+    secret_list_prefix_re: Regex,
+    secret_optional_prefix_re: Regex,
+    appconfig_prefix_re: Regex,
+    excluded: HashSet<String>,
+    included: HashSet<String>,
+    max_secrets: usize,
 }
 
+/// Secret references allowed in the environment unless ROTEL_MAX_SECRETS is set
+pub const DEFAULT_MAX_SECRETS: usize = 100;
+
 impl EnvArnParser {
     pub fn new() -> Self {
         Self {
             arn_sub_re: Regex::new(r"\$\{(arn:[^}]+)}").unwrap(),
             secret_prefix_re: Regex::new(r"^secret://(arn:.+)$").unwrap(),
+            param_prefix_re: Regex::new(r"^param://(arn:.+)$").unwrap(),
+            secret_list_prefix_re: Regex::new(r"^secret-list://(.+)$").unwrap(),
+            secret_optional_prefix_re: Regex::new(r"^secret-optional://(arn:.+)$").unwrap(),
+            appconfig_prefix_re: Regex::new(r"^(appconfig://.+)$").unwrap(),
+            excluded: std::env::var("ROTEL_SECRET_SCAN_EXCLUDE")
+                .map(|v| parse_var_names(v.as_str()))
+                .unwrap_or_default(),
+            included: std::env::var("ROTEL_RESOLVE_VARS")
+                .map(|v| parse_var_names(v.as_str()))
+                .unwrap_or_default(),
+            max_secrets: max_secrets_from_env(),
+        }
+    }
+
+    /// Fails when more secrets are referenced than ROTEL_MAX_SECRETS allows, as that many is
+    /// more likely a misconfiguration than intended, and each batch of lookups adds to the
+    /// cold start
+    pub fn check_secret_count(&self, arns: &HashMap<String, String>) -> Result<(), BoxError> {
+        if arns.len() > self.max_secrets {
+            return Err(format!(
+                "Environment references {} secrets, more than the {} allowed by ROTEL_MAX_SECRETS",
+                arns.len(),
+                self.max_secrets
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fails when a param:// reference is not a Parameter Store ARN, so a misplaced
+    /// Secrets Manager ARN is caught before any lookup is made
+    pub fn check_param_references(&self) -> Result<(), BoxError> {
+        for (k, v) in std::env::vars() {
+            if !self.should_scan(k.as_str()) {
+                continue;
+            }
+            if let Some(capture) = self.param_prefix_re.captures(v.as_str()) {
+                check_param_arn(capture.get(1).unwrap().as_str())
+                    .map_err(|e| format!("Invalid param:// reference in {}: {}", k, e))?;
+            }
         }
+        Ok(())
+    }
+
+    // ROTEL_ variables and any listed in ROTEL_RESOLVE_VARS are scanned, minus any listed in
+    // ROTEL_SECRET_SCAN_EXCLUDE
+    fn should_scan(&self, key: &str) -> bool {
+        (key.starts_with("ROTEL_") || self.included.contains(key)) && !self.excluded.contains(key)
     }
 
     pub fn extract_arns_from_env(&self) -> HashMap<String, String> {
-        let mut sec_subs = HashMap::new();
+        let (required, optional) = self.scan_env();
+        required
+            .into_iter()
+            .chain(optional)
+            .map(|arn| (arn, "".to_string()))
+            .collect()
+    }
+
+    /// ARNs referenced only with secret-optional://, these resolve to an empty value
+    /// when the secret does not exist
+    pub fn optional_arns_from_env(&self) -> HashSet<String> {
+        let (required, optional) = self.scan_env();
+        optional.difference(&required).cloned().collect()
+    }
+
+    /// Every secret referenced by the environment, parsed and sorted, without resolving any
+    /// of them. Intended for auditing which secrets a deployment depends on. AppConfig
+    /// profiles are not addressed by ARN and are not included.
+    pub fn secret_references(&self) -> Result<Vec<AwsArn>, BoxError> {
+        parse_secret_references(
+            self.extract_arns_from_env()
+                .keys()
+                .map(|arn| arn.as_str())
+                .filter(|arn| !arn.starts_with(APPCONFIG_PREFIX)),
+        )
+    }
+
+    // Required and optional ARNs referenced by the scanned variables
+    fn scan_env(&self) -> (HashSet<String>, HashSet<String>) {
+        let mut required = HashSet::new();
+        let mut optional = HashSet::new();
         for (k, v) in std::env::vars() {
-            if !k.starts_with("ROTEL_") {
+            if !self.should_scan(k.as_str()) {
                 continue;
             }
 
             // Check for ${arn:...} format
             for capture in self.arn_sub_re.captures_iter(v.as_str()) {
                 let matched = capture.get(1).unwrap().as_str().to_string();
-                sec_subs.insert(matched, "".to_string());
+                required.insert(matched);
             }
 
             // Check for secret://arn:... format
             if let Some(capture) = self.secret_prefix_re.captures(v.as_str()) {
                 let matched = capture.get(1).unwrap().as_str().to_string();
-                sec_subs.insert(matched, "".to_string());
+                required.insert(matched);
+            }
+
+            // Check for param://arn:... format
+            if let Some(capture) = self.param_prefix_re.captures(v.as_str()) {
+                let matched = capture.get(1).unwrap().as_str().to_string();
+                required.insert(matched);
+            }
+
+            // Check for secret-list://arn:...,arn:... format
+            for arn in self.secret_list_arns(v.as_str()) {
+                required.insert(arn.to_string());
+            }
+
+            // Check for secret-optional://arn:... format
+            if let Some(capture) = self.secret_optional_prefix_re.captures(v.as_str()) {
+                let matched = capture.get(1).unwrap().as_str().to_string();
+                optional.insert(matched);
+            }
+
+            // Check for appconfig://application/environment/profile format, the whole
+            // reference is the key
+            if let Some(capture) = self.appconfig_prefix_re.captures(v.as_str()) {
+                let matched = capture.get(1).unwrap().as_str().to_string();
+                required.insert(matched);
             }
         }
 
-        sec_subs
+        (required, optional)
+    }
+
+    // ARNs of a secret-list:// value, in the order they are listed
+    fn secret_list_arns<'a>(&self, value: &'a str) -> Vec<&'a str> {
+        match self.secret_list_prefix_re.captures(value) {
+            None => vec![],
+            Some(capture) => capture
+                .get(1)
+                .unwrap()
+                .as_str()
+                .split(',')
+                .map(|arn| arn.trim())
+                .filter(|arn| arn.starts_with("arn:"))
+                .collect(),
+        }
     }
 
     pub fn update_env_arn_secrets(&self, arn_map: HashMap<String, String>) {
         let mut updates = HashMap::new();
         for (k, v) in std::env::vars() {
-            if !k.starts_with("ROTEL_") {
+            if !self.should_scan(k.as_str()) {
                 continue;
             }
 
@@ -74,6 +203,41 @@ impl EnvArnParser {
                 }
             }
 
+            // Handle param://arn:... format
+            if let Some(capture) = self.param_prefix_re.captures(result.as_str()) {
+                let matched = capture.get(1).unwrap().as_str();
+                if let Some(param_value) = arn_map.get(matched) {
+                    result = param_value.clone();
+                }
+            }
+
+            // Handle secret-optional://arn:... format, a missing secret resolves to empty
+            if let Some(capture) = self.secret_optional_prefix_re.captures(result.as_str()) {
+                let matched = capture.get(1).unwrap().as_str();
+                if let Some(secret_value) = arn_map.get(matched) {
+                    result = secret_value.clone();
+                }
+            }
+
+            // Handle appconfig://... format
+            if let Some(capture) = self.appconfig_prefix_re.captures(result.as_str()) {
+                let matched = capture.get(1).unwrap().as_str();
+                if let Some(config) = arn_map.get(matched) {
+                    result = config.clone();
+                }
+            }
+
+            // Handle secret-list://arn:...,arn:... format, replaced with a JSON array of
+            // the values only if every ARN resolved
+            let arns = self.secret_list_arns(result.as_str());
+            if !arns.is_empty() {
+                let values: Option<Vec<&String>> =
+                    arns.iter().map(|arn| arn_map.get(*arn)).collect();
+                if let Some(values) = values {
+                    result = serde_json::to_string(&values).unwrap();
+                }
+            }
+
             if v != result {
                 updates.insert(k, result);
             }
@@ -85,17 +249,165 @@ impl EnvArnParser {
     }
 }
 
-pub async fn resolve_secrets(
-    aws_creds: AwsCreds,
+fn check_param_arn(arn_str: &str) -> Result<(), String> {
+    let arn = arn_str.parse::<AwsArn>().map_err(|e| e.to_string())?;
+    if arn.service() != PARAM_STORE_SERVICE {
+        return Err(format!(
+            "{} is a {} ARN, param:// only supports {} ARNs",
+            arn_str,
+            arn.service(),
+            PARAM_STORE_SERVICE
+        ));
+    }
+    Ok(())
+}
+
+// Parses referenced ARNs, dropping any VersionId and JSON field so each secret is listed once
+fn parse_secret_references<'a>(
+    arns: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<AwsArn>, BoxError> {
+    let mut refs = HashMap::new();
+    for arn_str in arns {
+        let (base_arn, _) = split_version_id(arn_str);
+        let arn = base_arn
+            .parse::<AwsArn>()
+            .map_err(|e| format!("Invalid secret ARN {}: {}", arn_str, e))?;
+        let arn = arn.set_resource_field("".to_string());
+        refs.insert(arn.to_string(), arn);
+    }
+
+    let mut refs: Vec<(String, AwsArn)> = refs.into_iter().collect();
+    refs.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(refs.into_iter().map(|(_, arn)| arn).collect())
+}
+
+fn max_secrets_from_env() -> usize {
+    let Ok(v) = std::env::var("ROTEL_MAX_SECRETS") else {
+        return DEFAULT_MAX_SECRETS;
+    };
+    match v.trim().parse() {
+        Ok(max) => max,
+        Err(_) => {
+            warn!(
+                value = v,
+                default = DEFAULT_MAX_SECRETS,
+                "Invalid ROTEL_MAX_SECRETS, using the default"
+            );
+            DEFAULT_MAX_SECRETS
+        }
+    }
+}
+
+// Comma-separated variable names, surrounding whitespace and empty entries are ignored
+fn parse_var_names(vars: &str) -> HashSet<String> {
+    vars.split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
+
+// Splits a `?versionId=<id>` suffix, which may precede a `#json-key`, off of a secret ARN
+fn split_version_id(arn: &str) -> (String, Option<String>) {
+    let Some(start) = arn.find(VERSION_ID_PARAM) else {
+        return (arn.to_string(), None);
+    };
+
+    let rest = &arn[start + VERSION_ID_PARAM.len()..];
+    let (version, field) = match rest.find('#') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    (
+        format!("{}{}", &arn[..start], field),
+        Some(version.to_string()),
+    )
+}
+
+const VERSION_ID_PARAM: &str = "?versionId=";
+
+/// Resolve the value of every secret reference in `arns`, returned by reference. The process
+/// environment is not modified, so this can be used to resolve secrets outside of the
+/// extension.
+pub async fn resolve_secrets_map<I, S>(
+    aws_config: AwsConfig,
+    arns: I,
+) -> Result<HashMap<String, String>, BoxError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    resolve_secrets_map_with_optional(aws_config, arns, &HashSet::new()).await
+}
+
+/// Like [`resolve_secrets_map`], but references in `optional` that do not exist resolve to
+/// an empty value with a warning rather than failing.
+pub async fn resolve_secrets_map_with_optional<I, S>(
+    aws_config: AwsConfig,
+    arns: I,
+    optional: &HashSet<String>,
+) -> Result<HashMap<String, String>, BoxError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let client = AwsClient::from_config(aws_config)?;
+    resolve_secrets_map_with_client(&client, arns, optional).await
+}
+
+async fn resolve_secrets_map_with_client<I, S>(
+    client: &AwsClient,
+    arns: I,
+    optional: &HashSet<String>,
+) -> Result<HashMap<String, String>, BoxError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut resolved = arns
+        .into_iter()
+        .map(|arn| (arn.into(), String::new()))
+        .collect();
+    resolve_secrets_with_client(client, &mut resolved, optional).await?;
+    Ok(resolved)
+}
+
+// Secrets are fetched from the region in their ARN, which may not be intended when it differs
+// from the function's region
+fn is_cross_region(arn: &AwsArn, function_region: Option<&str>) -> bool {
+    function_region.is_some_and(|region| !region.is_empty() && arn.region() != region)
+}
+
+async fn resolve_secrets_with_client(
+    client: &AwsClient,
     secure_arns: &mut HashMap<String, String>,
+    optional: &HashSet<String>,
 ) -> Result<(), BoxError> {
     let secrets_start = Instant::now();
 
-    let client = AwsClient::new(aws_creds)?;
+    let function_region = std::env::var("AWS_REGION").ok();
 
     let mut arns_by_svc = HashMap::new();
+    // Secrets pinned to a VersionId can not be batched, they are fetched one at a time
+    let mut versioned = vec![];
+    // AppConfig profiles are fetched from the function's region, one session each
+    let mut appconfig = vec![];
     for (arn_str, _) in secure_arns.iter() {
-        let arn = arn_str.parse::<AwsArn>()?;
+        if arn_str.starts_with(APPCONFIG_PREFIX) {
+            appconfig.push((arn_str.clone(), AppConfigRef::parse(arn_str)?));
+            continue;
+        }
+
+        let (base_arn, version_id) = split_version_id(arn_str);
+        let arn = base_arn.parse::<AwsArn>()?;
+
+        if is_cross_region(&arn, function_region.as_deref()) {
+            warn!(
+                arn = base_arn,
+                function_region = function_region.as_deref().unwrap_or_default(),
+                "Secret is in a different region than the function, fetching it adds latency"
+            );
+        }
 
         if arn.service() != SECRETS_MANAGER_SERVICE && arn.service() != PARAM_STORE_SERVICE {
             return Err(format!("Unknown secret ARN service name: {}", arn.service()).into());
@@ -109,16 +421,35 @@ pub async fn resolve_secrets(
             .into());
         }
 
+        if arn.service() == PARAM_STORE_SERVICE {
+            if let Some(selector) = parameter_selector(&base_arn) {
+                validate_selector(selector)
+                    .map_err(|e| format!("Invalid parameter selector in {}: {}", base_arn, e))?;
+            }
+        }
+
         // This should never happen, but avoid silent bugs later
-        if arn.to_string() != *arn_str {
+        if arn.to_string() != base_arn {
             return Err(format!(
                 "ARN value did not match input string: {} != {}",
                 arn.to_string(),
-                arn_str
+                base_arn
             )
             .into());
         }
 
+        if let Some(version_id) = version_id {
+            if arn.service() != SECRETS_MANAGER_SERVICE {
+                return Err(format!(
+                    "VersionId selection is only supported for secrets manager: {}",
+                    arn_str
+                )
+                .into());
+            }
+            versioned.push((arn_str.clone(), arn, version_id));
+            continue;
+        }
+
         let arn_without_field = arn.clone().set_resource_field("".to_string());
 
         arns_by_svc
@@ -136,104 +467,583 @@ pub async fn resolve_secrets(
             .collect::<Vec<AwsArn>>()
             .chunks(MAX_LOOKUP_LEN)
         {
-            if svc == SECRETS_MANAGER_SERVICE {
-                let sm = client.secrets_manager();
-
-                match sm.batch_get_secret(arn_chunk).await {
-                    Ok(res) => {
-                        for (arn, secret) in res {
-                            let aws_arn = arn.parse::<AwsArn>()?;
-                            match arns_by_base.get(&aws_arn) {
-                                None => {
-                                    return Err(format!(
-                                        "Returned secret ARN was not found: {}",
-                                        arn
-                                    )
-                                    .into());
-                                }
-                                Some(entry) => {
-                                    for full_arn in entry {
-                                        if full_arn.resource_field() == "" {
+            // Missing optional ARNs are dropped and the rest of the chunk looked up again
+            let mut chunk = arn_chunk.to_vec();
+            while !chunk.is_empty() {
+                if svc == SECRETS_MANAGER_SERVICE {
+                    let sm = client.secrets_manager();
+
+                    match sm.batch_get_secret(&chunk).await {
+                        Ok(res) => {
+                            for (arn, secret) in res {
+                                let aws_arn = arn.parse::<AwsArn>()?;
+                                match arns_by_base.get(&aws_arn) {
+                                    None => {
+                                        return Err(format!(
+                                            "Returned secret ARN was not found: {}",
+                                            arn
+                                        )
+                                        .into());
+                                    }
+                                    Some(entry) => {
+                                        for full_arn in entry {
                                             secure_arns.insert(
                                                 full_arn.to_string(),
-                                                secret.secret_string.clone(),
+                                                secret_value(full_arn, &secret.secret_string)?,
                                             );
-                                            continue;
-                                        }
-
-                                        match serde_json::from_str::<HashMap<String, String>>(
-                                            secret.secret_string.as_str(),
-                                        ) {
-                                            Ok(json) => match json.get(full_arn.resource_field()) {
-                                                None => return Err(format!(
-                                                    "Secret JSON did not contain field {}: {:?}",
-                                                    full_arn.resource_field(),
-                                                    full_arn
-                                                )
-                                                .into()),
-                                                Some(value) => {
-                                                    secure_arns.insert(
-                                                        full_arn.to_string(),
-                                                        value.to_string(),
-                                                    );
-                                                }
-                                            },
-                                            Err(_) => {
-                                                return Err(format!(
-                                                    "Unable to parse secret string as JSON: {:?}",
-                                                    full_arn
-                                                )
-                                                .into());
-                                            }
                                         }
                                     }
                                 }
                             }
+                            break;
+                        }
+                        Err(Error::InvalidSecrets(ids))
+                            if missing_optional(&ids, &arns_by_base, optional) =>
+                        {
+                            resolve_missing_optional(&ids, &arns_by_base, secure_arns);
+                            chunk.retain(|arn| !ids.contains(&arn.to_string()));
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Unable to resolve ARNs from secrets manager: {:?}: {:?}",
+                                chunk, err,
+                            );
+                            return Err(lookup_error(
+                                "Unable to resolve ARNs from secrets manager",
+                                &err,
+                            ));
                         }
                     }
-                    Err(err) => {
-                        warn!(
-                            "Unable to resolve ARNs from secrets manager: {:?}: {:?}",
-                            arn_chunk, err,
-                        );
-                        return Err("Unable to resolve ARNs from secrets manager".into());
-                    }
-                }
-            } else {
-                let ps = client.parameter_store();
+                } else {
+                    let ps = client.parameter_store();
 
-                match ps.get_parameters(arn_chunk).await {
-                    Ok(res) => {
-                        for (arn, param) in res {
-                            secure_arns.insert(arn, param.value);
+                    match ps.get_parameters(&chunk).await {
+                        Ok(res) => {
+                            for (arn, param) in res {
+                                secure_arns.insert(arn, param.value);
+                            }
+                            break;
+                        }
+                        Err(Error::InvalidSecrets(ids))
+                            if missing_optional(&ids, &arns_by_base, optional) =>
+                        {
+                            resolve_missing_optional(&ids, &arns_by_base, secure_arns);
+                            chunk.retain(|arn| !ids.contains(&arn.to_string()));
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Unable to resolve ARNs from parameter store: {:?}: {:?}",
+                                chunk, err,
+                            );
+                            return Err(lookup_error(
+                                "Unable to resolve ARNs from parameter store",
+                                &err,
+                            ));
                         }
-                    }
-                    Err(err) => {
-                        warn!(
-                            "Unable to resolve ARNs from parameter store: {:?}: {:?}",
-                            arn_chunk, err,
-                        );
-                        return Err("Unable to resolve ARNs from parameter store".into());
                     }
                 }
             }
         }
     }
 
+    for (arn_str, arn, version_id) in versioned {
+        let arn_without_field = arn.clone().set_resource_field("".to_string());
+        match client
+            .secrets_manager()
+            .get_secret_value(&arn_without_field, &version_id)
+            .await
+        {
+            Ok(secret) => {
+                secure_arns.insert(arn_str, secret_value(&arn, &secret.secret_string)?);
+            }
+            Err(err) => {
+                warn!(
+                    "Unable to resolve ARN version from secrets manager: {}: {:?}",
+                    arn_str, err,
+                );
+                return Err(lookup_error(
+                    "Unable to resolve ARNs from secrets manager",
+                    &err,
+                ));
+            }
+        }
+    }
+
+    if !appconfig.is_empty() {
+        let region = function_region
+            .as_deref()
+            .filter(|region| !region.is_empty())
+            .ok_or("AWS_REGION must be set to resolve AppConfig references")?;
+
+        for (ref_str, reference) in appconfig {
+            match client
+                .appconfig()
+                .get_configuration(&reference, region)
+                .await
+            {
+                Ok(config) => {
+                    secure_arns.insert(ref_str, config);
+                }
+                Err(err) => {
+                    warn!(
+                        "Unable to resolve configuration from AppConfig: {}: {:?}",
+                        ref_str, err,
+                    );
+                    return Err(lookup_error(
+                        "Unable to resolve configuration from AppConfig",
+                        &err,
+                    ));
+                }
+            }
+        }
+    }
+
+    let conn_stats = client.connection_stats();
     debug!(
+        new_connections = conn_stats.new_connections,
+        reused_connections = conn_stats.reused_connections,
         "Resolved all secrets in {} ms",
         Instant::now().duration_since(secrets_start).as_millis()
     );
     Ok(())
 }
 
+/// A secret lookup that failed on the service or the way to it, throttling, a server error
+/// or the network, rather than on a reference that is invalid or does not exist
+#[derive(Debug)]
+pub struct LookupError(pub String);
+
+impl Display for LookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+fn lookup_error(message: &str, err: &Error) -> BoxError {
+    if err.is_rejected() {
+        message.into()
+    } else {
+        Box::new(LookupError(message.to_string()))
+    }
+}
+
+// Whether every ARN that failed to resolve is only referenced as optional
+fn missing_optional(
+    ids: &[String],
+    arns_by_base: &HashMap<AwsArn, Vec<AwsArn>>,
+    optional: &HashSet<String>,
+) -> bool {
+    !ids.is_empty()
+        && ids.iter().all(|id| {
+            id.parse::<AwsArn>()
+                .ok()
+                .and_then(|arn| arns_by_base.get(&arn))
+                .is_some_and(|entry| entry.iter().all(|a| optional.contains(&a.to_string())))
+        })
+}
+
+fn resolve_missing_optional(
+    ids: &[String],
+    arns_by_base: &HashMap<AwsArn, Vec<AwsArn>>,
+    secure_arns: &mut HashMap<String, String>,
+) {
+    warn!(arns = ?ids, "Optional secrets were not found, resolving to empty values");
+    for id in ids {
+        let entry = id
+            .parse::<AwsArn>()
+            .ok()
+            .and_then(|arn| arns_by_base.get(&arn));
+        for full_arn in entry.into_iter().flatten() {
+            secure_arns.insert(full_arn.to_string(), "".to_string());
+        }
+    }
+}
+
+// The whole secret string, or a top-level field of it when the ARN has a #json-key
+fn secret_value(full_arn: &AwsArn, secret_string: &str) -> Result<String, BoxError> {
+    if full_arn.resource_field() == "" {
+        return Ok(secret_string.to_string());
+    }
+
+    match serde_json::from_str::<HashMap<String, String>>(secret_string) {
+        Ok(json) => match json.get(full_arn.resource_field()) {
+            None => Err(format!(
+                "Secret JSON did not contain field {}: {:?}",
+                full_arn.resource_field(),
+                full_arn
+            )
+            .into()),
+            Some(value) => Ok(value.to_string()),
+        },
+        Err(_) => Err(format!("Unable to parse secret string as JSON: {:?}", full_arn).into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use rotel::aws_api::creds::AwsCreds;
+    use crate::env::{
+        EnvArnParser, LookupError, is_cross_region, parse_secret_references, resolve_secrets_map,
+        resolve_secrets_map_with_client, resolve_secrets_with_client, split_version_id,
+    };
+    use crate::secrets::client::{AwsClient, AwsConfig, StubTransport};
+    use crate::test_util::{ScopedEnv, env_lock, init_crypto, parse_test_arns, test_creds};
+    use rotel::aws_api::arn::AwsArn;
+    use serde_json::json;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_is_cross_region() {
+        let arn: AwsArn = "arn:aws:secretsmanager:eu-west-1:123456789012:secret:otlp-key"
+            .parse()
+            .unwrap();
+
+        assert!(is_cross_region(&arn, Some("us-east-1")));
+        assert!(!is_cross_region(&arn, Some("eu-west-1")));
+        // Without a function region there is nothing to compare against
+        assert!(!is_cross_region(&arn, None));
+        assert!(!is_cross_region(&arn, Some("")));
+    }
+
+    #[test]
+    fn test_parse_secret_references() {
+        let refs = parse_secret_references([
+            "arn:aws:ssm:us-west-2:123456789012:parameter/api-key",
+            "arn:aws:secretsmanager:us-east-1:123456789012:secret:creds-AbCdEf#user",
+            "arn:aws:secretsmanager:us-east-1:123456789012:secret:creds-AbCdEf#password",
+            "arn:aws:secretsmanager:us-east-1:123456789012:secret:creds-AbCdEf?versionId=v-1",
+        ])
+        .unwrap();
+
+        let listed: Vec<(String, String, String)> = refs
+            .iter()
+            .map(|arn| {
+                (
+                    arn.service().to_string(),
+                    arn.region().to_string(),
+                    arn.to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "secretsmanager".to_string(),
+                    "us-east-1".to_string(),
+                    "arn:aws:secretsmanager:us-east-1:123456789012:secret:creds-AbCdEf".to_string()
+                ),
+                (
+                    "ssm".to_string(),
+                    "us-west-2".to_string(),
+                    "arn:aws:ssm:us-west-2:123456789012:parameter/api-key".to_string()
+                ),
+            ],
+            listed
+        );
+
+        assert!(parse_secret_references(["arn:invalid"]).is_err());
+    }
+
+    #[test]
+    fn test_split_version_id() {
+        let arn = "arn:aws:secretsmanager:us-east-1:123456789012:secret:test-abc123";
+        assert_eq!((arn.to_string(), None), split_version_id(arn));
+        assert_eq!(
+            (arn.to_string(), Some("v-1".to_string())),
+            split_version_id(&format!("{}?versionId=v-1", arn))
+        );
+        assert_eq!(
+            (format!("{}#password", arn), Some("v-1".to_string())),
+            split_version_id(&format!("{}?versionId=v-1#password", arn))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_version_pinned_secret() {
+        let arn = "arn:aws:secretsmanager:us-east-1:123456789012:secret:test-abc123";
+        let pinned = "arn:aws:secretsmanager:us-east-1:123456789012:secret:pinned-abc123";
+        let pinned_key = format!("{}?versionId=v-1#password", pinned);
+
+        let batch = json!({
+            "Errors": [],
+            "SecretValues": [{
+                "ARN": arn,
+                "CreatedDate": 1.7e9,
+                "Name": "test",
+                "SecretString": "latest",
+                "VersionId": "v-2",
+            }],
+        })
+        .to_string();
+        let single = json!({
+            "ARN": pinned,
+            "CreatedDate": 1.7e9,
+            "Name": "pinned",
+            "SecretString": r#"{"password": "hunter2"}"#,
+            "VersionId": "v-1",
+        })
+        .to_string();
+        let stub = Arc::new(StubTransport::new(vec![(200, &batch), (200, &single)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+
+        let mut arns = HashMap::new();
+        arns.insert(arn.to_string(), "".to_string());
+        arns.insert(pinned_key.clone(), "".to_string());
+        resolve_secrets_with_client(&client, &mut arns, &HashSet::new())
+            .await
+            .unwrap();
+
+        assert_eq!("latest", arns[arn]);
+        assert_eq!("hunter2", arns[&pinned_key]);
+
+        // The pinned secret is fetched on its own, with its VersionId
+        assert_eq!(
+            vec![
+                "secretsmanager.BatchGetSecretValue",
+                "secretsmanager.GetSecretValue"
+            ],
+            *stub.targets.lock().unwrap()
+        );
+        let req: serde_json::Value =
+            serde_json::from_slice(&stub.requests.lock().unwrap()[1]).unwrap();
+        assert_eq!(json!({"SecretId": pinned, "VersionId": "v-1"}), req);
+
+        // Parameter store has no VersionId selection
+        let mut arns = HashMap::new();
+        arns.insert(
+            "arn:aws:ssm:us-east-1:123456789012:parameter/test?versionId=v-1".to_string(),
+            "".to_string(),
+        );
+        assert!(
+            resolve_secrets_with_client(&client, &mut arns, &HashSet::new())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_parameter_selectors() {
+        let labeled = "arn:aws:ssm:us-east-1:123456789012:parameter/test:prod";
+        let pinned = "arn:aws:ssm:us-east-1:123456789012:parameter/test:3";
+        let body = json!({
+            "InvalidParameters": [],
+            "Parameters": [
+                {
+                    "ARN": "arn:aws:ssm:us-east-1:123456789012:parameter/test",
+                    "Name": "test",
+                    "Selector": ":prod",
+                    "Type": "String",
+                    "Value": "labeled",
+                },
+                {
+                    "ARN": "arn:aws:ssm:us-east-1:123456789012:parameter/test",
+                    "Name": "test",
+                    "Selector": ":3",
+                    "Type": "String",
+                    "Value": "pinned",
+                },
+            ],
+        })
+        .to_string();
+        let stub = Arc::new(StubTransport::new(vec![(200, &body)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+
+        let mut arns = HashMap::from([
+            (labeled.to_string(), "".to_string()),
+            (pinned.to_string(), "".to_string()),
+        ]);
+        resolve_secrets_with_client(&client, &mut arns, &HashSet::new())
+            .await
+            .unwrap();
+        assert_eq!("labeled", arns[labeled]);
+        assert_eq!("pinned", arns[pinned]);
+
+        // Invalid selectors fail before any request is made
+        let mut arns = HashMap::from([(
+            "arn:aws:ssm:us-east-1:123456789012:parameter/test:aws-prod".to_string(),
+            "".to_string(),
+        )]);
+        assert!(
+            resolve_secrets_with_client(&client, &mut arns, &HashSet::new())
+                .await
+                .is_err()
+        );
+        assert_eq!(1, stub.requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_secrets_map() {
+        let arn = "arn:aws:ssm:us-east-1:123456789012:parameter/resolve-map";
+        let optional = "arn:aws:ssm:us-east-1:123456789012:parameter/resolve-map-missing";
+        unsafe { std::env::set_var("RESOLVE_MAP_SECRET", format!("${{{}}}", arn)) }
+
+        let body = json!({
+            "InvalidParameters": [{"Name": optional}],
+            "Parameters": [{
+                "ARN": arn,
+                "Name": "resolve-map",
+                "Type": "String",
+                "Value": "value",
+            }],
+        })
+        .to_string();
+        let found = json!({
+            "InvalidParameters": [],
+            "Parameters": [{
+                "ARN": arn,
+                "Name": "resolve-map",
+                "Type": "String",
+                "Value": "value",
+            }],
+        })
+        .to_string();
+        let stub = Arc::new(StubTransport::new(vec![(200, &body), (200, &found)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+
+        let resolved = resolve_secrets_map_with_client(
+            &client,
+            [arn, optional],
+            &HashSet::from([optional.to_string()]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            HashMap::from([
+                (arn.to_string(), "value".to_string()),
+                (optional.to_string(), "".to_string()),
+            ]),
+            resolved
+        );
+
+        // The variable referencing the secret is left as it was
+        assert_eq!(
+            format!("${{{}}}", arn),
+            std::env::var("RESOLVE_MAP_SECRET").unwrap()
+        );
+        unsafe { std::env::remove_var("RESOLVE_MAP_SECRET") }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_appconfig() {
+        let _lock = env_lock();
+        let _region = ScopedEnv::set("AWS_REGION", "us-east-1");
+
+        let arn = "arn:aws:ssm:us-east-1:123456789012:parameter/test";
+        let reference = "appconfig://my-app/prod/flags";
+
+        let params = json!({
+            "InvalidParameters": [],
+            "Parameters": [{
+                "ARN": arn,
+                "Name": "test",
+                "Type": "String",
+                "Value": "value",
+            }],
+        })
+        .to_string();
+        let session = json!({"InitialConfigurationToken": "token-1"}).to_string();
+        let stub = Arc::new(StubTransport::new(vec![
+            (200, &params),
+            (201, &session),
+            (200, "flags: on"),
+        ]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+
+        let mut arns = HashMap::new();
+        arns.insert(arn.to_string(), "".to_string());
+        arns.insert(reference.to_string(), "".to_string());
+        resolve_secrets_with_client(&client, &mut arns, &HashSet::new())
+            .await
+            .unwrap();
+
+        assert_eq!("value", arns[arn]);
+        assert_eq!("flags: on", arns[reference]);
+        assert_eq!(3, stub.requests.lock().unwrap().len());
 
-    use crate::env::{EnvArnParser, resolve_secrets};
-    use crate::test_util::{init_crypto, parse_test_arns};
-    use std::collections::HashMap;
+        // Malformed references fail before any request is made
+        let mut arns = HashMap::new();
+        arns.insert("appconfig://my-app/flags".to_string(), "".to_string());
+        assert!(
+            resolve_secrets_with_client(&client, &mut arns, &HashSet::new())
+                .await
+                .is_err()
+        );
+        assert_eq!(3, stub.requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_missing_optional_secret() {
+        let present = "arn:aws:ssm:us-east-1:123456789012:parameter/present";
+        let missing = "arn:aws:ssm:us-east-1:123456789012:parameter/missing";
+
+        let partial = json!({
+            "InvalidParameters": [{"Name": missing}],
+            "Parameters": [],
+        })
+        .to_string();
+        let found = json!({
+            "InvalidParameters": [],
+            "Parameters": [{
+                "ARN": present,
+                "Name": "present",
+                "Type": "String",
+                "Value": "value",
+            }],
+        })
+        .to_string();
+
+        let arns = HashMap::from([
+            (present.to_string(), "".to_string()),
+            (missing.to_string(), "".to_string()),
+        ]);
+
+        // Optional: warn, resolve to empty and look up the remaining parameters
+        let stub = Arc::new(StubTransport::new(vec![(200, &partial), (200, &found)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+        let mut resolved = arns.clone();
+        let optional = HashSet::from([missing.to_string()]);
+        resolve_secrets_with_client(&client, &mut resolved, &optional)
+            .await
+            .unwrap();
+        assert_eq!("value", resolved[present]);
+        assert_eq!("", resolved[missing]);
+        assert_eq!(2, stub.requests.lock().unwrap().len());
+
+        // Required: the lookup fails, and is not a service failure
+        let stub = Arc::new(StubTransport::new(vec![(200, &partial)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub));
+        let mut resolved = arns.clone();
+        let err = resolve_secrets_with_client(&client, &mut resolved, &HashSet::new())
+            .await
+            .unwrap_err();
+        assert!(!err.is::<LookupError>());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_service_failure() {
+        let arn = "arn:aws:ssm:us-east-1:123456789012:parameter/present";
+
+        // Server errors are retried, then fail as a lookup error
+        let stub = Arc::new(StubTransport::new(vec![(500, "internal"); 3]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+        let mut resolved = HashMap::from([(arn.to_string(), "".to_string())]);
+        let err = resolve_secrets_with_client(&client, &mut resolved, &HashSet::new())
+            .await
+            .unwrap_err();
+        assert!(err.is::<LookupError>());
+        assert_eq!(3, stub.requests.lock().unwrap().len());
+
+        // A rejected request is not
+        let body = r#"{"__type":"AccessDeniedException","Message":"not authorized"}"#;
+        let stub = Arc::new(StubTransport::new(vec![(400, body)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub));
+        let err = resolve_secrets_with_client(&client, &mut resolved, &HashSet::new())
+            .await
+            .unwrap_err();
+        assert!(!err.is::<LookupError>());
+    }
 
     #[test]
     fn test_extract_and_update_arns_from_env() {
@@ -243,21 +1053,44 @@ mod tests {
         unsafe { std::env::set_var("ROTEL_ALREADY_EXISTS", "Bearer ${arn:test2}") }
         unsafe { std::env::set_var("ROTEL_WONT_UPDATE", "empty:${arn:test4}") }
         unsafe { std::env::set_var("ROTEL_SECRET_PREFIX", "secret://arn:test5") }
+        unsafe { std::env::set_var("ROTEL_SECRET_LIST", "secret-list://arn:test6, arn:test1") }
+        unsafe { std::env::set_var("ROTEL_LOG_TEMPLATE", "resolving ${arn:test7}") }
+        unsafe { std::env::set_var("ROTEL_OPTIONAL", "secret-optional://arn:test8") }
+        unsafe { std::env::set_var("ROTEL_OPTIONAL_REQUIRED", "secret-optional://arn:test1") }
+        unsafe { std::env::set_var("ROTEL_APPCONFIG", "appconfig://my-app/prod/flags") }
+        unsafe { std::env::set_var("ROTEL_SECRET_SCAN_EXCLUDE", "ROTEL_LOG_TEMPLATE, ") }
 
         let es = EnvArnParser::new();
         let mut hm = es.extract_arns_from_env();
 
-        assert_eq!(5, hm.len());
+        assert_eq!(8, hm.len());
         assert!(hm.contains_key("arn:test1"));
         assert!(hm.contains_key("arn:test2"));
         assert!(hm.contains_key("arn:test3"));
         assert!(hm.contains_key("arn:test4"));
         assert!(hm.contains_key("arn:test5"));
+        assert!(hm.contains_key("arn:test6"));
+        assert!(!hm.contains_key("arn:test7"));
+        assert!(hm.contains_key("arn:test8"));
+        assert!(hm.contains_key("appconfig://my-app/prod/flags"));
+
+        // arn:test1 is also referenced as required, so only arn:test8 is optional
+        assert_eq!(
+            HashSet::from(["arn:test8".to_string()]),
+            es.optional_arns_from_env()
+        );
 
         hm.insert("arn:test1".to_string(), "result-1".to_string());
         hm.insert("arn:test2".to_string(), "result-2".to_string());
         hm.insert("arn:test3".to_string(), "result-3".to_string());
         hm.insert("arn:test5".to_string(), "secret-result".to_string());
+        hm.insert("arn:test6".to_string(), "result-\"6\"".to_string());
+        hm.insert("arn:test7".to_string(), "result-7".to_string());
+        hm.insert("arn:test8".to_string(), "".to_string());
+        hm.insert(
+            "appconfig://my-app/prod/flags".to_string(),
+            r#"{"enabled":true}"#.to_string(),
+        );
 
         es.update_env_arn_secrets(hm);
 
@@ -274,12 +1107,135 @@ mod tests {
             std::env::var("ROTEL_SECRET_PREFIX").unwrap()
         );
 
+        assert_eq!("", std::env::var("ROTEL_OPTIONAL").unwrap());
+        assert_eq!(
+            "result-1",
+            std::env::var("ROTEL_OPTIONAL_REQUIRED").unwrap()
+        );
+
+        assert_eq!(
+            r#"{"enabled":true}"#,
+            std::env::var("ROTEL_APPCONFIG").unwrap()
+        );
+
+        unsafe { std::env::remove_var("ROTEL_APPCONFIG") }
+        unsafe { std::env::remove_var("ROTEL_OPTIONAL") }
+        unsafe { std::env::remove_var("ROTEL_OPTIONAL_REQUIRED") }
         unsafe { std::env::remove_var("ROTEL_DONT_EXPAND") }
         unsafe { std::env::remove_var("ROTEL_SINGLE") }
         unsafe { std::env::remove_var("ROTEL_MULTI") }
         unsafe { std::env::remove_var("ROTEL_ALREADY_EXISTS") }
         unsafe { std::env::remove_var("ROTEL_WONT_UPDATE") }
+        assert_eq!(
+            r#"["result-\"6\"","result-1"]"#,
+            std::env::var("ROTEL_SECRET_LIST").unwrap()
+        );
+
+        // Excluded variables are left untouched even when the ARN resolved
+        assert_eq!(
+            "resolving ${arn:test7}",
+            std::env::var("ROTEL_LOG_TEMPLATE").unwrap()
+        );
+
         unsafe { std::env::remove_var("ROTEL_SECRET_PREFIX") }
+        unsafe { std::env::remove_var("ROTEL_SECRET_LIST") }
+        unsafe { std::env::remove_var("ROTEL_LOG_TEMPLATE") }
+        unsafe { std::env::remove_var("ROTEL_SECRET_SCAN_EXCLUDE") }
+    }
+
+    #[test]
+    fn test_check_secret_count() {
+        let es = EnvArnParser {
+            max_secrets: 3,
+            ..EnvArnParser::new()
+        };
+        let arns = |n: usize| -> HashMap<String, String> {
+            (0..n)
+                .map(|i| (format!("arn:test{}", i), "".to_string()))
+                .collect()
+        };
+
+        assert!(es.check_secret_count(&arns(2)).is_ok());
+        assert!(es.check_secret_count(&arns(3)).is_ok());
+
+        let err = es.check_secret_count(&arns(4)).unwrap_err();
+        assert_eq!(
+            "Environment references 4 secrets, more than the 3 allowed by ROTEL_MAX_SECRETS",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_param_prefix() {
+        let ssm_arn = "arn:aws:ssm:us-east-1:123456789012:parameter/api-key";
+        unsafe { std::env::set_var("PARAM_PREFIX_SSM", format!("param://{}", ssm_arn)) }
+        unsafe {
+            std::env::set_var(
+                "PARAM_PREFIX_SECRETSMANAGER",
+                "param://arn:aws:secretsmanager:us-east-1:123456789012:secret:api-key-AbCdEf",
+            )
+        }
+
+        let es = EnvArnParser {
+            included: HashSet::from(["PARAM_PREFIX_SSM".to_string()]),
+            ..EnvArnParser::new()
+        };
+        assert!(es.check_param_references().is_ok());
+        let hm = es.extract_arns_from_env();
+        assert!(hm.contains_key(ssm_arn));
+
+        es.update_env_arn_secrets(HashMap::from([(
+            ssm_arn.to_string(),
+            "param-result".to_string(),
+        )]));
+        assert_eq!("param-result", std::env::var("PARAM_PREFIX_SSM").unwrap());
+
+        let es = EnvArnParser {
+            included: HashSet::from(["PARAM_PREFIX_SECRETSMANAGER".to_string()]),
+            ..EnvArnParser::new()
+        };
+        let err = es.check_param_references().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("is a secretsmanager ARN, param:// only supports ssm ARNs"),
+            "{}",
+            err
+        );
+
+        unsafe { std::env::remove_var("PARAM_PREFIX_SSM") }
+        unsafe { std::env::remove_var("PARAM_PREFIX_SECRETSMANAGER") }
+    }
+
+    #[test]
+    fn test_extract_and_update_resolve_vars() {
+        unsafe { std::env::set_var("RESOLVE_VARS_DATABASE_URL", "${arn:test-db}") }
+        unsafe { std::env::set_var("RESOLVE_VARS_OTHER", "secret://arn:test-other") }
+
+        // Set directly rather than through ROTEL_RESOLVE_VARS, so that parsers in other
+        // tests do not pick up these variables
+        let es = EnvArnParser {
+            included: parse_var_names("RESOLVE_VARS_DATABASE_URL"),
+            ..EnvArnParser::new()
+        };
+
+        let mut arn_map = es.extract_arns_from_env();
+        assert!(arn_map.contains_key("arn:test-db"));
+        assert!(!arn_map.contains_key("arn:test-other"));
+
+        arn_map.insert("arn:test-db".to_string(), "postgres://db".to_string());
+        es.update_env_arn_secrets(arn_map);
+
+        assert_eq!(
+            "postgres://db",
+            std::env::var("RESOLVE_VARS_DATABASE_URL").unwrap()
+        );
+        assert_eq!(
+            "secret://arn:test-other",
+            std::env::var("RESOLVE_VARS_OTHER").unwrap()
+        );
+
+        unsafe { std::env::remove_var("RESOLVE_VARS_DATABASE_URL") }
+        unsafe { std::env::remove_var("RESOLVE_VARS_OTHER") }
     }
 
     #[tokio::test]
@@ -296,13 +1252,13 @@ mod tests {
 
         init_crypto();
 
-        let mut test_arn_map = HashMap::new();
-        for (test_arn, _) in &test_arns {
-            test_arn_map.insert(test_arn.clone(), "".to_string());
-        }
-
-        let res = resolve_secrets(AwsCreds::from_env(), &mut test_arn_map).await;
+        let res = resolve_secrets_map(
+            AwsConfig::from_env(),
+            test_arns.iter().map(|(test_arn, _)| test_arn.clone()),
+        )
+        .await;
         assert!(res.is_ok());
+        let test_arn_map = res.unwrap();
 
         for (test_arn, test_value) in test_arns {
             let result = test_arn_map.get(&test_arn).unwrap();
@@ -323,10 +1279,7 @@ mod tests {
         init_crypto();
 
         for (test_arn, _) in &test_arns {
-            let mut test_arn_map = HashMap::new();
-            test_arn_map.insert(test_arn.clone(), "".to_string());
-
-            let res = resolve_secrets(AwsCreds::from_env(), &mut test_arn_map).await;
+            let res = resolve_secrets_map(AwsConfig::from_env(), [test_arn.as_str()]).await;
             assert!(res.is_err());
         }
     }
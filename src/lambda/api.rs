@@ -1,30 +1,32 @@
 use crate::lambda::constants;
-use crate::lambda::constants::TELEMETRY_API_SCHEMA;
-use crate::lambda::types::{
-    RegisterResponseBody, TelemetryAPISubscribe, TelemetryAPISubscribeBuffering,
-    TelemetryAPISubscribeDestination,
-};
+use crate::lambda::constants::TELEMETRY_API_SCHEMAS;
+use crate::lambda::error::LambdaApiError;
+use crate::lambda::types::{RegisterResponseBody, TelemetryAPISubscribe};
 use crate::util::http::response_string;
 use bytes::Bytes;
-use http::header::CONTENT_TYPE;
-use http::{Method, Request};
+use http::header::{CONTENT_TYPE, USER_AGENT};
+use http::{HeaderMap, HeaderValue, Method, Request};
 use http_body_util::BodyExt;
 use http_body_util::Full;
 use hyper_util::client::legacy::Client;
 use hyper_util::client::legacy::connect::HttpConnector;
 use lambda_extension::NextEvent;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use tower::BoxError;
 
+pub use crate::lambda::constants::{TELEMETRY_API_SCHEMA, TELEMETRY_DESTINATION_HOST};
+
 pub async fn register(
     client: Client<HttpConnector, Full<Bytes>>,
-) -> Result<RegisterResponseBody, BoxError> {
+    user_agent: &HeaderValue,
+) -> Result<RegisterResponseBody, LambdaApiError> {
     let events = serde_json::json!({"events": ["INVOKE", "SHUTDOWN"]});
 
     let url = lambda_api_url(constants::REGISTER_PATH)?;
     let req = Request::builder()
         .method(Method::POST)
         .uri(&url)
+        .header(USER_AGENT, user_agent)
         // This value must match the binary name, or this call will 403
         .header(constants::EXTENSION_NAME_HEADER, "rotel-extension")
         .header(
@@ -39,30 +41,28 @@ pub async fn register(
     let status = parts.status;
 
     if status != 200 {
-        let text = body
-            .collect()
-            .await
-            .map_err(|e| format!("Failed to read response body from {}: {}", url, e))
-            .map(|c| c.to_bytes())
-            .map(|s| String::from_utf8(s.to_vec()))?
-            .map_err(|e| format!("Unable to convert response body to string: {}", e))?;
-        return Err(format!(
-            "Can not register extension at {}, got {}: {}",
-            url, status, text,
-        )
-        .into());
+        return Err(LambdaApiError::Status {
+            action: "register extension",
+            url,
+            status,
+            body: response_string(body).await?,
+        });
     }
 
     let ext_id = match parts.headers.get(constants::EXTENSION_ID_HEADER) {
         None => {
-            return Err("Can not get extension id, got no header".into());
+            return Err(LambdaApiError::MissingHeader {
+                name: "extension id",
+                reason: "no header".to_string(),
+            });
         }
         Some(v) => match v.to_str() {
             Ok(v) => v,
             Err(e) => {
-                return Err(
-                    format!("Can not get extension id, got invalid header value: {}", e).into(),
-                );
+                return Err(LambdaApiError::MissingHeader {
+                    name: "extension id",
+                    reason: format!("invalid header value: {}", e),
+                });
             }
         },
     };
@@ -81,11 +81,13 @@ pub async fn register(
 pub async fn next_request(
     client: Client<HttpConnector, Full<Bytes>>,
     ext_id: &str,
+    user_agent: &HeaderValue,
 ) -> Result<NextEvent, BoxError> {
     let url = lambda_api_url(constants::NEXT_PATH)?;
     let req = Request::builder()
         .method(Method::GET)
         .uri(&url)
+        .header(USER_AGENT, user_agent)
         .header(constants::EXTENSION_ID_HEADER, ext_id)
         .body(Full::default())?;
 
@@ -106,49 +108,94 @@ pub async fn next_request(
     let event: NextEvent = serde_json::from_str(text.as_str())
         .map_err(|e| format!("Unable to deser next_event: {}", e))?;
 
-    Ok(event)
+    Ok(with_deadline_header(event, &parts.headers))
+}
+
+// The invoke event body carries the deadline, fall back to the deadline header when the
+// body value is missing so that flushes are still bounded by the invocation deadline
+fn with_deadline_header(mut event: NextEvent, headers: &HeaderMap) -> NextEvent {
+    if let NextEvent::Invoke(invoke) = &mut event {
+        if invoke.deadline_ms == 0 {
+            if let Some(deadline_ms) = headers
+                .get(constants::DEADLINE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+            {
+                invoke.deadline_ms = deadline_ms;
+            }
+        }
+    }
+    event
+}
+
+// The sandbox hostname resolves to the extension whether it is bound to an IPv4 or
+// IPv6 address, so only the port is taken from the bound address. Another host can be
+// given for emulators where the sandbox hostname does not resolve.
+fn telemetry_destination_uri(host: &str, addr: &SocketAddr, tls: bool) -> String {
+    let scheme = if tls { "https" } else { "http" };
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("{}://[{}]:{}/", scheme, ip, addr.port()),
+        _ => format!("{}://{}:{}/", scheme, host, addr.port()),
+    }
+}
+
+/// Validate a TelemetryAPI schema version against the versions we know Lambda accepts, so a
+/// typo fails at startup rather than when subscribing.
+pub fn parse_telemetry_schema_version(version: &str) -> Result<String, String> {
+    if TELEMETRY_API_SCHEMAS.contains(&version) {
+        Ok(version.to_string())
+    } else {
+        Err(format!(
+            "unsupported telemetry schema version '{}', expected one of {}",
+            version,
+            TELEMETRY_API_SCHEMAS.join(", ")
+        ))
+    }
+}
+
+fn subscribe_request(
+    host: &str,
+    addr: &SocketAddr,
+    schema_version: &str,
+    tls: bool,
+) -> Result<TelemetryAPISubscribe, String> {
+    // todo: buffering uses the defaults from API ref, consider adjusting
+    TelemetryAPISubscribe::builder(telemetry_destination_uri(host, addr, tls))
+        .schema_version(schema_version)
+        .build()
 }
 
 pub async fn telemetry_subscribe(
     client: Client<HttpConnector, Full<Bytes>>,
     ext_id: &str,
+    host: &str,
     addr: &SocketAddr,
-) -> Result<(), BoxError> {
-    let sub = serde_json::json!(TelemetryAPISubscribe {
-        schema_version: TELEMETRY_API_SCHEMA.to_string(),
-        types: vec![
-            "platform".to_string(),
-            "function".to_string(),
-            "extension".to_string()
-        ],
-        buffering: TelemetryAPISubscribeBuffering {
-            // todo: these are the defaults from API ref, consider adjusting
-            max_items: 1000,
-            max_bytes: 256 * 1024,
-            timeout_ms: 100,
-        },
-        destination: TelemetryAPISubscribeDestination {
-            protocol: "HTTP".to_string(),
-            uri: format!("http://sandbox.localdomain:{}/", addr.port()),
-        },
-    });
+    schema_version: &str,
+    tls: bool,
+    user_agent: &HeaderValue,
+) -> Result<(), LambdaApiError> {
+    let sub = subscribe_request(host, addr, schema_version, tls)
+        .map_err(LambdaApiError::InvalidRequest)?;
+    let sub = serde_json::json!(sub);
 
     let url = lambda_api_url(constants::TELEMETRY_PATH)?;
     let req = Request::builder()
         .method(Method::PUT)
         .uri(&url)
+        .header(USER_AGENT, user_agent)
         .header(CONTENT_TYPE, "application/json")
         .header(constants::EXTENSION_ID_HEADER, ext_id)
         .body(Full::from(Bytes::from(serde_json::to_vec(&sub)?)))?;
 
     let resp = client.request(req).await?;
-    if resp.status() != 200 {
-        return Err(format!(
-            "Can not subscribe to telemetry API at {}, got {}",
+    let (parts, body) = resp.into_parts();
+    if parts.status != 200 {
+        return Err(LambdaApiError::Status {
+            action: "subscribe to telemetry API",
             url,
-            resp.status()
-        )
-        .into());
+            status: parts.status,
+            body: response_string(body).await?,
+        });
     }
 
     Ok(())
@@ -158,9 +205,371 @@ fn lambda_api_url(path: &str) -> Result<String, BoxError> {
     let base_api = std::env::var("AWS_LAMBDA_RUNTIME_API")
         .map_err(|e| format!("Unable to read AWS_LAMBDA_RUNTIME_API: {:?}", e))?;
 
+    Ok(runtime_api_url(&base_api, path))
+}
+
+/// Checks that AWS_LAMBDA_RUNTIME_API resolves only to loopback or link-local addresses, as
+/// the real Runtime API always does inside the Lambda sandbox. Emulators running the API on
+/// another host must disable this check.
+pub fn validate_runtime_api_host() -> Result<(), BoxError> {
+    let base_api = std::env::var("AWS_LAMBDA_RUNTIME_API")
+        .map_err(|e| format!("Unable to read AWS_LAMBDA_RUNTIME_API: {:?}", e))?;
+
+    check_runtime_api_host(&base_api)
+}
+
+/// Checks that AWS_LAMBDA_RUNTIME_API is plain HTTP, the only protocol the Runtime API
+/// client speaks. This applies even when any host is allowed.
+pub fn validate_runtime_api_scheme() -> Result<(), BoxError> {
+    let base_api = std::env::var("AWS_LAMBDA_RUNTIME_API")
+        .map_err(|e| format!("Unable to read AWS_LAMBDA_RUNTIME_API: {:?}", e))?;
+
+    check_runtime_api_scheme(&base_api)
+}
+
+fn check_runtime_api_scheme(base_api: &str) -> Result<(), BoxError> {
+    match base_api.trim().split_once("://") {
+        None | Some(("http", _)) => Ok(()),
+        Some((scheme, _)) => Err(format!(
+            "AWS_LAMBDA_RUNTIME_API {} uses the {} scheme, only http is supported",
+            base_api, scheme
+        )
+        .into()),
+    }
+}
+
+fn check_runtime_api_host(base_api: &str) -> Result<(), BoxError> {
+    let uri: http::Uri = runtime_api_url(base_api, "/")
+        .parse()
+        .map_err(|e| format!("Invalid AWS_LAMBDA_RUNTIME_API {}: {}", base_api, e))?;
+    let host = match uri.host() {
+        None => return Err(format!("AWS_LAMBDA_RUNTIME_API {} has no host", base_api).into()),
+        Some(h) => h.trim_start_matches('[').trim_end_matches(']'),
+    };
+    let port = uri.port_u16().unwrap_or(80);
+
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve Runtime API host {}: {}", host, e))?
+        .collect();
+    // Every resolved address must be local, so a name can't mix in an external address
+    if addrs.is_empty() || !addrs.iter().all(|a| is_local_ip(a.ip())) {
+        return Err(format!(
+            "Runtime API host {} does not resolve to a loopback or link-local address",
+            host
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn is_local_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.is_loopback() || ip.is_link_local(),
+            None => ip.is_loopback() || ip.is_unicast_link_local(),
+        },
+    }
+}
+
+// Lambda sets a bare host:port, but emulators and users sometimes include an http:// scheme
+// or a trailing slash. Other schemes are rejected at startup.
+fn runtime_api_url(base_api: &str, path: &str) -> String {
+    let base_api = base_api.trim().trim_end_matches('/');
     if base_api.starts_with("http://") {
-        Ok(format!("{}{}", base_api, path))
+        format!("{}{}", base_api, path)
     } else {
-        Ok(format!("http://{}{}", base_api, path))
+        format!("http://{}{}", base_api, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ScopedEnv, env_lock};
+    use http::{Response, StatusCode};
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rotel::init::misc::bind_endpoints;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_telemetry_destination_uri() {
+        let v4: SocketAddr = "0.0.0.0:8990".parse().unwrap();
+        let v6: SocketAddr = "[::]:8990".parse().unwrap();
+
+        assert_eq!(
+            "http://sandbox.localdomain:8990/",
+            telemetry_destination_uri(TELEMETRY_DESTINATION_HOST, &v4, false)
+        );
+        assert_eq!(
+            "http://sandbox.localdomain:8990/",
+            telemetry_destination_uri(TELEMETRY_DESTINATION_HOST, &v6, false)
+        );
+        assert_eq!(
+            "https://sandbox.localdomain:8990/",
+            telemetry_destination_uri(TELEMETRY_DESTINATION_HOST, &v4, true)
+        );
+    }
+
+    #[test]
+    fn test_telemetry_destination_host() {
+        let addr: SocketAddr = "0.0.0.0:8990".parse().unwrap();
+
+        let sub = subscribe_request("127.0.0.1", &addr, TELEMETRY_API_SCHEMA, false).unwrap();
+        assert_eq!("http://127.0.0.1:8990/", sub.destination.uri);
+
+        let sub = subscribe_request("localhost", &addr, TELEMETRY_API_SCHEMA, true).unwrap();
+        assert_eq!("https://localhost:8990/", sub.destination.uri);
+
+        // IPv6 literals are bracketed
+        let sub = subscribe_request("::1", &addr, TELEMETRY_API_SCHEMA, false).unwrap();
+        assert_eq!("http://[::1]:8990/", sub.destination.uri);
+
+        let sub = subscribe_request(
+            TELEMETRY_DESTINATION_HOST,
+            &addr,
+            TELEMETRY_API_SCHEMA,
+            false,
+        )
+        .unwrap();
+        assert_eq!("http://sandbox.localdomain:8990/", sub.destination.uri);
+    }
+
+    #[test]
+    fn test_runtime_api_url() {
+        let expected = "http://127.0.0.1:9001/2020-01-01/extension/register";
+        for base in [
+            "127.0.0.1:9001",
+            "http://127.0.0.1:9001",
+            "127.0.0.1:9001/",
+            "http://127.0.0.1:9001/",
+            " 127.0.0.1:9001 ",
+        ] {
+            let url = runtime_api_url(base, constants::REGISTER_PATH);
+            assert_eq!(expected, url, "{}", base);
+            assert!(url.parse::<http::Uri>().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_runtime_api_scheme() {
+        for base in [
+            "127.0.0.1:9001",
+            "http://127.0.0.1:9001/",
+            " http://localhost:9001",
+        ] {
+            assert!(check_runtime_api_scheme(base).is_ok(), "{}", base);
+        }
+
+        // The Runtime API client only speaks plain HTTP
+        for base in ["https://localhost:9001", "unix:///var/run/runtime.sock"] {
+            let err = check_runtime_api_scheme(base).unwrap_err();
+            assert!(
+                err.to_string().contains("only http is supported"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_runtime_api_host_local() {
+        for base in [
+            "127.0.0.1:9001",
+            "http://127.0.0.1:9001/",
+            "localhost:9001",
+            "[::1]:9001",
+            "169.254.100.1:9001",
+        ] {
+            assert!(check_runtime_api_host(base).is_ok(), "{}", base);
+        }
+    }
+
+    #[test]
+    fn test_runtime_api_host_external() {
+        for base in [
+            "203.0.113.10:9001",
+            "http://10.0.0.5:9001",
+            "[2001:db8::1]:9001",
+            "[::ffff:203.0.113.10]:9001",
+        ] {
+            let err = check_runtime_api_host(base).unwrap_err();
+            assert!(
+                err.to_string().contains("loopback or link-local"),
+                "{}: {}",
+                base,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_invoke_deadline() {
+        let invoke = |deadline_ms: u64| -> NextEvent {
+            serde_json::from_str(&format!(
+                r#"{{
+    "eventType": "INVOKE",
+    "deadlineMs": {},
+    "requestId": "3da1f2dc-3222-475e-9205-e2e6c6318895",
+    "invokedFunctionArn": "arn:aws:lambda:us-east-1:123456789012:function:ExtensionTest",
+    "tracing": {{"type": "X-Amzn-Trace-Id", "value": "Root=1-5f35ae12-0c0fec141ab77a00bc047aa2"}}
+}}"#,
+                deadline_ms
+            ))
+            .unwrap()
+        };
+        let deadline = |evt: NextEvent| match evt {
+            NextEvent::Invoke(invoke) => invoke.deadline_ms,
+            _ => panic!("expected invoke"),
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(constants::DEADLINE_HEADER, "1676051299999".parse().unwrap());
+
+        // The body deadline is used when present
+        let evt = with_deadline_header(invoke(1676051295123), &headers);
+        assert_eq!(1676051295123, deadline(evt));
+
+        // Otherwise the header provides it
+        let evt = with_deadline_header(invoke(0), &headers);
+        assert_eq!(1676051299999, deadline(evt));
+
+        let evt = with_deadline_header(invoke(0), &HeaderMap::new());
+        assert_eq!(0, deadline(evt));
+    }
+
+    #[test]
+    fn test_subscribe_schema_version() {
+        let addr: SocketAddr = "0.0.0.0:8990".parse().unwrap();
+
+        let sub = serde_json::to_value(
+            subscribe_request(
+                TELEMETRY_DESTINATION_HOST,
+                &addr,
+                TELEMETRY_API_SCHEMA,
+                false,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!("2022-12-13", sub["schemaVersion"]);
+
+        let version = parse_telemetry_schema_version("2025-01-29").unwrap();
+        let sub = serde_json::to_value(
+            subscribe_request(TELEMETRY_DESTINATION_HOST, &addr, &version, false).unwrap(),
+        )
+        .unwrap();
+        assert_eq!("2025-01-29", sub["schemaVersion"]);
+
+        assert!(parse_telemetry_schema_version("2022-12-31").is_err());
+        assert!(parse_telemetry_schema_version("").is_err());
+    }
+
+    #[test]
+    fn test_subscribe_ephemeral_port() {
+        let endpoint: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = bind_endpoints(&[endpoint])
+            .unwrap()
+            .remove(&endpoint)
+            .unwrap();
+
+        let bound = listener.bound_address().unwrap();
+        assert_ne!(0, bound.port());
+
+        let sub = subscribe_request(
+            TELEMETRY_DESTINATION_HOST,
+            &bound,
+            TELEMETRY_API_SCHEMA,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("http://sandbox.localdomain:{}/", bound.port()),
+            sub.destination.uri
+        );
+    }
+
+    #[test]
+    fn test_api_error_status() {
+        // The lock is held outside the runtime, it is not held across an await
+        let _lock = env_lock();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let user_agents = Arc::new(Mutex::new(Vec::new()));
+            let agents = user_agents.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let agents = agents.clone();
+                    tokio::spawn(async move {
+                        let svc = service_fn(move |req: Request<hyper::body::Incoming>| {
+                            agents
+                                .lock()
+                                .unwrap()
+                                .push(req.headers().get(USER_AGENT).cloned());
+                            async {
+                                let mut resp = Response::new(Full::new(Bytes::from(
+                                    r#"{"errorType":"Extension.Forbidden"}"#,
+                                )));
+                                *resp.status_mut() = StatusCode::FORBIDDEN;
+                                Ok::<_, Infallible>(resp)
+                            }
+                        });
+                        let _ = hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), svc)
+                            .await;
+                    });
+                }
+            });
+
+            let _runtime_api = ScopedEnv::set("AWS_LAMBDA_RUNTIME_API", &addr.to_string());
+            let client = Client::builder(TokioExecutor::new()).build_http();
+
+            let user_agent = HeaderValue::from_static("custom-agent/1.0");
+            let err = register(client.clone(), &user_agent).await.unwrap_err();
+            assert_eq!(Some(StatusCode::FORBIDDEN), err.status());
+            assert!(!err.is_retryable());
+            match &err {
+                LambdaApiError::Status { body, .. } => {
+                    assert_eq!(r#"{"errorType":"Extension.Forbidden"}"#, body)
+                }
+                e => panic!("unexpected error: {:?}", e),
+            }
+            assert_eq!(
+                format!(
+                    "Can not register extension at http://{}{}, got 403 Forbidden: {}",
+                    addr,
+                    constants::REGISTER_PATH,
+                    r#"{"errorType":"Extension.Forbidden"}"#
+                ),
+                err.to_string()
+            );
+
+            let bound: SocketAddr = "127.0.0.1:8990".parse().unwrap();
+            let err = telemetry_subscribe(
+                client,
+                "ext-id",
+                TELEMETRY_DESTINATION_HOST,
+                &bound,
+                TELEMETRY_API_SCHEMA,
+                false,
+                &user_agent,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(Some(StatusCode::FORBIDDEN), err.status());
+
+            let user_agents = user_agents.lock().unwrap();
+            assert_eq!(2, user_agents.len());
+            for ua in user_agents.iter() {
+                assert_eq!(Some(&user_agent), ua.as_ref());
+            }
+        });
     }
 }
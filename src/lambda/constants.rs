@@ -2,7 +2,11 @@ pub const REGISTER_PATH: &str = "/2020-01-01/extension/register";
 pub const NEXT_PATH: &str = "/2020-01-01/extension/event/next";
 pub const TELEMETRY_PATH: &str = "/2022-07-01/telemetry";
 
+// Resolves to the extension inside the Lambda sandbox
+pub const TELEMETRY_DESTINATION_HOST: &str = "sandbox.localdomain";
+
 pub const TELEMETRY_API_SCHEMA: &str = "2022-12-13";
+pub const TELEMETRY_API_SCHEMAS: &[&str] = &["2022-07-01", "2022-12-13", "2025-01-29"];
 
 pub const EXTENSION_NAME_HEADER: &str = "Lambda-Extension-Name";
 pub const EXTENSION_ACCEPT_FEATURE: &str = "Lambda-Extension-Accept-Feature";
@@ -10,3 +14,6 @@ pub const EXTENSION_ACCEPT_FEATURE: &str = "Lambda-Extension-Accept-Feature";
 pub const EXTENSION_FEATURE_ACCOUNTID: &str = "accountId";
 
 pub const EXTENSION_ID_HEADER: &str = "Lambda-Extension-Identifier";
+
+// Set on next responses, the invocation deadline in milliseconds since the epoch
+pub const DEADLINE_HEADER: &str = "Lambda-Runtime-Deadline-Ms";
@@ -0,0 +1,179 @@
+use crate::lambda::invocation_summary::memory_utilization;
+use crate::lambda::logs::{OWN_LOG_FIELD, OWN_LOG_SOURCE};
+use chrono::{DateTime, Utc};
+use lambda_extension::LambdaTelemetryRecord;
+use serde_json::{Map, Value, json};
+
+pub const EMF_NAMESPACE: &str = "RotelLambdaExtension";
+
+const FUNCTION_NAME_DIMENSION: &str = "FunctionName";
+
+/// Prints platform.report metrics to stdout in CloudWatch Embedded Metric Format, which
+/// CloudWatch Logs extracts into metrics without an OTLP backend.
+#[derive(Clone)]
+pub struct EmfEmitter {
+    function_name: String,
+}
+
+impl EmfEmitter {
+    pub fn new(function_name: String) -> Self {
+        Self { function_name }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("AWS_LAMBDA_FUNCTION_NAME").unwrap_or_default())
+    }
+
+    /// Print the EMF document for a platform.report record, other records are ignored
+    pub fn emit<L>(&self, time: DateTime<Utc>, record: &LambdaTelemetryRecord<L>) {
+        if let Some(doc) = report_document(&self.function_name, time, record) {
+            println!("{}", doc);
+        }
+    }
+}
+
+/// The EMF document for a platform.report record. The document carries the extension's
+/// own log marker, so it is not forwarded when Lambda delivers it back as an extension log.
+pub fn report_document<L>(
+    function_name: &str,
+    time: DateTime<Utc>,
+    record: &LambdaTelemetryRecord<L>,
+) -> Option<Value> {
+    let LambdaTelemetryRecord::PlatformReport {
+        request_id,
+        metrics,
+        ..
+    } = record
+    else {
+        return None;
+    };
+
+    let mut values = vec![
+        ("Duration", "Milliseconds", json!(metrics.duration_ms)),
+        (
+            "BilledDuration",
+            "Milliseconds",
+            json!(metrics.billed_duration_ms),
+        ),
+        ("MemorySize", "Megabytes", json!(metrics.memory_size_mb)),
+        (
+            "MaxMemoryUsed",
+            "Megabytes",
+            json!(metrics.max_memory_used_mb),
+        ),
+        (
+            "MemoryUtilization",
+            "None",
+            json!(memory_utilization(
+                metrics.memory_size_mb,
+                metrics.max_memory_used_mb
+            )),
+        ),
+    ];
+    if let Some(init_duration_ms) = metrics.init_duration_ms {
+        values.push(("InitDuration", "Milliseconds", json!(init_duration_ms)));
+    }
+
+    let mut doc = Map::new();
+    doc.insert(
+        "_aws".to_string(),
+        json!({
+            "Timestamp": time.timestamp_millis(),
+            "CloudWatchMetrics": [{
+                "Namespace": EMF_NAMESPACE,
+                "Dimensions": [[FUNCTION_NAME_DIMENSION]],
+                "Metrics": values
+                    .iter()
+                    .map(|(name, unit, _)| json!({"Name": name, "Unit": unit}))
+                    .collect::<Vec<_>>(),
+            }],
+        }),
+    );
+    doc.insert(FUNCTION_NAME_DIMENSION.to_string(), json!(function_name));
+    doc.insert("requestId".to_string(), json!(request_id));
+    doc.insert(OWN_LOG_FIELD.to_string(), json!(OWN_LOG_SOURCE));
+    for (name, _, value) in values {
+        doc.insert(name.to_string(), value);
+    }
+
+    Some(Value::Object(doc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lambda::logs::is_own_log;
+    use lambda_extension::LambdaTelemetry;
+
+    #[test]
+    fn test_report_document() {
+        let events: Vec<LambdaTelemetry<Value>> = serde_json::from_str(
+            r#"[
+    {
+        "time": "2022-10-12T00:00:00.710Z",
+        "type": "platform.report",
+        "record": {
+            "requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa",
+            "status": "success",
+            "metrics": {
+                "durationMs": 205.5,
+                "billedDurationMs": 206,
+                "memorySizeMB": 128,
+                "maxMemoryUsedMB": 32,
+                "initDurationMs": 480.0
+            }
+        }
+    },
+    {
+        "time": "2022-10-12T00:00:00.500Z",
+        "type": "platform.start",
+        "record": {
+            "requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa",
+            "version": "$LATEST"
+        }
+    }
+]"#,
+        )
+        .unwrap();
+
+        let doc = report_document("my-function", events[0].time, &events[0].record).unwrap();
+
+        let emf = &doc["_aws"];
+        assert_eq!(json!(1665532800710i64), emf["Timestamp"]);
+        let cwm = &emf["CloudWatchMetrics"][0];
+        assert_eq!(EMF_NAMESPACE, cwm["Namespace"]);
+        assert_eq!(json!([["FunctionName"]]), cwm["Dimensions"]);
+        assert_eq!(
+            json!({"Name": "Duration", "Unit": "Milliseconds"}),
+            cwm["Metrics"][0]
+        );
+        let names: Vec<&str> = cwm["Metrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["Name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "Duration",
+                "BilledDuration",
+                "MemorySize",
+                "MaxMemoryUsed",
+                "MemoryUtilization",
+                "InitDuration"
+            ],
+            names
+        );
+
+        // Every metric has a value at the top level, alongside the dimension
+        assert_eq!("my-function", doc["FunctionName"]);
+        assert_eq!("6d68ca91-49c9-448d-89b8-7ca3e6dc66aa", doc["requestId"]);
+        assert_eq!(json!(205.5), doc["Duration"]);
+        assert_eq!(json!(206), doc["BilledDuration"]);
+        assert_eq!(json!(0.25), doc["MemoryUtilization"]);
+        assert_eq!(json!(480.0), doc["InitDuration"]);
+        assert!(is_own_log(&doc));
+
+        assert!(report_document("my-function", events[1].time, &events[1].record).is_none());
+    }
+}
@@ -0,0 +1,100 @@
+use http::StatusCode;
+use std::fmt;
+use tower::BoxError;
+
+/// Errors from the Lambda Extensions and Telemetry APIs
+#[derive(Debug)]
+pub enum LambdaApiError {
+    /// The API responded with a non-200 status, `action` describes the failed call
+    Status {
+        action: &'static str,
+        url: String,
+        status: StatusCode,
+        body: String,
+    },
+    /// The request could not be built, sent, or its response read
+    Transport(BoxError),
+    /// A required response header was missing or invalid
+    MissingHeader { name: &'static str, reason: String },
+    /// The request was rejected before it was sent, for example an invalid subscription
+    InvalidRequest(String),
+}
+
+impl LambdaApiError {
+    /// Status code returned by the API, if a response was received
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            LambdaApiError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether the call may succeed if retried: transport failures, throttling and
+    /// server errors
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LambdaApiError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            LambdaApiError::Transport(_) => true,
+            LambdaApiError::MissingHeader { .. } => false,
+            LambdaApiError::InvalidRequest(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for LambdaApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LambdaApiError::Status {
+                action,
+                url,
+                status,
+                body,
+            } => {
+                write!(f, "Can not {} at {}, got {}", action, url, status)?;
+                if !body.is_empty() {
+                    write!(f, ": {}", body)?;
+                }
+                Ok(())
+            }
+            LambdaApiError::Transport(e) => write!(f, "{}", e),
+            LambdaApiError::MissingHeader { name, reason } => {
+                write!(f, "Can not get {}, got {}", name, reason)
+            }
+            LambdaApiError::InvalidRequest(reason) => write!(f, "Invalid request: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LambdaApiError {}
+
+impl From<BoxError> for LambdaApiError {
+    fn from(err: BoxError) -> Self {
+        LambdaApiError::Transport(err)
+    }
+}
+
+impl From<hyper_util::client::legacy::Error> for LambdaApiError {
+    fn from(err: hyper_util::client::legacy::Error) -> Self {
+        LambdaApiError::Transport(err.into())
+    }
+}
+
+impl From<hyper::Error> for LambdaApiError {
+    fn from(err: hyper::Error) -> Self {
+        LambdaApiError::Transport(err.into())
+    }
+}
+
+impl From<http::Error> for LambdaApiError {
+    fn from(err: http::Error) -> Self {
+        LambdaApiError::Transport(err.into())
+    }
+}
+
+impl From<serde_json::Error> for LambdaApiError {
+    fn from(err: serde_json::Error) -> Self {
+        LambdaApiError::Transport(err.into())
+    }
+}
@@ -0,0 +1,333 @@
+use crate::lambda::{otel_bool_attr, otel_double_attr, otel_int_attr};
+use crate::lifecycle::internal_metrics::{gauge, now_nanos};
+use lambda_extension::LambdaTelemetryRecord;
+use opentelemetry_proto::tonic::common::v1::InstrumentationScope;
+use opentelemetry_proto::tonic::metrics::v1::exemplar::Value as ExemplarValue;
+use opentelemetry_proto::tonic::metrics::v1::number_data_point::Value;
+use opentelemetry_proto::tonic::metrics::v1::{
+    Exemplar, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_semantic_conventions::attribute::FAAS_COLDSTART;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const INVOCATION_SUMMARY_SCOPE: &str = "github.com/streamfold/rotel-lambda-extension";
+
+pub const INVOCATION_SUMMARY: &str = "faas.invocation.summary";
+
+const BILLED_DURATION_ATTR: &str = "aws.lambda.billed_duration_ms";
+const MEMORY_UTILIZATION_ATTR: &str = "aws.lambda.memory_utilization";
+
+// Invocations whose events never complete, for example after a dropped batch, are
+// discarded oldest first once this many are pending
+const MAX_PENDING_INVOCATIONS: usize = 64;
+
+/// Correlates the platform events of each invocation, keyed by request id, into a single
+/// data point whose value is the invocation duration in milliseconds, with the cold start
+/// flag, billed duration and memory utilization as attributes. When a log of the invocation
+/// carried a trace id, the data point has an exemplar linking it to that trace.
+#[derive(Default)]
+pub struct InvocationSummaries {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // An init phase was seen, so the next invocation to start is a cold start
+    init_pending: bool,
+    seq: u64,
+    pending: HashMap<String, Pending>,
+    points: Vec<NumberDataPoint>,
+}
+
+#[derive(Default)]
+struct Pending {
+    seq: u64,
+    cold_start: bool,
+    runtime_done: bool,
+    duration_ms: Option<f64>,
+    report: Option<Report>,
+    trace_id: Option<Vec<u8>>,
+}
+
+struct Report {
+    duration_ms: f64,
+    billed_duration_ms: u64,
+    memory_utilization: f64,
+}
+
+impl InvocationSummaries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a telemetry record. The summary is recorded once runtimeDone has been seen
+    /// for the request, together with its report, which Lambda may deliver in a later batch.
+    pub fn observe<L>(&self, record: &LambdaTelemetryRecord<L>) {
+        let mut inner = self.inner.lock().unwrap();
+        match record {
+            LambdaTelemetryRecord::PlatformInitStart { .. } => inner.init_pending = true,
+            LambdaTelemetryRecord::PlatformStart { request_id, .. } => {
+                let cold_start = std::mem::take(&mut inner.init_pending);
+                inner.pending(request_id).cold_start = cold_start;
+            }
+            LambdaTelemetryRecord::PlatformRuntimeDone {
+                request_id,
+                metrics,
+                ..
+            } => {
+                let p = inner.pending(request_id);
+                p.runtime_done = true;
+                p.duration_ms = metrics.as_ref().map(|m| m.duration_ms);
+                inner.complete(request_id);
+            }
+            LambdaTelemetryRecord::PlatformReport {
+                request_id,
+                metrics,
+                ..
+            } => {
+                let p = inner.pending(request_id);
+                p.cold_start |= metrics.init_duration_ms.is_some();
+                p.report = Some(Report {
+                    duration_ms: metrics.duration_ms,
+                    billed_duration_ms: metrics.billed_duration_ms,
+                    memory_utilization: memory_utilization(
+                        metrics.memory_size_mb,
+                        metrics.max_memory_used_mb,
+                    ),
+                });
+                inner.complete(request_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Associate a trace id, taken from a log of the request, with its summary. The first
+    /// trace id seen for a request is kept.
+    pub fn observe_trace(&self, request_id: &str, trace_id: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .pending(request_id)
+            .trace_id
+            .get_or_insert_with(|| trace_id.to_vec());
+    }
+
+    /// Drain the completed summaries into a batch of metrics
+    pub fn take(&self, resource: &Resource) -> Option<ResourceMetrics> {
+        let points = std::mem::take(&mut self.inner.lock().unwrap().points);
+        if points.is_empty() {
+            return None;
+        }
+
+        Some(ResourceMetrics {
+            resource: Some(resource.clone()),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: INVOCATION_SUMMARY_SCOPE.to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    ..Default::default()
+                }),
+                metrics: vec![gauge(
+                    INVOCATION_SUMMARY,
+                    "Duration of each invocation, with cold start, billed duration and memory utilization",
+                    points,
+                )],
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+/// Max memory used as a fraction of the configured memory
+pub(crate) fn memory_utilization(memory_size_mb: u64, max_memory_used_mb: u64) -> f64 {
+    if memory_size_mb > 0 {
+        max_memory_used_mb as f64 / memory_size_mb as f64
+    } else {
+        0.0
+    }
+}
+
+impl Inner {
+    fn pending(&mut self, request_id: &str) -> &mut Pending {
+        if !self.pending.contains_key(request_id) && self.pending.len() >= MAX_PENDING_INVOCATIONS {
+            if let Some(oldest) = self
+                .pending
+                .iter()
+                .min_by_key(|(_, p)| p.seq)
+                .map(|(id, _)| id.clone())
+            {
+                self.pending.remove(&oldest);
+            }
+        }
+
+        self.seq += 1;
+        let seq = self.seq;
+        self.pending
+            .entry(request_id.to_string())
+            .or_insert_with(|| Pending {
+                seq,
+                ..Default::default()
+            })
+    }
+
+    fn complete(&mut self, request_id: &str) {
+        let ready = self
+            .pending
+            .get(request_id)
+            .is_some_and(|p| p.runtime_done && p.report.is_some());
+        if !ready {
+            return;
+        }
+
+        let p = self.pending.remove(request_id).unwrap();
+        let report = p.report.unwrap();
+        let duration_ms = p.duration_ms.unwrap_or(report.duration_ms);
+        let time_unix_nano = now_nanos();
+        let exemplars = p
+            .trace_id
+            .map(|trace_id| Exemplar {
+                time_unix_nano,
+                trace_id,
+                value: Some(ExemplarValue::AsDouble(duration_ms)),
+                ..Default::default()
+            })
+            .into_iter()
+            .collect();
+
+        self.points.push(NumberDataPoint {
+            attributes: vec![
+                otel_bool_attr(FAAS_COLDSTART, p.cold_start),
+                otel_int_attr(BILLED_DURATION_ATTR, report.billed_duration_ms as i64),
+                otel_double_attr(MEMORY_UTILIZATION_ATTR, report.memory_utilization),
+            ],
+            time_unix_nano,
+            value: Some(Value::AsDouble(duration_ms)),
+            exemplars,
+            ..Default::default()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_extension::LambdaTelemetry;
+    use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+
+    fn events(body: &str) -> Vec<LambdaTelemetry<serde_json::Value>> {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_invocation_summary() {
+        let summaries = InvocationSummaries::new();
+
+        let batch = events(
+            r#"[
+    {
+        "time": "2022-10-12T00:00:00.000Z",
+        "type": "platform.initStart",
+        "record": {
+            "initializationType": "on-demand",
+            "phase": "init"
+        }
+    },
+    {
+        "time": "2022-10-12T00:00:00.500Z",
+        "type": "platform.start",
+        "record": {
+            "requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa",
+            "version": "$LATEST"
+        }
+    },
+    {
+        "time": "2022-10-12T00:00:00.700Z",
+        "type": "platform.runtimeDone",
+        "record": {
+            "requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa",
+            "status": "success",
+            "metrics": {
+                "durationMs": 200.0,
+                "producedBytes": 20
+            }
+        }
+    }
+]"#,
+        );
+        for e in &batch {
+            summaries.observe(&e.record);
+        }
+
+        // The report has not arrived yet
+        assert!(summaries.take(&Resource::default()).is_none());
+
+        let batch = events(
+            r#"[
+    {
+        "time": "2022-10-12T00:00:00.710Z",
+        "type": "platform.report",
+        "record": {
+            "requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa",
+            "status": "success",
+            "metrics": {
+                "durationMs": 205.5,
+                "billedDurationMs": 206,
+                "memorySizeMB": 128,
+                "maxMemoryUsedMB": 32,
+                "initDurationMs": 480.0
+            }
+        }
+    }
+]"#,
+        );
+        for e in &batch {
+            summaries.observe(&e.record);
+        }
+
+        let rm = summaries.take(&Resource::default()).unwrap();
+        let metric = &rm.scope_metrics[0].metrics[0];
+        assert_eq!(INVOCATION_SUMMARY, metric.name);
+
+        let points = match metric.data.as_ref().unwrap() {
+            Data::Gauge(g) => &g.data_points,
+            _ => panic!("expected gauge"),
+        };
+        assert_eq!(1, points.len());
+        assert_eq!(Some(Value::AsDouble(200.0)), points[0].value);
+        assert_eq!(
+            vec![
+                otel_bool_attr(FAAS_COLDSTART, true),
+                otel_int_attr(BILLED_DURATION_ATTR, 206),
+                otel_double_attr(MEMORY_UTILIZATION_ATTR, 0.25),
+            ],
+            points[0].attributes
+        );
+
+        // No log carried a trace id
+        assert!(points[0].exemplars.is_empty());
+
+        // Completed invocations are drained
+        assert!(summaries.take(&Resource::default()).is_none());
+        assert!(summaries.inner.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_invocation_summary_bounded() {
+        let summaries = InvocationSummaries::new();
+        for i in 0..(MAX_PENDING_INVOCATIONS + 10) {
+            let body = format!(
+                r#"[{{"time": "2022-10-12T00:00:00.000Z", "type": "platform.start", "record": {{"requestId": "req-{}"}}}}]"#,
+                i
+            );
+            for e in events(&body) {
+                summaries.observe(&e.record);
+            }
+        }
+
+        let inner = summaries.inner.lock().unwrap();
+        assert_eq!(MAX_PENDING_INVOCATIONS, inner.pending.len());
+        assert!(!inner.pending.contains_key("req-0"));
+    }
+}
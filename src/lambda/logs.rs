@@ -1,19 +1,152 @@
-use crate::lambda::otel_string_attr;
+use crate::lambda::logs_sink::LogsSink;
+use crate::lambda::telemetry_api::{TelemetryItem, parse_telemetry_events};
+use crate::lambda::{otel_bool_attr, otel_int_attr, otel_string_attr};
 use chrono::{DateTime, Utc};
-use opentelemetry_proto::tonic::common::v1::any_value::Value::StringValue;
-use opentelemetry_proto::tonic::common::v1::{AnyValue, InstrumentationScope};
+use lambda_extension::LambdaTelemetryRecord;
+use opentelemetry_proto::tonic::common::v1::any_value::Value::{
+    BoolValue, DoubleValue, IntValue, StringValue,
+};
+use opentelemetry_proto::tonic::common::v1::{AnyValue, InstrumentationScope, KeyValue};
 use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs, SeverityNumber};
 use opentelemetry_proto::tonic::resource::v1::Resource;
-use opentelemetry_semantic_conventions::attribute::FAAS_INVOCATION_ID;
-use serde_json::Value;
-use std::time::SystemTime;
+use opentelemetry_semantic_conventions::attribute::{
+    CLOUD_RESOURCE_ID, FAAS_INVOCATION_ID, FAAS_MAX_MEMORY, FAAS_NAME, FAAS_VERSION,
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, SystemTime};
 use tower::BoxError;
 
 const LOG_SCOPE: &str = "github.com/streamfold/rotel-lambda-extension";
 
-pub(crate) enum Log {
-    Function(DateTime<Utc>, Value),
-    Extension(DateTime<Utc>, Value),
+const TRUNCATED_MARKER: &str = "...[truncated]";
+const TRUNCATED_ATTR: &str = "log.truncated";
+
+// Set when the invocation id was taken from the last platform.start event rather
+// than from the log record itself.
+const INFERRED_INVOCATION_ID_ATTR: &str = "faas.invocation_id.inferred";
+
+// The telemetry record as Lambda delivered it, for debugging the conversion
+const RAW_RECORD_ATTR: &str = "lambda.raw_record";
+
+// Marks the extension's own log output so it can be recognized, and dropped, when it is
+// delivered back to us as extension telemetry.
+pub(crate) const OWN_LOG_FIELD: &str = "source";
+pub(crate) const OWN_LOG_SOURCE: &str = "rotel-lambda-extension";
+const OWN_LOG_PREFIX: &str = "[rotel-lambda-extension] ";
+
+// Fields that may carry the trace id of the invocation, in order of preference. X-Ray ids
+// such as 1-5f35ae12-0c0fec141ab77a00bc047aa2 are converted to the W3C form.
+const TRACE_ID_FIELDS: &[&str] = &["traceId", "trace_id", "xray_trace_id"];
+
+// Lambda context fields that runtimes add to their JSON logs, mapped to the semantic
+// convention attribute for the same value. A field is skipped when an earlier field has
+// already set its attribute.
+const LAMBDA_CONTEXT_FIELDS: &[(&str, &str)] = &[
+    ("aws_request_id", FAAS_INVOCATION_ID),
+    ("function_arn", CLOUD_RESOURCE_ID),
+    ("invoked_function_arn", CLOUD_RESOURCE_ID),
+    ("function_name", FAAS_NAME),
+    ("function_version", FAAS_VERSION),
+    ("memory_limit_in_mb", FAAS_MAX_MEMORY),
+];
+
+const BYTES_PER_MB: i64 = 1024 * 1024;
+
+// Fields that are already mapped onto the log record and are not flattened
+const FLATTEN_SKIP_FIELDS: &[&str] = &["timestamp", "level", "requestId", "message"];
+const FLATTEN_PREFIX: &str = "record";
+
+/// Options controlling how Lambda telemetry logs are converted to OTLP.
+#[derive(Clone, Debug, Default)]
+pub struct LogsConfig {
+    /// Maximum size in bytes of a string log body, longer bodies are truncated.
+    pub max_body_bytes: Option<usize>,
+    /// Destination for converted logs
+    pub sink: LogsSink,
+    /// Severity for records whose level is present but not recognized. Records without
+    /// a level stay unspecified.
+    pub default_severity: Option<SeverityNumber>,
+    /// Additional level names, uppercased, mapped to a severity. These take precedence over
+    /// the built-in names.
+    pub severity_map: HashMap<String, SeverityNumber>,
+    /// Add the remaining fields of JSON records as `record.`-prefixed attributes, descending
+    /// this many levels into nested objects. Deeper values are added as JSON strings.
+    pub flatten_depth: Option<usize>,
+    /// Which timestamp is preferred when a record carries its own
+    pub timestamp_source: TimestampSource,
+    /// How long a batch may wait for room in the logs pipeline before it is dropped. When
+    /// unset, the TelemetryAPI request waits until the batch is accepted.
+    pub send_timeout: Option<Duration>,
+    /// Parse string records that hold a JSON object, as written by runtimes that encode
+    /// their JSON logs a second time, and convert them like JSON records
+    pub parse_nested_json: bool,
+    /// What `observed_time_unix_nano` is set from
+    pub observed_time_source: ObservedTimeSource,
+    /// Add the record as Lambda delivered it, serialized to JSON, as an attribute. This
+    /// doubles the size of each record and is meant for debugging.
+    pub include_raw: bool,
+}
+
+/// Source of a log record's timestamp when the JSON record has a `timestamp` field as well
+/// as the time of the telemetry event.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimestampSource {
+    /// The record's own `timestamp`, falling back to the event time
+    #[default]
+    Record,
+    /// The time Lambda gave the telemetry event, ignoring application timestamps
+    Event,
+}
+
+/// Parse a timestamp source, either `record` or `event`
+pub fn parse_timestamp_source(s: &str) -> Result<TimestampSource, String> {
+    match s {
+        "record" => Ok(TimestampSource::Record),
+        "event" => Ok(TimestampSource::Event),
+        _ => Err(format!(
+            "invalid timestamp source '{}', expected record or event",
+            s
+        )),
+    }
+}
+
+/// Source of a log record's observed time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ObservedTimeSource {
+    /// The time the TelemetryAPI request carrying the log was received
+    #[default]
+    Received,
+    /// The time Lambda gave the telemetry event
+    Event,
+    /// Leave the observed time unset
+    None,
+}
+
+/// Parse an observed time source, one of `received`, `event` or `none`
+pub fn parse_observed_time_source(s: &str) -> Result<ObservedTimeSource, String> {
+    match s {
+        "received" => Ok(ObservedTimeSource::Received),
+        "event" => Ok(ObservedTimeSource::Event),
+        "none" => Ok(ObservedTimeSource::None),
+        _ => Err(format!(
+            "invalid observed time source '{}', expected received, event or none",
+            s
+        )),
+    }
+}
+
+/// A log from a telemetry batch, with the time Lambda gave the event and its record. The
+/// optional string is the request id of the invocation that was active when the log was
+/// received, used if the record does not contain its own requestId.
+pub enum Log {
+    Function(DateTime<Utc>, Value, Option<String>),
+    Extension(DateTime<Utc>, Value, Option<String>),
+    /// A platform.fault event, recorded at ERROR severity
+    Fault(DateTime<Utc>, Value, Option<String>),
+    /// A platform.error event for a runtime error, recorded at ERROR severity like a fault
+    Error(DateTime<Utc>, Value, Option<String>),
 }
 
 impl Log {
@@ -21,20 +154,122 @@ impl Log {
         match self {
             Log::Function { .. } => "function".to_string(),
             Log::Extension { .. } => "extension".to_string(),
+            Log::Fault { .. } => "platform.fault".to_string(),
+            Log::Error { .. } => "platform.error".to_string(),
         }
     }
 
-    fn into_parts(self) -> (DateTime<Utc>, serde_json::Value) {
+    fn into_parts(self) -> (DateTime<Utc>, serde_json::Value, Option<String>) {
         match self {
-            Log::Function(dt, l) => (dt, l),
-            Log::Extension(dt, l) => (dt, l),
+            Log::Function(dt, l, req_id) => (dt, l, req_id),
+            Log::Extension(dt, l, req_id) => (dt, l, req_id),
+            Log::Fault(dt, l, req_id) => (dt, l, req_id),
+            Log::Error(dt, l, req_id) => (dt, l, req_id),
         }
     }
 }
 
-pub(crate) fn parse_logs(resource: Resource, logs: Vec<Log>) -> Result<ResourceLogs, BoxError> {
+/// Whether an extension log record was written by this extension, see [`MarkedWriter`].
+pub fn is_own_log(record: &Value) -> bool {
+    match record {
+        Value::String(s) => s.starts_with(OWN_LOG_PREFIX),
+        Value::Object(m) => m
+            .get(OWN_LOG_FIELD)
+            .is_some_and(|v| v.as_str() == Some(OWN_LOG_SOURCE)),
+        _ => false,
+    }
+}
+
+/// Writer for the extension's own logs that tags each line with a marker. Plain text lines
+/// are prefixed and JSON lines get an extra field.
+pub struct MarkedWriter<W> {
+    inner: W,
+    json: bool,
+}
+
+impl<W: Write> MarkedWriter<W> {
+    pub fn new(inner: W, json: bool) -> Self {
+        Self { inner, json }
+    }
+}
+
+impl<W: Write> Write for MarkedWriter<W> {
+    // Each formatted event arrives as a single write, so we only mark the start of buf
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.json && buf.first() == Some(&b'{') {
+            let field = format!("{{\"{}\":\"{}\",", OWN_LOG_FIELD, OWN_LOG_SOURCE);
+            self.inner.write_all(field.as_bytes())?;
+            self.inner.write_all(&buf[1..])?;
+        } else {
+            self.inner.write_all(OWN_LOG_PREFIX.as_bytes())?;
+            self.inner.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Logs converted from a batch of telemetry. Records that can not be converted are dropped
+/// on their own rather than failing the batch, the first error is kept for reporting.
+pub struct ParsedLogs {
+    pub resource_logs: ResourceLogs,
+    pub dropped: usize,
+    pub first_error: Option<String>,
+}
+
+impl ParsedLogs {
+    /// Whether no records were converted
+    pub fn is_empty(&self) -> bool {
+        self.resource_logs
+            .scope_logs
+            .iter()
+            .all(|sl| sl.log_records.is_empty())
+    }
+}
+
+/// Converts logs that arrived at the given time, which is the observed time of the records
+/// unless configured otherwise
+///
+/// ```
+/// use chrono::{DateTime, Utc};
+/// use opentelemetry_proto::tonic::resource::v1::Resource;
+/// use rotel_extension::lambda::logs::{Log, LogsConfig, parse_logs};
+/// use std::time::SystemTime;
+///
+/// let time: DateTime<Utc> = "2022-10-12T00:00:00.100Z".parse().unwrap();
+/// let logs = vec![
+///     Log::Function(time, serde_json::json!("Hello from my function"), None),
+///     Log::Function(
+///         time,
+///         serde_json::json!({"level": "ERROR", "message": "failed"}),
+///         Some("6d68ca91-49c9-448d-89b8-7ca3e6dc66aa".to_string()),
+///     ),
+///     // Neither a string nor a JSON object, so dropped
+///     Log::Function(time, serde_json::json!(42), None),
+/// ];
+///
+/// let parsed = parse_logs(
+///     &Resource::default(),
+///     logs,
+///     &LogsConfig::default(),
+///     SystemTime::now(),
+/// );
+/// assert_eq!(1, parsed.dropped);
+/// let records = &parsed.resource_logs.scope_logs[0].log_records;
+/// assert_eq!(2, records.len());
+/// assert_eq!("SEVERITY_NUMBER_ERROR", records[1].severity_text);
+/// ```
+pub fn parse_logs(
+    resource: &Resource,
+    logs: Vec<Log>,
+    config: &LogsConfig,
+    received: SystemTime,
+) -> ParsedLogs {
     let mut rl = ResourceLogs {
-        resource: Some(resource),
+        resource: Some(resource.clone()),
         ..Default::default()
     };
 
@@ -49,25 +284,43 @@ pub(crate) fn parse_logs(resource: Resource, logs: Vec<Log>) -> Result<ResourceL
     let now = SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap();
+    let received = received
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or(now);
 
-    let log_records: Result<Vec<_>, _> = logs
+    let mut dropped = 0;
+    let mut first_error = None;
+    sl.log_records = logs
         .into_iter()
         .map(|log| {
             let log_type = log.get_type();
-            let (time, record) = log.into_parts();
+            let is_fault = matches!(log, Log::Fault(..) | Log::Error(..));
+            let (time, mut record, inferred_request_id) = log.into_parts();
+            let raw_record = config.include_raw.then(|| record.to_string());
+            if config.parse_nested_json {
+                record = parse_nested_json(record);
+            }
 
             let mut lr = LogRecord::default();
 
             lr.attributes
                 .push(otel_string_attr("type", log_type.as_str()));
             lr.time_unix_nano = time.timestamp_nanos_opt().unwrap_or(now.as_nanos() as i64) as u64;
-            lr.observed_time_unix_nano = now.as_nanos() as u64;
+            lr.observed_time_unix_nano = match config.observed_time_source {
+                ObservedTimeSource::Received => received.as_nanos() as u64,
+                ObservedTimeSource::Event => lr.time_unix_nano,
+                ObservedTimeSource::None => 0,
+            };
 
             // Logs can be JSON or String
             // https://docs.aws.amazon.com/lambda/latest/dg/telemetry-schema-reference.html#telemetry-api-function
             match record {
                 Value::Object(mut rec) => {
-                    if let Some(Value::String(ts)) = rec.get("timestamp") {
+                    let record_ts = match config.timestamp_source {
+                        TimestampSource::Record => rec.get("timestamp"),
+                        TimestampSource::Event => None,
+                    };
+                    if let Some(Value::String(ts)) = record_ts {
                         if let Ok(dt) = DateTime::parse_from_rfc3339(ts.as_str()) {
                             if let Some(nanos) = dt.timestamp_nanos_opt() {
                                 lr.time_unix_nano = nanos as u64;
@@ -75,13 +328,28 @@ pub(crate) fn parse_logs(resource: Resource, logs: Vec<Log>) -> Result<ResourceL
                         }
                     }
                     if let Some(Value::String(level)) = rec.get("level") {
-                        lr.severity_number = i32::from(severity_text_to_number(level));
+                        let severity = match config
+                            .severity_map
+                            .get(&level.to_uppercase())
+                            .copied()
+                            .unwrap_or_else(|| severity_text_to_number(level))
+                        {
+                            SeverityNumber::Unspecified => config
+                                .default_severity
+                                .unwrap_or(SeverityNumber::Unspecified),
+                            severity => severity,
+                        };
+                        lr.severity_number = i32::from(severity);
                         lr.severity_text = lr.severity_number().as_str_name().to_string();
                     }
+                    if let Some(trace_id) = record_trace_id(&rec) {
+                        lr.trace_id = trace_id;
+                    }
                     if let Some(Value::String(request_id)) = rec.get("requestId") {
                         lr.attributes
                             .push(otel_string_attr(FAAS_INVOCATION_ID, request_id));
                     }
+                    add_context_attributes(&mut lr.attributes, &mut rec);
                     if let Some(Value::String(msg)) = rec.remove("message") {
                         lr.body = Some(AnyValue {
                             value: Some(StringValue(msg)),
@@ -92,6 +360,23 @@ pub(crate) fn parse_logs(resource: Resource, logs: Vec<Log>) -> Result<ResourceL
                                 value: Some(StringValue(msg)),
                             })
                         }
+                        if !fields.is_empty() {
+                            rec.insert("fields".to_string(), Value::Object(fields));
+                        }
+                    }
+
+                    if let Some(depth) = config.flatten_depth {
+                        for (key, value) in rec {
+                            if FLATTEN_SKIP_FIELDS.contains(&key.as_str()) {
+                                continue;
+                            }
+                            flatten_value(
+                                &mut lr.attributes,
+                                format!("{}.{}", FLATTEN_PREFIX, key),
+                                value,
+                                depth,
+                            );
+                        }
                     }
                 }
                 Value::String(rec) => {
@@ -104,18 +389,286 @@ pub(crate) fn parse_logs(resource: Resource, logs: Vec<Log>) -> Result<ResourceL
                 }
             };
 
+            if is_fault {
+                lr.severity_number = i32::from(SeverityNumber::Error);
+                lr.severity_text = lr.severity_number().as_str_name().to_string();
+                if let Some(request_id) = lr.body.as_ref().and_then(fault_request_id) {
+                    lr.attributes
+                        .push(otel_string_attr(FAAS_INVOCATION_ID, request_id));
+                }
+            }
+
+            if let Some(request_id) = inferred_request_id {
+                if !lr.attributes.iter().any(|kv| kv.key == FAAS_INVOCATION_ID) {
+                    lr.attributes
+                        .push(otel_string_attr(FAAS_INVOCATION_ID, request_id.as_str()));
+                    lr.attributes
+                        .push(otel_bool_attr(INFERRED_INVOCATION_ID_ATTR, true));
+                }
+            }
+
+            if let Some(raw_record) = raw_record {
+                lr.attributes
+                    .push(otel_string_attr(RAW_RECORD_ATTR, raw_record.as_str()));
+            }
+
+            if let Some(max_bytes) = config.max_body_bytes {
+                truncate_body(&mut lr, max_bytes);
+            }
+
             Ok(lr)
         })
+        .filter_map(|res| match res {
+            Ok(lr) => Some(lr),
+            Err(e) => {
+                dropped += 1;
+                first_error.get_or_insert(e);
+                None
+            }
+        })
         .collect();
 
-    match log_records {
-        Ok(lr) => sl.log_records = lr,
-        Err(e) => return Err(format!("Failed to parse log records: {}", e).into()),
+    rl.scope_logs = vec![sl];
+
+    ParsedLogs {
+        resource_logs: rl,
+        dropped,
+        first_error,
     }
+}
 
-    rl.scope_logs = vec![sl];
+// Moves the known Lambda context fields of a record to their attributes, so they are not
+// also flattened as generic record fields
+fn add_context_attributes(attrs: &mut Vec<KeyValue>, rec: &mut Map<String, Value>) {
+    for (field, attr) in LAMBDA_CONTEXT_FIELDS {
+        let Some(value) = rec.remove(*field) else {
+            continue;
+        };
+        if attrs.iter().any(|kv| kv.key == *attr) {
+            continue;
+        }
 
-    Ok(rl)
+        let kv = match (*attr, value) {
+            (FAAS_MAX_MEMORY, value) => memory_bytes(&value).map(|b| otel_int_attr(attr, b)),
+            (_, Value::String(s)) => Some(otel_string_attr(attr, &s)),
+            _ => None,
+        };
+        attrs.extend(kv);
+    }
+}
+
+// faas.max_memory is in bytes, runtimes log the limit in MB as a number or a string
+fn memory_bytes(value: &Value) -> Option<i64> {
+    let mb = match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse::<i64>().ok(),
+        _ => None,
+    };
+    mb.and_then(|mb| mb.checked_mul(BYTES_PER_MB))
+}
+
+/// Converts a batch of telemetry, the JSON array the TelemetryAPI delivers, to OTLP logs
+/// under the given resource, the same way the extension does. Function, extension,
+/// platform.fault and platform.error events become log records, and a platform.start event sets the request
+/// id of the logs that follow it. The extension's own logs are skipped, as are records that
+/// can not be converted, use [`parse_logs`] to count those. Returns an empty list when the
+/// batch has no logs.
+///
+/// ```
+/// use opentelemetry_proto::tonic::common::v1::any_value::Value::StringValue;
+/// use opentelemetry_proto::tonic::resource::v1::Resource;
+/// use rotel_extension::lambda::logs::{LogsConfig, resource_logs_from_telemetry};
+///
+/// let telemetry = br#"[
+///     {
+///         "time": "2022-10-12T00:00:00.000Z",
+///         "type": "platform.start",
+///         "record": {"requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa", "version": "$LATEST"}
+///     },
+///     {
+///         "time": "2022-10-12T00:00:00.100Z",
+///         "type": "function",
+///         "record": "Hello from my function"
+///     },
+///     {
+///         "time": "2022-10-12T00:00:00.200Z",
+///         "type": "function",
+///         "record": {"timestamp": "2022-10-12T00:00:00.200Z", "level": "WARN", "message": "slow"}
+///     }
+/// ]"#;
+///
+/// let resource_logs =
+///     resource_logs_from_telemetry(telemetry, &Resource::default(), &LogsConfig::default())
+///         .unwrap();
+/// assert_eq!(1, resource_logs.len());
+///
+/// let records = &resource_logs[0].scope_logs[0].log_records;
+/// assert_eq!(2, records.len());
+/// assert_eq!(
+///     Some(StringValue("Hello from my function".to_string())),
+///     records[0].body.as_ref().and_then(|b| b.value.clone())
+/// );
+/// assert_eq!("SEVERITY_NUMBER_WARN", records[1].severity_text);
+///
+/// // The request id is taken from the platform.start event
+/// assert!(records[0].attributes.iter().any(|kv| kv.key == "faas.invocation_id"));
+/// ```
+pub fn resource_logs_from_telemetry(
+    json: &[u8],
+    resource: &Resource,
+    config: &LogsConfig,
+) -> Result<Vec<ResourceLogs>, BoxError> {
+    let mut request_id = None;
+    let mut logs = vec![];
+    for item in parse_telemetry_events(json)? {
+        let event = match item {
+            TelemetryItem::Event(event) => event,
+            TelemetryItem::Fault(fault) => {
+                logs.push(fault.into_log(request_id.clone()));
+                continue;
+            }
+        };
+
+        match event.record {
+            LambdaTelemetryRecord::Function(log) => {
+                logs.push(Log::Function(event.time, log, request_id.clone()))
+            }
+            LambdaTelemetryRecord::Extension(log) if !is_own_log(&log) => {
+                logs.push(Log::Extension(event.time, log, request_id.clone()))
+            }
+            LambdaTelemetryRecord::PlatformStart { request_id: id, .. } => request_id = Some(id),
+            _ => {}
+        }
+    }
+
+    let parsed = parse_logs(resource, logs, config, SystemTime::now());
+    if parsed.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(vec![parsed.resource_logs])
+}
+
+// Strings that do not parse as a JSON object are kept as plain text
+fn parse_nested_json(record: Value) -> Value {
+    match record {
+        Value::String(s) if s.trim_start().starts_with('{') => {
+            match serde_json::from_str::<Map<String, Value>>(&s) {
+                Ok(rec) => Value::Object(rec),
+                Err(_) => Value::String(s),
+            }
+        }
+        record => record,
+    }
+}
+
+fn record_trace_id(rec: &Map<String, Value>) -> Option<Vec<u8>> {
+    TRACE_ID_FIELDS
+        .iter()
+        .filter_map(|field| rec.get(*field)?.as_str())
+        .find_map(parse_trace_id)
+}
+
+// Accepts a 32 character hex trace id, or an X-Ray trace id optionally in its Root= form
+fn parse_trace_id(s: &str) -> Option<Vec<u8>> {
+    let s = s.strip_prefix("Root=").unwrap_or(s);
+    let s = s.split(';').next().unwrap_or_default();
+    let hex_id = match s.strip_prefix("1-") {
+        Some(xray) => xray.replace('-', ""),
+        None => s.to_string(),
+    };
+    if hex_id.len() != 32 {
+        return None;
+    }
+
+    hex::decode(hex_id)
+        .ok()
+        .filter(|id| id.iter().any(|b| *b != 0))
+}
+
+// Fault messages name the failed request, e.g. "RequestId: <id> Error: Runtime exited ..."
+fn fault_request_id(body: &AnyValue) -> Option<&str> {
+    match &body.value {
+        Some(StringValue(msg)) => msg.strip_prefix("RequestId: ")?.split_whitespace().next(),
+        _ => None,
+    }
+}
+
+// Truncates a string body that exceeds max_bytes, respecting UTF-8 character boundaries
+fn truncate_body(lr: &mut LogRecord, max_bytes: usize) {
+    if let Some(AnyValue {
+        value: Some(StringValue(body)),
+    }) = lr.body.as_mut()
+    {
+        if body.len() <= max_bytes {
+            return;
+        }
+
+        let mut idx = max_bytes;
+        while !body.is_char_boundary(idx) {
+            idx -= 1;
+        }
+        body.truncate(idx);
+        body.push_str(TRUNCATED_MARKER);
+
+        lr.attributes.push(otel_bool_attr(TRUNCATED_ATTR, true));
+    }
+}
+
+// Adds a JSON value as attributes under key. Objects are expanded into dotted keys while
+// depth remains, after which they are kept as JSON strings, as are arrays.
+fn flatten_value(attrs: &mut Vec<KeyValue>, key: String, value: Value, depth: usize) {
+    let value = match value {
+        Value::Null => return,
+        Value::Object(map) if depth > 1 => {
+            for (k, v) in map {
+                flatten_value(attrs, format!("{}.{}", key, k), v, depth - 1);
+            }
+            return;
+        }
+        Value::String(s) => StringValue(s),
+        Value::Bool(b) => BoolValue(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => IntValue(i),
+            None => DoubleValue(n.as_f64().unwrap_or_default()),
+        },
+        v @ (Value::Array(_) | Value::Object(_)) => StringValue(v.to_string()),
+    };
+
+    attrs.push(KeyValue {
+        key,
+        value: Some(AnyValue { value: Some(value) }),
+    });
+}
+
+/// Parse a severity level name, such as INFO or warn
+pub fn parse_severity(level: &str) -> Result<SeverityNumber, String> {
+    match severity_text_to_number(&level.to_string()) {
+        SeverityNumber::Unspecified => Err(format!("unknown log severity '{}'", level)),
+        severity => Ok(severity),
+    }
+}
+
+/// Parse a comma-separated list of NAME=SEVERITY pairs, such as SEVERE=ERROR,CONFIG=DEBUG.
+/// Names are matched case-insensitively and each severity must be a built-in level name.
+pub fn parse_severity_map(s: &str) -> Result<HashMap<String, SeverityNumber>, String> {
+    let mut map = HashMap::new();
+    for entry in s.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let (name, level) = entry.split_once('=').ok_or_else(|| {
+            format!(
+                "invalid severity mapping '{}', expected NAME=SEVERITY",
+                entry
+            )
+        })?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!(
+                "invalid severity mapping '{}', missing name",
+                entry
+            ));
+        }
+        map.insert(name.to_uppercase(), parse_severity(level.trim())?);
+    }
+    Ok(map)
 }
 
 fn severity_text_to_number(level: &String) -> SeverityNumber {
@@ -155,19 +708,29 @@ fn severity_text_to_number(level: &String) -> SeverityNumber {
 
 #[cfg(test)]
 mod tests {
-    use crate::lambda::logs::{Log, parse_logs};
-    use crate::lambda::otel_string_attr;
+    use crate::lambda::logs::{
+        Log, LogsConfig, MarkedWriter, ObservedTimeSource, TRUNCATED_ATTR, TRUNCATED_MARKER,
+        TimestampSource, is_own_log, parse_logs, parse_observed_time_source, parse_severity,
+        parse_severity_map, parse_timestamp_source, parse_trace_id,
+    };
+    use crate::lambda::{otel_int_attr, otel_string_attr};
     use chrono::DateTime;
     use lambda_extension::LambdaTelemetryRecord;
     use opentelemetry_proto::tonic::common::v1::KeyValue;
-    use opentelemetry_proto::tonic::common::v1::any_value::Value::StringValue;
+    use opentelemetry_proto::tonic::common::v1::any_value::Value::{
+        BoolValue, IntValue, StringValue,
+    };
     use opentelemetry_proto::tonic::logs::v1::SeverityNumber;
     use opentelemetry_proto::tonic::resource::v1::Resource;
-    use opentelemetry_semantic_conventions::attribute::FAAS_INVOCATION_ID;
+    use opentelemetry_semantic_conventions::attribute::{
+        CLOUD_RESOURCE_ID, FAAS_INVOCATION_ID, FAAS_MAX_MEMORY, FAAS_NAME, FAAS_VERSION,
+    };
     use opentelemetry_semantic_conventions::resource::SERVICE_NAME;
     use serde_json::Value;
     use std::collections::HashMap;
+    use std::io::Write;
     use std::ops::{Add, Sub};
+    use std::sync::Arc;
     use std::time::{Duration, SystemTime};
 
     #[test]
@@ -216,11 +779,16 @@ mod tests {
                     ("requestId", Value::String("1234abcd".to_string())),
                     ("message", Value::String("the message".to_string())),
                 ]))),
+                Some("inferred".to_string()),
+            ),
+            Log::Extension(
+                tm3,
+                Value::String("INFO Plain text message".to_string()),
+                None,
             ),
-            Log::Extension(tm3, Value::String("INFO Plain text message".to_string())),
         ];
 
-        let mut res = parse_logs(r, logs).unwrap();
+        let mut res = parse_logs(&r, logs, &LogsConfig::default(), SystemTime::now()).resource_logs;
 
         assert_eq!(1, res.scope_logs.len());
         assert_eq!(2, res.scope_logs[0].log_records.len());
@@ -265,6 +833,160 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_log_parse_include_raw() {
+        let tm = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
+        let logs = || {
+            vec![
+                Log::Function(
+                    tm,
+                    serde_json::json!({"level": "info", "message": "structured"}),
+                    None,
+                ),
+                Log::Function(tm, Value::String("INFO plain text".to_string()), None),
+            ]
+        };
+
+        let config = LogsConfig {
+            include_raw: true,
+            ..Default::default()
+        };
+        let res = parse_logs(&Resource::default(), logs(), &config, SystemTime::now());
+        let records = &res.resource_logs.scope_logs[0].log_records;
+        assert_eq!(
+            Some(r#"{"level":"info","message":"structured"}"#.to_string()),
+            find_str_attr(&records[0].attributes, "lambda.raw_record")
+        );
+        assert_eq!(
+            Some(r#""INFO plain text""#.to_string()),
+            find_str_attr(&records[1].attributes, "lambda.raw_record")
+        );
+
+        let res = parse_logs(
+            &Resource::default(),
+            logs(),
+            &LogsConfig::default(),
+            SystemTime::now(),
+        );
+        for lr in &res.resource_logs.scope_logs[0].log_records {
+            assert_eq!(None, find_str_attr(&lr.attributes, "lambda.raw_record"));
+        }
+    }
+
+    #[test]
+    fn test_log_parse_nested_json() {
+        let tm = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
+        let logs = || {
+            vec![
+                Log::Function(
+                    tm,
+                    Value::String(
+                        r#"{"level":"ERROR","requestId":"1234abcd","message":"nested"}"#
+                            .to_string(),
+                    ),
+                    None,
+                ),
+                Log::Function(tm, Value::String("{not json".to_string()), None),
+                Log::Function(tm, Value::String("INFO plain text".to_string()), None),
+            ]
+        };
+
+        let config = LogsConfig {
+            parse_nested_json: true,
+            ..Default::default()
+        };
+        let res =
+            parse_logs(&Resource::default(), logs(), &config, SystemTime::now()).resource_logs;
+        let records = &res.scope_logs[0].log_records;
+        assert_eq!(3, records.len());
+
+        assert_eq!(SeverityNumber::Error as i32, records[0].severity_number);
+        assert_eq!(
+            Some("1234abcd".to_string()),
+            find_str_attr(&records[0].attributes, FAAS_INVOCATION_ID)
+        );
+        assert_eq!(
+            StringValue("nested".to_string()),
+            records[0].body.clone().unwrap().value.unwrap()
+        );
+
+        assert_eq!(
+            SeverityNumber::Unspecified as i32,
+            records[1].severity_number
+        );
+        assert_eq!(
+            StringValue("{not json".to_string()),
+            records[1].body.clone().unwrap().value.unwrap()
+        );
+        assert_eq!(
+            StringValue("INFO plain text".to_string()),
+            records[2].body.clone().unwrap().value.unwrap()
+        );
+
+        // Disabled by default, the encoded record is kept as the body
+        let res = parse_logs(
+            &Resource::default(),
+            logs(),
+            &LogsConfig::default(),
+            SystemTime::now(),
+        )
+        .resource_logs;
+        let record = &res.scope_logs[0].log_records[0];
+        assert_eq!(SeverityNumber::Unspecified as i32, record.severity_number);
+        assert_eq!(
+            StringValue(
+                r#"{"level":"ERROR","requestId":"1234abcd","message":"nested"}"#.to_string()
+            ),
+            record.body.clone().unwrap().value.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_log_parse_shared_resource() {
+        let tm = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
+        let mut r = Resource::default();
+        r.attributes
+            .push(otel_string_attr(SERVICE_NAME, "test_log_parse"));
+        let shared = Arc::new(r.clone());
+
+        let logs = || {
+            vec![Log::Function(
+                tm,
+                Value::String("INFO Plain text message".to_string()),
+                None,
+            )]
+        };
+
+        let received = SystemTime::now();
+        let owned = parse_logs(&r, logs(), &LogsConfig::default(), received).resource_logs;
+        let first = parse_logs(&shared, logs(), &LogsConfig::default(), received).resource_logs;
+        let second = parse_logs(&shared, logs(), &LogsConfig::default(), received).resource_logs;
+
+        assert_eq!(owned, first);
+        assert_eq!(first, second);
+        assert_eq!(Some(r), first.resource);
+    }
+
+    #[test]
+    fn test_marked_writer() {
+        let mut w = MarkedWriter::new(Vec::new(), false);
+        w.write_all(b" INFO Extension started\n").unwrap();
+        let line = String::from_utf8(w.inner).unwrap();
+        assert!(is_own_log(&Value::String(line.trim_end().to_string())));
+
+        let mut w = MarkedWriter::new(Vec::new(), true);
+        w.write_all(b"{\"level\":\"INFO\",\"fields\":{\"message\":\"started\"}}\n")
+            .unwrap();
+        let record: Value = serde_json::from_slice(&w.inner).unwrap();
+        assert!(is_own_log(&record));
+        assert_eq!("INFO", record["level"]);
+
+        assert!(!is_own_log(&Value::String(
+            "INFO other extension".to_string()
+        )));
+        assert!(!is_own_log(&serde_json::json!({"source": "other"})));
+    }
+
     #[test]
     fn test_log_parse_invalid() {
         let tm1 = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
@@ -272,13 +994,306 @@ mod tests {
         r.attributes
             .push(otel_string_attr(SERVICE_NAME, "test_log_parse"));
 
-        let logs = vec![Log::Extension(
-            tm1,
-            Value::Array(vec![Value::String("invalid".to_string())]),
-        )];
+        let logs = vec![
+            Log::Function(tm1, Value::String("first".to_string()), None),
+            Log::Extension(
+                tm1,
+                Value::Array(vec![Value::String("invalid".to_string())]),
+                None,
+            ),
+            Log::Function(tm1, Value::String("second".to_string()), None),
+        ];
+
+        // The invalid record is dropped on its own, the valid ones are kept
+        let res = parse_logs(&r, logs, &LogsConfig::default(), SystemTime::now());
+        assert_eq!(1, res.dropped);
+        assert!(res.first_error.unwrap().contains("invalid log record type"));
+        assert!(!res.is_empty());
+
+        let bodies: Vec<_> = res.resource_logs.scope_logs[0]
+            .log_records
+            .iter()
+            .map(|lr| lr.body.clone().unwrap().value.unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                StringValue("first".to_string()),
+                StringValue("second".to_string())
+            ],
+            bodies
+        );
+
+        let logs = vec![Log::Extension(tm1, Value::Bool(true), None)];
+        let res = parse_logs(&r, logs, &LogsConfig::default(), SystemTime::now());
+        assert_eq!(1, res.dropped);
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn test_log_timestamp_source() {
+        let event_tm = DateTime::parse_from_rfc3339("2022-10-12T00:03:50Z")
+            .unwrap()
+            .to_utc();
+        let record_tm = DateTime::parse_from_rfc3339("2022-10-12T00:03:49.5Z")
+            .unwrap()
+            .to_utc();
+        let logs = || {
+            vec![Log::Function(
+                event_tm,
+                Value::Object(json_map(HashMap::from([
+                    (
+                        "timestamp",
+                        Value::String("2022-10-12T00:03:49.500Z".to_string()),
+                    ),
+                    ("message", Value::String("skewed".to_string())),
+                ]))),
+                None,
+            )]
+        };
+        let time_unix_nano = |source| {
+            let config = LogsConfig {
+                timestamp_source: source,
+                ..Default::default()
+            };
+            parse_logs(&Resource::default(), logs(), &config, SystemTime::now())
+                .resource_logs
+                .scope_logs[0]
+                .log_records[0]
+                .time_unix_nano
+        };
+
+        assert_eq!(
+            TimestampSource::Record,
+            LogsConfig::default().timestamp_source
+        );
+        assert_eq!(
+            record_tm.timestamp_nanos_opt().unwrap() as u64,
+            time_unix_nano(TimestampSource::Record)
+        );
+        assert_eq!(
+            event_tm.timestamp_nanos_opt().unwrap() as u64,
+            time_unix_nano(TimestampSource::Event)
+        );
+
+        assert_eq!(
+            Ok(TimestampSource::Record),
+            parse_timestamp_source("record")
+        );
+        assert_eq!(Ok(TimestampSource::Event), parse_timestamp_source("event"));
+        assert!(parse_timestamp_source("now").is_err());
+    }
+
+    #[test]
+    fn test_log_observed_time_source() {
+        let event_tm = DateTime::parse_from_rfc3339("2022-10-12T00:03:50Z")
+            .unwrap()
+            .to_utc();
+        let received = SystemTime::UNIX_EPOCH + Duration::from_secs(1_665_533_100);
+        let observed_time_unix_nano = |source| {
+            let config = LogsConfig {
+                observed_time_source: source,
+                ..Default::default()
+            };
+            let logs = vec![Log::Function(
+                event_tm,
+                Value::String("retried".to_string()),
+                None,
+            )];
+            parse_logs(&Resource::default(), logs, &config, received)
+                .resource_logs
+                .scope_logs[0]
+                .log_records[0]
+                .observed_time_unix_nano
+        };
+
+        assert_eq!(
+            ObservedTimeSource::Received,
+            LogsConfig::default().observed_time_source
+        );
+        assert_eq!(
+            1_665_533_100_000_000_000,
+            observed_time_unix_nano(ObservedTimeSource::Received)
+        );
+        assert_eq!(
+            event_tm.timestamp_nanos_opt().unwrap() as u64,
+            observed_time_unix_nano(ObservedTimeSource::Event)
+        );
+        assert_eq!(0, observed_time_unix_nano(ObservedTimeSource::None));
+
+        assert_eq!(
+            Ok(ObservedTimeSource::Event),
+            parse_observed_time_source("event")
+        );
+        assert_eq!(
+            Ok(ObservedTimeSource::None),
+            parse_observed_time_source("none")
+        );
+        assert!(parse_observed_time_source("now").is_err());
+    }
+
+    #[test]
+    fn test_log_parse_default_severity() {
+        let tm = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
+        let logs = || {
+            vec![
+                Log::Function(
+                    tm,
+                    Value::Object(json_map(HashMap::from([
+                        ("level", Value::String("notice".to_string())),
+                        ("message", Value::String("unmapped level".to_string())),
+                    ]))),
+                    None,
+                ),
+                Log::Function(
+                    tm,
+                    Value::Object(json_map(HashMap::from([(
+                        "message",
+                        Value::String("no level".to_string()),
+                    )]))),
+                    None,
+                ),
+            ]
+        };
+
+        let config = LogsConfig {
+            default_severity: Some(parse_severity("INFO").unwrap()),
+            ..Default::default()
+        };
+        let res =
+            parse_logs(&Resource::default(), logs(), &config, SystemTime::now()).resource_logs;
+        let records = &res.scope_logs[0].log_records;
+        assert_eq!(SeverityNumber::Info as i32, records[0].severity_number);
+        assert_eq!(
+            SeverityNumber::Unspecified as i32,
+            records[1].severity_number
+        );
+
+        // Without a default, unmapped levels stay unspecified
+        let res = parse_logs(
+            &Resource::default(),
+            logs(),
+            &LogsConfig::default(),
+            SystemTime::now(),
+        )
+        .resource_logs;
+        let records = &res.scope_logs[0].log_records;
+        assert_eq!(
+            SeverityNumber::Unspecified as i32,
+            records[0].severity_number
+        );
+
+        assert!(parse_severity("notice").is_err());
+        assert_eq!(Ok(SeverityNumber::Warn), parse_severity("warning"));
+    }
+
+    #[test]
+    fn test_log_parse_severity_map() {
+        let tm = DateTime::from(SystemTime::now());
+        let log = |level: &str| {
+            Log::Function(
+                tm,
+                serde_json::json!({"level": level, "message": "hello"}),
+                None,
+            )
+        };
+        let logs = vec![log("severe"), log("WARN"), log("CONFIG"), log("notice")];
+
+        let config = LogsConfig {
+            severity_map: parse_severity_map("SEVERE=ERROR, warn=fatal,CONFIG=debug,").unwrap(),
+            ..Default::default()
+        };
+        let res = parse_logs(&Resource::default(), logs, &config, SystemTime::now()).resource_logs;
+        let severities: Vec<i32> = res.scope_logs[0]
+            .log_records
+            .iter()
+            .map(|lr| lr.severity_number)
+            .collect();
+        assert_eq!(
+            vec![
+                SeverityNumber::Error as i32,
+                // Custom mappings override the built-in names
+                SeverityNumber::Fatal as i32,
+                SeverityNumber::Debug as i32,
+                // Unknown without a mapping stays unspecified
+                SeverityNumber::Unspecified as i32,
+            ],
+            severities
+        );
+        assert_eq!(
+            SeverityNumber::Error.as_str_name(),
+            res.scope_logs[0].log_records[0].severity_text
+        );
+
+        assert!(parse_severity_map("").unwrap().is_empty());
+        assert!(parse_severity_map("SEVERE").is_err());
+        assert!(parse_severity_map("=ERROR").is_err());
+        assert!(parse_severity_map("SEVERE=LOUD").is_err());
+    }
+
+    #[test]
+    fn test_log_parse_flatten_nested() {
+        let tm = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
+        let record = serde_json::json!({
+            "timestamp": "2022-10-12T00:03:50.000Z",
+            "level": "INFO",
+            "requestId": "1234abcd",
+            "message": "order placed",
+            "order": {
+                "id": 42,
+                "customer": {"tier": "gold", "tags": ["a", "b"]},
+                "gift": false,
+            },
+            "note": null,
+        });
+
+        let config = LogsConfig {
+            flatten_depth: Some(2),
+            ..Default::default()
+        };
+        let res = parse_logs(
+            &Resource::default(),
+            vec![Log::Function(tm, record.clone(), None)],
+            &config,
+            SystemTime::now(),
+        )
+        .resource_logs;
+        let attrs = &res.scope_logs[0].log_records[0].attributes;
+
+        let find = |key: &str| {
+            attrs
+                .iter()
+                .find(|kv| kv.key == key)
+                .and_then(|kv| kv.value.clone().unwrap().value)
+        };
+        assert_eq!(Some(IntValue(42)), find("record.order.id"));
+        assert_eq!(Some(BoolValue(false)), find("record.order.gift"));
+        // Beyond the configured depth, objects are kept as JSON
+        match find("record.order.customer") {
+            Some(StringValue(json)) => assert_eq!(
+                serde_json::json!({"tier": "gold", "tags": ["a", "b"]}),
+                serde_json::from_str::<Value>(&json).unwrap()
+            ),
+            v => panic!("expected JSON string, got {:?}", v),
+        }
+        // Known fields keep their special handling and are not duplicated
+        assert_eq!(None, find("record.message"));
+        assert_eq!(None, find("record.requestId"));
+        assert_eq!(None, find("record.note"));
+        assert_eq!(
+            Some(StringValue("1234abcd".to_string())),
+            find(FAAS_INVOCATION_ID)
+        );
 
-        let res = parse_logs(r, logs);
-        assert!(res.is_err())
+        // Flattening is off by default
+        let res = parse_logs(
+            &Resource::default(),
+            vec![Log::Function(tm, record, None)],
+            &LogsConfig::default(),
+            SystemTime::now(),
+        )
+        .resource_logs;
+        let attrs = &res.scope_logs[0].log_records[0].attributes;
+        assert!(!attrs.iter().any(|kv| kv.key.starts_with("record.")));
     }
 
     #[test]
@@ -304,9 +1319,10 @@ mod tests {
                     )]))),
                 ),
             ]))),
+            None,
         )];
 
-        let mut res = parse_logs(r, logs).unwrap();
+        let mut res = parse_logs(&r, logs, &LogsConfig::default(), SystemTime::now()).resource_logs;
 
         assert_eq!(1, res.scope_logs.len());
         assert_eq!(1, res.scope_logs[0].log_records.len());
@@ -332,6 +1348,166 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_log_parse_lambda_context() {
+        let tm1 = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
+        let arn = "arn:aws:lambda:us-east-1:123456789012:function:my-function";
+
+        let logs = vec![
+            Log::Function(
+                tm1,
+                Value::Object(json_map(HashMap::from([
+                    ("message", Value::String("python context".to_string())),
+                    ("aws_request_id", Value::String("1234abcd".to_string())),
+                    ("function_arn", Value::String(arn.to_string())),
+                    ("function_name", Value::String("my-function".to_string())),
+                    ("function_version", Value::String("$LATEST".to_string())),
+                    ("memory_limit_in_mb", Value::String("128".to_string())),
+                ]))),
+                None,
+            ),
+            Log::Function(
+                tm1,
+                Value::Object(json_map(HashMap::from([
+                    ("message", Value::String("record request id".to_string())),
+                    ("requestId", Value::String("5678efgh".to_string())),
+                    ("aws_request_id", Value::String("1234abcd".to_string())),
+                    ("invoked_function_arn", Value::String(arn.to_string())),
+                    ("memory_limit_in_mb", Value::from(256)),
+                ]))),
+                None,
+            ),
+        ];
+
+        let config = LogsConfig {
+            flatten_depth: Some(1),
+            ..Default::default()
+        };
+        let res = parse_logs(&Resource::default(), logs, &config, SystemTime::now()).resource_logs;
+        let records = &res.scope_logs[0].log_records;
+
+        let attrs = &records[0].attributes;
+        assert_eq!(
+            Some("1234abcd".to_string()),
+            find_str_attr(attrs, FAAS_INVOCATION_ID)
+        );
+        assert_eq!(
+            Some(arn.to_string()),
+            find_str_attr(attrs, CLOUD_RESOURCE_ID)
+        );
+        assert_eq!(
+            Some("my-function".to_string()),
+            find_str_attr(attrs, FAAS_NAME)
+        );
+        assert_eq!(
+            Some("$LATEST".to_string()),
+            find_str_attr(attrs, FAAS_VERSION)
+        );
+        assert!(attrs.contains(&otel_int_attr(FAAS_MAX_MEMORY, 128 * 1024 * 1024)));
+        // Mapped fields are not flattened as well
+        assert!(!attrs.iter().any(|kv| kv.key.starts_with("record.")));
+
+        // requestId takes precedence over aws_request_id
+        let attrs = &records[1].attributes;
+        let invocation_ids: Vec<&KeyValue> = attrs
+            .iter()
+            .filter(|kv| kv.key == FAAS_INVOCATION_ID)
+            .collect();
+        assert_eq!(1, invocation_ids.len());
+        assert_eq!(
+            Some("5678efgh".to_string()),
+            find_str_attr(attrs, FAAS_INVOCATION_ID)
+        );
+        assert_eq!(
+            Some(arn.to_string()),
+            find_str_attr(attrs, CLOUD_RESOURCE_ID)
+        );
+        assert!(attrs.contains(&otel_int_attr(FAAS_MAX_MEMORY, 256 * 1024 * 1024)));
+    }
+
+    #[test]
+    fn test_log_parse_truncate_body() {
+        let tm1 = DateTime::from(SystemTime::now().sub(Duration::from_secs(3600)));
+        let config = LogsConfig {
+            max_body_bytes: Some(10),
+            ..Default::default()
+        };
+
+        let logs = vec![
+            Log::Function(tm1, Value::String("a".repeat(32)), None),
+            Log::Function(tm1, Value::String("short".to_string()), None),
+        ];
+
+        let mut res =
+            parse_logs(&Resource::default(), logs, &config, SystemTime::now()).resource_logs;
+
+        let under = res.scope_logs[0].log_records.pop().unwrap();
+        let over = res.scope_logs[0].log_records.pop().unwrap();
+
+        assert_eq!(
+            StringValue(format!("{}{}", "a".repeat(10), TRUNCATED_MARKER)),
+            over.body.unwrap().value.unwrap()
+        );
+        assert_eq!(
+            Some(BoolValue(true)),
+            over.attributes
+                .iter()
+                .find(|kv| kv.key == TRUNCATED_ATTR)
+                .and_then(|kv| kv.value.clone().unwrap().value)
+        );
+
+        assert_eq!(
+            StringValue("short".to_string()),
+            under.body.unwrap().value.unwrap()
+        );
+        assert!(!under.attributes.iter().any(|kv| kv.key == TRUNCATED_ATTR));
+    }
+
+    #[test]
+    fn test_log_parse_trace_id() {
+        let tm = DateTime::from(SystemTime::now());
+        let trace_id = hex::decode("5f35ae120c0fec141ab77a00bc047aa2").unwrap();
+
+        let logs = vec![
+            Log::Function(
+                tm,
+                serde_json::json!({"message": "w3c", "traceId": "5f35ae120c0fec141ab77a00bc047aa2"}),
+                None,
+            ),
+            Log::Function(
+                tm,
+                serde_json::json!({"message": "xray", "xray_trace_id": "1-5f35ae12-0c0fec141ab77a00bc047aa2"}),
+                None,
+            ),
+            Log::Function(
+                tm,
+                serde_json::json!({"message": "invalid", "trace_id": "not-a-trace"}),
+                None,
+            ),
+        ];
+        let records = parse_logs(
+            &Resource::default(),
+            logs,
+            &LogsConfig::default(),
+            SystemTime::now(),
+        )
+        .resource_logs
+        .scope_logs
+        .remove(0)
+        .log_records;
+
+        assert_eq!(trace_id, records[0].trace_id);
+        assert_eq!(trace_id, records[1].trace_id);
+        assert!(records[2].trace_id.is_empty());
+
+        assert_eq!(
+            Some(trace_id),
+            parse_trace_id("Root=1-5f35ae12-0c0fec141ab77a00bc047aa2;Parent=53995c3f42cd8ad8")
+        );
+        assert_eq!(None, parse_trace_id("00000000000000000000000000000000"));
+        assert_eq!(None, parse_trace_id("1-5f35ae12"));
+    }
+
     fn json_map(m: HashMap<&str, Value>) -> serde_json::Map<String, Value> {
         let mut new_map = serde_json::Map::new();
         for (k, v) in m.into_iter() {
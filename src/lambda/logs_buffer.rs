@@ -0,0 +1,163 @@
+use opentelemetry_proto::tonic::logs::v1::ResourceLogs;
+
+/// Collects converted logs between flushes, merging batches that share a resource so that
+/// a flush sends one larger batch instead of one per TelemetryAPI request.
+pub struct LogsBuffer {
+    batches: Vec<ResourceLogs>,
+    records: usize,
+    max_records: usize,
+}
+
+impl LogsBuffer {
+    pub fn new(max_records: usize) -> Self {
+        Self {
+            batches: Vec::new(),
+            records: 0,
+            max_records: max_records.max(1),
+        }
+    }
+
+    /// Adds a batch of logs. If it would take the buffer past `max_records`, the batches
+    /// buffered so far are returned to be sent now, and the new batch starts a new window.
+    pub fn push(&mut self, rl: ResourceLogs) -> Option<Vec<ResourceLogs>> {
+        let count = record_count(&rl);
+        let full =
+            (self.records > 0 && self.records + count > self.max_records).then(|| self.take());
+
+        self.records += count;
+        merge(&mut self.batches, rl);
+        full
+    }
+
+    /// Removes and returns every buffered batch
+    pub fn take(&mut self) -> Vec<ResourceLogs> {
+        self.records = 0;
+        std::mem::take(&mut self.batches)
+    }
+
+    /// Puts back batches that were taken but could not be sent, ahead of any batches
+    /// buffered since. The cap is not applied, these records were already admitted.
+    pub fn restore(&mut self, batches: Vec<ResourceLogs>) {
+        self.records += batches.iter().map(record_count).sum::<usize>();
+        let newer = std::mem::take(&mut self.batches);
+        for rl in batches.into_iter().chain(newer) {
+            merge(&mut self.batches, rl);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+}
+
+fn record_count(rl: &ResourceLogs) -> usize {
+    rl.scope_logs.iter().map(|sl| sl.log_records.len()).sum()
+}
+
+// Appends the scopes of a batch to the buffered batch with the same resource, records of a
+// scope that is already present are appended to that scope
+fn merge(batches: &mut Vec<ResourceLogs>, rl: ResourceLogs) {
+    let Some(existing) = batches
+        .iter_mut()
+        .find(|b| b.resource == rl.resource && b.schema_url == rl.schema_url)
+    else {
+        batches.push(rl);
+        return;
+    };
+
+    for sl in rl.scope_logs {
+        match existing
+            .scope_logs
+            .iter_mut()
+            .find(|e| e.scope == sl.scope && e.schema_url == sl.schema_url)
+        {
+            Some(e) => e.log_records.extend(sl.log_records),
+            None => existing.scope_logs.push(sl),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lambda::otel_string_attr;
+    use opentelemetry_proto::tonic::common::v1::InstrumentationScope;
+    use opentelemetry_proto::tonic::logs::v1::{LogRecord, ScopeLogs};
+    use opentelemetry_proto::tonic::resource::v1::Resource;
+
+    fn batch(service: &str, records: usize) -> ResourceLogs {
+        ResourceLogs {
+            resource: Some(Resource {
+                attributes: vec![otel_string_attr("service.name", service)],
+                ..Default::default()
+            }),
+            scope_logs: vec![ScopeLogs {
+                scope: Some(InstrumentationScope {
+                    name: "lambda".to_string(),
+                    ..Default::default()
+                }),
+                log_records: vec![LogRecord::default(); records],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_coalesce_same_resource() {
+        let mut buffer = LogsBuffer::new(100);
+        assert!(buffer.push(batch("fn", 2)).is_none());
+        assert!(buffer.push(batch("fn", 3)).is_none());
+
+        let batches = buffer.take();
+        assert_eq!(1, batches.len());
+        assert_eq!(1, batches[0].scope_logs.len());
+        assert_eq!(5, batches[0].scope_logs[0].log_records.len());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_coalesce_different_resources() {
+        let mut buffer = LogsBuffer::new(100);
+        buffer.push(batch("a", 1));
+        buffer.push(batch("b", 1));
+        buffer.push(batch("a", 1));
+
+        let batches = buffer.take();
+        assert_eq!(2, batches.len());
+        assert_eq!(2, record_count(&batches[0]));
+        assert_eq!(1, record_count(&batches[1]));
+    }
+
+    #[test]
+    fn test_coalesce_max_records() {
+        let mut buffer = LogsBuffer::new(4);
+        assert!(buffer.push(batch("fn", 3)).is_none());
+
+        // Would exceed the cap, so the first window is returned and this batch starts the next
+        let full = buffer.push(batch("fn", 2)).unwrap();
+        assert_eq!(1, full.len());
+        assert_eq!(3, record_count(&full[0]));
+
+        let batches = buffer.take();
+        assert_eq!(2, record_count(&batches[0]));
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut buffer = LogsBuffer::new(4);
+        buffer.push(batch("a", 2));
+        let taken = buffer.take();
+
+        // Logs buffered after the take follow the restored ones
+        buffer.push(batch("b", 1));
+        buffer.push(batch("a", 1));
+        buffer.restore(taken);
+
+        let full = buffer.push(batch("a", 1)).unwrap();
+        assert_eq!(2, full.len());
+        assert_eq!(3, record_count(&full[0]));
+        assert_eq!(1, record_count(&full[1]));
+        assert_eq!(batch("a", 0).resource, full[0].resource);
+    }
+}
@@ -0,0 +1,144 @@
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::logs::v1::ResourceLogs;
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Stdout, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tower::BoxError;
+
+/// Where converted Lambda logs are sent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum LogsSink {
+    /// Forward to the agent logs pipeline and its configured exporters
+    #[default]
+    Agent,
+    /// Write OTLP/JSON lines to stdout
+    Stdout,
+    /// Append OTLP/JSON lines to a file
+    File(PathBuf),
+}
+
+/// Parse a sink of the form `agent`, `stdout` or `file:<path>`
+pub fn parse_logs_sink(s: &str) -> Result<LogsSink, String> {
+    match s {
+        "agent" => Ok(LogsSink::Agent),
+        "stdout" => Ok(LogsSink::Stdout),
+        _ => match s.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => Ok(LogsSink::File(PathBuf::from(path))),
+            _ => Err(format!(
+                "invalid logs sink '{}', expected agent, stdout or file:<path>",
+                s
+            )),
+        },
+    }
+}
+
+enum Output {
+    Stdout(Stdout),
+    File(LineWriter<File>),
+}
+
+/// Writes each batch of logs as a single line of OTLP/JSON, the same encoding used by
+/// the OpenTelemetry collector file exporter.
+pub struct JsonLogsWriter {
+    out: Mutex<Output>,
+}
+
+impl JsonLogsWriter {
+    /// Open a writer for the sink, returns None when logs go to the agent.
+    pub fn from_sink(sink: &LogsSink) -> Result<Option<Self>, BoxError> {
+        let out = match sink {
+            LogsSink::Agent => return Ok(None),
+            LogsSink::Stdout => Output::Stdout(std::io::stdout()),
+            LogsSink::File(path) => {
+                let f = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("unable to open logs sink {:?}: {}", path, e))?;
+                Output::File(LineWriter::new(f))
+            }
+        };
+
+        Ok(Some(Self {
+            out: Mutex::new(out),
+        }))
+    }
+
+    pub fn is_stdout(&self) -> bool {
+        matches!(*self.out.lock().unwrap(), Output::Stdout(_))
+    }
+
+    pub fn write(&self, rl: ResourceLogs) -> Result<(), BoxError> {
+        let req = ExportLogsServiceRequest {
+            resource_logs: vec![rl],
+        };
+        let mut line = serde_json::to_vec(&req)?;
+        line.push(b'\n');
+
+        match &mut *self.out.lock().unwrap() {
+            Output::Stdout(s) => s.lock().write_all(&line)?,
+            Output::File(f) => f.write_all(&line)?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lambda::logs::{Log, LogsConfig, parse_logs};
+    use chrono::DateTime;
+    use opentelemetry_proto::tonic::resource::v1::Resource;
+    use serde_json::Value;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_parse_logs_sink() {
+        assert_eq!(Ok(LogsSink::Agent), parse_logs_sink("agent"));
+        assert_eq!(Ok(LogsSink::Stdout), parse_logs_sink("stdout"));
+        assert_eq!(
+            Ok(LogsSink::File(PathBuf::from("/tmp/logs.json"))),
+            parse_logs_sink("file:/tmp/logs.json")
+        );
+        assert!(parse_logs_sink("file:").is_err());
+        assert!(parse_logs_sink("s3://bucket").is_err());
+    }
+
+    #[test]
+    fn test_file_sink_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs.json");
+
+        let tm = DateTime::parse_from_rfc3339("2022-10-12T00:03:50Z")
+            .unwrap()
+            .to_utc();
+        let logs = vec![
+            Log::Function(tm, Value::String("INFO first".to_string()), None),
+            Log::Extension(tm, Value::String("WARN second".to_string()), None),
+        ];
+        let rl = parse_logs(
+            &Resource::default(),
+            logs,
+            &LogsConfig::default(),
+            SystemTime::now(),
+        )
+        .resource_logs;
+
+        let writer = JsonLogsWriter::from_sink(&LogsSink::File(path.clone()))
+            .unwrap()
+            .unwrap();
+        writer.write(rl.clone()).unwrap();
+        writer.write(rl.clone()).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(2, lines.len());
+
+        for line in lines {
+            let req: ExportLogsServiceRequest = serde_json::from_str(line).unwrap();
+            assert_eq!(vec![rl.clone()], req.resource_logs);
+        }
+    }
+}
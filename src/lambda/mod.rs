@@ -1,9 +1,17 @@
-use opentelemetry_proto::tonic::common::v1::any_value::Value::StringValue;
+use opentelemetry_proto::tonic::common::v1::any_value::Value::{
+    BoolValue, DoubleValue, IntValue, StringValue,
+};
 use opentelemetry_proto::tonic::common::v1::{AnyValue, KeyValue};
 
 pub mod api;
 mod constants;
-mod logs;
+pub mod emf;
+pub mod error;
+pub mod invocation_summary;
+pub mod logs;
+pub mod logs_buffer;
+pub mod logs_sink;
+mod request_state;
 pub mod telemetry_api;
 pub mod types;
 
@@ -15,3 +23,30 @@ pub(crate) fn otel_string_attr(key: &str, value: &str) -> KeyValue {
         }),
     }
 }
+
+pub(crate) fn otel_bool_attr(key: &str, value: bool) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(BoolValue(value)),
+        }),
+    }
+}
+
+pub(crate) fn otel_int_attr(key: &str, value: i64) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(IntValue(value)),
+        }),
+    }
+}
+
+pub(crate) fn otel_double_attr(key: &str, value: f64) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(DoubleValue(value)),
+        }),
+    }
+}
@@ -0,0 +1,152 @@
+use lambda_extension::LambdaTelemetryRecord;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Long enough to cover redelivery of a batch, short enough that idle sandboxes do not
+// hold on to old invocations
+const REQUEST_STATE_TTL: Duration = Duration::from_secs(60);
+const MAX_TRACKED_REQUESTS: usize = 256;
+
+const START: u8 = 1;
+const RUNTIME_DONE: u8 = 1 << 1;
+const REPORT: u8 = 1 << 2;
+
+/// Tracks which platform events have been seen for each request id, so that redelivered
+/// events are only processed once regardless of the order they arrive in. Entries expire
+/// after a TTL, and the oldest are evicted once the map is full.
+pub(crate) struct RequestStates {
+    entries: HashMap<String, Entry>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+struct Entry {
+    seen: u8,
+    updated: Instant,
+}
+
+impl Default for RequestStates {
+    fn default() -> Self {
+        Self::new(REQUEST_STATE_TTL, MAX_TRACKED_REQUESTS)
+    }
+}
+
+impl RequestStates {
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    /// Returns false if this is a duplicate of an event already seen for the request.
+    /// Records that are not correlated by request id are always new.
+    pub(crate) fn first_seen<L>(
+        &mut self,
+        record: &LambdaTelemetryRecord<L>,
+        now: Instant,
+    ) -> bool {
+        let (request_id, kind) = match record {
+            LambdaTelemetryRecord::PlatformStart { request_id, .. } => (request_id, START),
+            LambdaTelemetryRecord::PlatformRuntimeDone { request_id, .. } => {
+                (request_id, RUNTIME_DONE)
+            }
+            LambdaTelemetryRecord::PlatformReport { request_id, .. } => (request_id, REPORT),
+            _ => return true,
+        };
+
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, e| now.saturating_duration_since(e.updated) < ttl);
+
+        if let Some(e) = self.entries.get_mut(request_id) {
+            if e.seen & kind != 0 {
+                return false;
+            }
+            e.seen |= kind;
+            e.updated = now;
+            return true;
+        }
+
+        if self.entries.len() >= self.capacity {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.updated)
+                .map(|(id, _)| id.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(
+            request_id.clone(),
+            Entry {
+                seen: kind,
+                updated: now,
+            },
+        );
+        true
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_extension::LambdaTelemetry;
+
+    fn record(kind: &str, request_id: &str) -> LambdaTelemetryRecord<serde_json::Value> {
+        let event: LambdaTelemetry<serde_json::Value> = serde_json::from_value(serde_json::json!({
+            "time": "2022-10-12T00:00:00.000Z",
+            "type": kind,
+            "record": {
+                "requestId": request_id,
+                "status": "success",
+            },
+        }))
+        .unwrap();
+        event.record
+    }
+
+    #[test]
+    fn test_duplicate_events() {
+        let mut states = RequestStates::default();
+        let now = Instant::now();
+
+        // Out of order, runtimeDone before start
+        assert!(states.first_seen(&record("platform.runtimeDone", "a"), now));
+        assert!(states.first_seen(&record("platform.start", "a"), now));
+        assert!(!states.first_seen(&record("platform.start", "a"), now));
+        assert!(!states.first_seen(&record("platform.runtimeDone", "a"), now));
+
+        // Other requests are tracked independently
+        assert!(states.first_seen(&record("platform.start", "b"), now));
+        assert_eq!(2, states.len());
+    }
+
+    #[test]
+    fn test_request_state_bounded() {
+        let mut states = RequestStates::new(Duration::from_secs(60), 4);
+        let now = Instant::now();
+
+        for i in 0..10 {
+            let at = now + Duration::from_millis(i);
+            assert!(states.first_seen(&record("platform.start", &format!("req-{}", i)), at));
+        }
+        assert_eq!(4, states.len());
+
+        // The oldest were evicted, so they are no longer recognized as duplicates
+        let at = now + Duration::from_millis(10);
+        assert!(states.first_seen(&record("platform.start", "req-0"), at));
+        assert!(!states.first_seen(&record("platform.start", "req-9"), at));
+
+        // Everything expires after the TTL
+        let later = now + Duration::from_secs(120);
+        assert!(states.first_seen(&record("platform.start", "req-9"), later));
+        assert_eq!(1, states.len());
+    }
+}
@@ -1,4 +1,13 @@
+use crate::lambda::constants::{TELEMETRY_API_SCHEMA, TELEMETRY_API_SCHEMAS};
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+// Telemetry types, and buffering limits, accepted by the TelemetryAPI
+// https://docs.aws.amazon.com/lambda/latest/dg/telemetry-api-reference.html
+const TELEMETRY_TYPES: &[&str] = &["platform", "function", "extension"];
+const BUFFERING_MAX_ITEMS: RangeInclusive<u32> = 1_000..=10_000;
+const BUFFERING_MAX_BYTES: RangeInclusive<u32> = 262_144..=1_048_576;
+const BUFFERING_TIMEOUT_MS: RangeInclusive<u32> = 25..=30_000;
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -22,7 +31,110 @@ pub struct TelemetryAPISubscribe {
     pub destination: TelemetryAPISubscribeDestination,
 }
 
-#[derive(Debug, Serialize)]
+impl TelemetryAPISubscribe {
+    /// Start building a subscription that delivers to the given URI, with every telemetry
+    /// type, the default schema version and the API's default buffering
+    pub fn builder(destination_uri: impl Into<String>) -> TelemetryAPISubscribeBuilder {
+        TelemetryAPISubscribeBuilder {
+            schema_version: TELEMETRY_API_SCHEMA.to_string(),
+            types: TELEMETRY_TYPES.iter().map(|t| t.to_string()).collect(),
+            buffering: TelemetryAPISubscribeBuffering {
+                max_items: *BUFFERING_MAX_ITEMS.start(),
+                max_bytes: *BUFFERING_MAX_BYTES.start(),
+                timeout_ms: 100,
+            },
+            destination_uri: destination_uri.into(),
+        }
+    }
+}
+
+/// Builds a [`TelemetryAPISubscribe`], validating the options against what the TelemetryAPI
+/// accepts so a bad value fails before the subscribe call.
+#[derive(Debug, Clone)]
+pub struct TelemetryAPISubscribeBuilder {
+    schema_version: String,
+    types: Vec<String>,
+    buffering: TelemetryAPISubscribeBuffering,
+    destination_uri: String,
+}
+
+impl TelemetryAPISubscribeBuilder {
+    pub fn schema_version(mut self, schema_version: impl Into<String>) -> Self {
+        self.schema_version = schema_version.into();
+        self
+    }
+
+    /// Telemetry types to subscribe to: platform, function and extension
+    pub fn types<T: Into<String>>(mut self, types: impl IntoIterator<Item = T>) -> Self {
+        self.types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn max_items(mut self, max_items: u32) -> Self {
+        self.buffering.max_items = max_items;
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: u32) -> Self {
+        self.buffering.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn timeout_ms(mut self, timeout_ms: u32) -> Self {
+        self.buffering.timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn build(self) -> Result<TelemetryAPISubscribe, String> {
+        if !TELEMETRY_API_SCHEMAS.contains(&self.schema_version.as_str()) {
+            return Err(format!(
+                "unsupported schema version '{}'",
+                self.schema_version
+            ));
+        }
+        if self.types.is_empty() {
+            return Err("at least one telemetry type is required".to_string());
+        }
+        if let Some(t) = self
+            .types
+            .iter()
+            .find(|t| !TELEMETRY_TYPES.contains(&t.as_str()))
+        {
+            return Err(format!("unknown telemetry type '{}'", t));
+        }
+        check_range("maxItems", self.buffering.max_items, BUFFERING_MAX_ITEMS)?;
+        check_range("maxBytes", self.buffering.max_bytes, BUFFERING_MAX_BYTES)?;
+        check_range("timeoutMs", self.buffering.timeout_ms, BUFFERING_TIMEOUT_MS)?;
+        if self.destination_uri.is_empty() {
+            return Err("destination URI is required".to_string());
+        }
+
+        Ok(TelemetryAPISubscribe {
+            schema_version: self.schema_version,
+            types: self.types,
+            buffering: self.buffering,
+            destination: TelemetryAPISubscribeDestination {
+                protocol: "HTTP".to_string(),
+                uri: self.destination_uri,
+            },
+        })
+    }
+}
+
+fn check_range(name: &str, value: u32, range: RangeInclusive<u32>) -> Result<(), String> {
+    if !range.contains(&value) {
+        return Err(format!(
+            "buffering {} must be between {} and {}, got {}",
+            name,
+            range.start(),
+            range.end(),
+            value
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TelemetryAPISubscribeBuffering {
     pub max_items: u32,
@@ -38,3 +150,67 @@ pub struct TelemetryAPISubscribeDestination {
     #[serde(rename = "URI")]
     pub uri: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const URI: &str = "http://sandbox.localdomain:8990/";
+
+    #[test]
+    fn test_subscribe_builder_defaults() {
+        let sub = TelemetryAPISubscribe::builder(URI).build().unwrap();
+        assert_eq!(
+            json!({
+                "schemaVersion": TELEMETRY_API_SCHEMA,
+                "types": ["platform", "function", "extension"],
+                "buffering": {"maxItems": 1000, "maxBytes": 262144, "timeoutMs": 100},
+                "destination": {"protocol": "HTTP", "URI": URI},
+            }),
+            serde_json::to_value(sub).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_subscribe_builder_options() {
+        let sub = TelemetryAPISubscribe::builder(URI)
+            .schema_version("2025-01-29")
+            .types(["platform", "function"])
+            .max_items(5_000)
+            .max_bytes(1_048_576)
+            .timeout_ms(25)
+            .build()
+            .unwrap();
+        assert_eq!(
+            json!({
+                "schemaVersion": "2025-01-29",
+                "types": ["platform", "function"],
+                "buffering": {"maxItems": 5000, "maxBytes": 1048576, "timeoutMs": 25},
+                "destination": {"protocol": "HTTP", "URI": URI},
+            }),
+            serde_json::to_value(sub).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_subscribe_builder_validation() {
+        let builder = || TelemetryAPISubscribe::builder(URI);
+
+        assert!(builder().schema_version("2022-12-31").build().is_err());
+        assert!(builder().types(Vec::<String>::new()).build().is_err());
+        assert!(builder().types(["platform", "logs"]).build().is_err());
+        assert!(builder().max_items(999).build().is_err());
+        assert!(builder().max_items(10_001).build().is_err());
+        assert!(builder().max_bytes(1_048_577).build().is_err());
+        assert!(builder().timeout_ms(24).build().is_err());
+        assert!(builder().timeout_ms(30_001).build().is_err());
+        assert!(TelemetryAPISubscribe::builder("").build().is_err());
+
+        let err = builder().max_bytes(1024).build().unwrap_err();
+        assert_eq!(
+            "buffering maxBytes must be between 262144 and 1048576, got 1024",
+            err
+        );
+    }
+}
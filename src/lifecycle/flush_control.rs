@@ -1,6 +1,7 @@
-use crate::lifecycle::flush_control::FlushMode::{AfterCall, Periodic};
+use crate::lifecycle::flush_control::FlushMode::{AfterCall, EveryN, Periodic};
 use crate::lifecycle::invocation_rate::InvocationRate;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // Default flush interval that captures any long duration
 // lambda invocations. If we flush at the end or periodically at the
@@ -16,23 +17,54 @@ const PERIODIC_FLUSH_RATE_MILLIS: u64 = 20 * 1_000;
 // an invocation.
 const ACTIVE_INVOCATION_RATE_MILLIS: u64 = 60 * 1_000;
 
+/// Source of the current time in milliseconds since the epoch
 pub trait Clock {
     fn now(&self) -> u64;
 }
 
+#[derive(Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
 pub struct FlushControl<C: Clock> {
     rate: InvocationRate,
     inner: Arc<Mutex<Inner>>,
     clock: C,
+    every_n: Option<u64>,
+    invocations: u64,
+    min_flush_interval_millis: u64,
 }
 
 struct Inner {
     last_flush: u64,
+    // When a flush last started, which unlike last_flush is only set by actual flushes
+    last_flush_started: Option<u64>,
 }
 
 pub enum FlushMode<C: Clock> {
     AfterCall,
     Periodic(PeriodicFlushControl<C>),
+    /// Flushing every Nth invocation, true when this invocation is one to flush after
+    EveryN(bool),
+}
+
+impl<C: Clock> FlushMode<C> {
+    /// Name of the mode as reported by the health endpoint
+    pub fn name(&self) -> &'static str {
+        match self {
+            AfterCall => "after-call",
+            Periodic(_) => "periodic",
+            EveryN(_) => "every-n",
+        }
+    }
 }
 
 pub struct PeriodicFlushControl<C: Clock> {
@@ -61,14 +93,54 @@ impl<C: Clock + Clone> FlushControl<C> {
             rate: InvocationRate::default(),
             inner: Arc::new(Mutex::new(Inner {
                 last_flush: clock.now(),
+                last_flush_started: None,
             })),
+            every_n: None,
+            invocations: 0,
+            min_flush_interval_millis: 0,
         }
     }
 
+    /// Skip flushes that would start sooner than this after the previous one, in any mode
+    pub fn with_min_flush_interval(mut self, min_flush_interval: Duration) -> Self {
+        self.min_flush_interval_millis = min_flush_interval.as_millis() as u64;
+        self
+    }
+
+    /// Records the start of a flush, or returns false if the previous flush started less
+    /// than the minimum flush interval ago and this one should be skipped
+    pub fn start_flush(&self) -> bool {
+        let now_millis = self.clock.now();
+        let mut g = self.inner.lock().unwrap();
+
+        if let Some(last) = g.last_flush_started {
+            if now_millis.saturating_sub(last) < self.min_flush_interval_millis {
+                return false;
+            }
+        }
+        g.last_flush_started = Some(now_millis);
+        true
+    }
+
+    /// Flush after every Nth invocation instead of adapting to the invocation rate
+    pub fn with_every_n(mut self, every_n: Option<u64>) -> Self {
+        self.every_n = every_n.map(|n| n.max(1));
+        self
+    }
+
     pub fn pick(&mut self) -> FlushMode<C> {
         let now_millis = self.clock.now();
         self.rate.add(now_millis);
 
+        if let Some(every_n) = self.every_n {
+            self.invocations += 1;
+            let flush = self.invocations % every_n == 0;
+            if flush {
+                self.inner.lock().unwrap().last_flush = now_millis;
+            }
+            return EveryN(flush);
+        }
+
         let mode = match self.rate.is_faster_than(ACTIVE_INVOCATION_RATE_MILLIS) {
             // Not initialized, stick to flush per call
             None => AfterCall,
@@ -94,6 +166,11 @@ impl<C: Clock + Clone> FlushControl<C> {
 
         mode
     }
+
+    /// Smoothed milliseconds between invocations, None until enough have been seen
+    pub fn invocation_interval_millis(&self) -> Option<u64> {
+        self.rate.interval_millis()
+    }
 }
 
 #[cfg(test)]
@@ -136,6 +213,7 @@ mod tests {
             FlushMode::AfterCall => {}
             _ => panic!("Expected AfterCall mode initially"),
         }
+        assert_eq!(None, flush_control.invocation_interval_millis());
     }
 
     #[test]
@@ -180,6 +258,11 @@ mod tests {
             FlushMode::Periodic(_) => {}
             _ => panic!("Expected Periodic mode for fast invocations"),
         }
+        assert!(
+            flush_control
+                .invocation_interval_millis()
+                .is_some_and(|i| i < ACTIVE_INVOCATION_RATE_MILLIS)
+        );
     }
 
     #[test]
@@ -249,6 +332,28 @@ mod tests {
         assert!(periodic_control.should_flush());
     }
 
+    #[test]
+    fn test_every_n_mode() {
+        let clock = TestClock::new(1000);
+        let mut flush_control = FlushControl::new(clock.clone()).with_every_n(Some(3));
+
+        // Only every third invocation flushes, however fast the invocations are
+        let flushes: Vec<bool> = (0..9)
+            .map(|_| {
+                clock.advance(100);
+                match flush_control.pick() {
+                    FlushMode::EveryN(flush) => flush,
+                    _ => panic!("Expected EveryN mode"),
+                }
+            })
+            .collect();
+        assert_eq!(
+            vec![false, false, true, false, false, true, false, false, true],
+            flushes
+        );
+        assert_eq!("every-n", flush_control.pick().name());
+    }
+
     #[test]
     fn test_multiple_periodic_flush_controls_share_state() {
         let clock = TestClock::new(1000);
@@ -0,0 +1,274 @@
+use crate::lambda::telemetry_api::EXTENSION_VERSION;
+use crate::lambda::{otel_bool_attr, otel_double_attr, otel_string_attr};
+use crate::lifecycle::internal_metrics::{loopback, now_nanos};
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request};
+use http_body_util::Full;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::common::v1::InstrumentationScope;
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_proto::tonic::trace::v1::span::SpanKind;
+use opentelemetry_proto::tonic::trace::v1::status::StatusCode;
+use opentelemetry_proto::tonic::trace::v1::{ResourceSpans, ScopeSpans, Span, Status};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tower::BoxError;
+
+const FLUSH_TRACE_SCOPE: &str = "github.com/streamfold/rotel-lambda-extension/flush";
+
+pub const FLUSH_SPAN: &str = "flush";
+
+const FLUSH_STAGE_ATTR: &str = "flush.stage";
+const FLUSH_DURATION_ATTR: &str = "flush.duration_ms";
+const FLUSH_TIMED_OUT_ATTR: &str = "flush.timed_out";
+
+/// How a flush stage ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StageOutcome {
+    Done,
+    TimedOut,
+    Failed,
+}
+
+/// Spans of completed flushes, exported as OTLP traces under a dedicated scope. A flush's
+/// spans are exported by the flush that follows it, as they only end once it has finished.
+#[derive(Default)]
+pub struct FlushTraces {
+    spans: Mutex<Vec<Span>>,
+}
+
+/// The spans of a flush in progress, a parent flush span with a child per stage
+pub struct FlushTrace {
+    trace_id: Vec<u8>,
+    span_id: Vec<u8>,
+    start_time_unix_nano: u64,
+    stages: Vec<Span>,
+}
+
+impl FlushTraces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self) -> FlushTrace {
+        FlushTrace {
+            trace_id: [random_id(), random_id()].concat(),
+            span_id: random_id(),
+            start_time_unix_nano: now_nanos(),
+            stages: vec![],
+        }
+    }
+
+    /// Ends the flush span, which has an error status when any stage did not complete
+    pub fn finish(&self, trace: FlushTrace) {
+        let end_time_unix_nano = now_nanos();
+        let failed = trace
+            .stages
+            .iter()
+            .find(|s| s.status.as_ref().is_some_and(is_error));
+
+        let flush = Span {
+            trace_id: trace.trace_id,
+            span_id: trace.span_id,
+            name: FLUSH_SPAN.to_string(),
+            kind: SpanKind::Internal as i32,
+            start_time_unix_nano: trace.start_time_unix_nano,
+            end_time_unix_nano,
+            attributes: vec![otel_double_attr(
+                FLUSH_DURATION_ATTR,
+                millis(end_time_unix_nano - trace.start_time_unix_nano),
+            )],
+            status: Some(match failed {
+                Some(stage) => error_status(format!("flush stage {} did not complete", stage.name)),
+                None => ok_status(),
+            }),
+            ..Default::default()
+        };
+
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(flush);
+        spans.extend(trace.stages);
+    }
+
+    /// Drain the finished spans into a batch
+    pub fn take(&self, resource: &Resource) -> Option<ResourceSpans> {
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap());
+        if spans.is_empty() {
+            return None;
+        }
+
+        Some(ResourceSpans {
+            resource: Some(resource.clone()),
+            scope_spans: vec![ScopeSpans {
+                scope: Some(InstrumentationScope {
+                    name: FLUSH_TRACE_SCOPE.to_string(),
+                    version: EXTENSION_VERSION.to_string(),
+                    ..Default::default()
+                }),
+                spans,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+impl FlushTrace {
+    /// Adds a child span for a stage that started `duration` ago
+    pub fn stage(&mut self, name: &str, duration: Duration, outcome: StageOutcome) {
+        let end_time_unix_nano = now_nanos();
+        let status = match outcome {
+            StageOutcome::Done => ok_status(),
+            StageOutcome::TimedOut => error_status("timeout".to_string()),
+            StageOutcome::Failed => error_status("failed".to_string()),
+        };
+
+        self.stages.push(Span {
+            trace_id: self.trace_id.clone(),
+            span_id: random_id(),
+            parent_span_id: self.span_id.clone(),
+            name: name.to_string(),
+            kind: SpanKind::Internal as i32,
+            start_time_unix_nano: end_time_unix_nano.saturating_sub(duration.as_nanos() as u64),
+            end_time_unix_nano,
+            attributes: vec![
+                otel_string_attr(FLUSH_STAGE_ATTR, name),
+                otel_double_attr(FLUSH_DURATION_ATTR, duration.as_secs_f64() * 1000.0),
+                otel_bool_attr(FLUSH_TIMED_OUT_ATTR, outcome == StageOutcome::TimedOut),
+            ],
+            status: Some(status),
+            ..Default::default()
+        });
+    }
+}
+
+/// Sends flush spans to the agent's own OTLP/HTTP receiver, so they flow through the same
+/// pipeline and exporters as function telemetry.
+pub async fn export_flush_traces(
+    client: Client<HttpConnector, Full<Bytes>>,
+    receiver: SocketAddr,
+    resource_spans: Vec<ResourceSpans>,
+) -> Result<(), BoxError> {
+    let req = ExportTraceServiceRequest { resource_spans };
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/v1/traces", loopback(receiver)))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(Bytes::from(serde_json::to_vec(&req)?)))?;
+
+    let resp = client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(format!("flush traces export failed: {}", resp.status()).into());
+    }
+
+    Ok(())
+}
+
+fn ok_status() -> Status {
+    Status {
+        code: StatusCode::Ok as i32,
+        ..Default::default()
+    }
+}
+
+fn error_status(message: String) -> Status {
+    Status {
+        code: StatusCode::Error as i32,
+        message,
+    }
+}
+
+fn is_error(status: &Status) -> bool {
+    status.code == StatusCode::Error as i32
+}
+
+fn millis(nanos: u64) -> f64 {
+    nanos as f64 / 1_000_000.0
+}
+
+// Ids only need to be unique, the hasher is randomly keyed and the counter separates ids
+// made with the same keys
+fn random_id() -> Vec<u8> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u64(now_nanos());
+    hasher.finish().to_be_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_span_tree() {
+        let traces = FlushTraces::new();
+
+        let mut trace = traces.start();
+        trace.stage("pipeline", Duration::from_millis(20), StageOutcome::Done);
+        trace.stage(
+            "exporters",
+            Duration::from_millis(50),
+            StageOutcome::TimedOut,
+        );
+        traces.finish(trace);
+
+        let rs = traces.take(&Resource::default()).unwrap();
+        let ss = &rs.scope_spans[0];
+        assert_eq!(FLUSH_TRACE_SCOPE, ss.scope.as_ref().unwrap().name);
+
+        let names: Vec<&str> = ss.spans.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec![FLUSH_SPAN, "pipeline", "exporters"], names);
+
+        // Stages are children of the flush span, in the same trace
+        let (flush, stages) = (&ss.spans[0], &ss.spans[1..]);
+        assert_eq!(16, flush.trace_id.len());
+        assert_eq!(8, flush.span_id.len());
+        assert!(flush.parent_span_id.is_empty());
+        for stage in stages {
+            assert_eq!(flush.trace_id, stage.trace_id);
+            assert_eq!(flush.span_id, stage.parent_span_id);
+            assert_ne!(flush.span_id, stage.span_id);
+            assert!(stage.start_time_unix_nano >= flush.start_time_unix_nano);
+        }
+        assert_ne!(stages[0].span_id, stages[1].span_id);
+
+        let pipeline = &stages[0];
+        assert_eq!(Some(ok_status()), pipeline.status);
+        assert!(
+            pipeline
+                .attributes
+                .contains(&otel_bool_attr(FLUSH_TIMED_OUT_ATTR, false))
+        );
+        assert!(
+            pipeline
+                .attributes
+                .contains(&otel_double_attr(FLUSH_DURATION_ATTR, 20.0))
+        );
+
+        // The timed out stage, and so the flush, have an error status
+        let exporters = &stages[1];
+        assert_eq!(Some(error_status("timeout".to_string())), exporters.status);
+        assert!(
+            exporters
+                .attributes
+                .contains(&otel_bool_attr(FLUSH_TIMED_OUT_ATTR, true))
+        );
+        assert_eq!(
+            Some(error_status(
+                "flush stage exporters did not complete".to_string()
+            )),
+            flush.status
+        );
+
+        assert!(traces.take(&Resource::default()).is_none());
+    }
+}
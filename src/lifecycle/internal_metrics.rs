@@ -0,0 +1,416 @@
+use crate::lambda::otel_string_attr;
+use crate::lambda::telemetry_api::EXTENSION_VERSION;
+use crate::lifecycle::status::status_class;
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, StatusCode};
+use http_body_util::Full;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+use opentelemetry_proto::tonic::metrics::v1::number_data_point::Value;
+use opentelemetry_proto::tonic::metrics::v1::{
+    AggregationTemporality, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tower::BoxError;
+
+const INTERNAL_METRICS_SCOPE: &str = "github.com/streamfold/rotel-lambda-extension/internal";
+
+pub const SECRETS_RESOLVE_DURATION: &str = "rotel_extension.secrets.resolve.duration";
+pub const COLDSTART_DURATION: &str = "rotel_extension.coldstart.duration";
+pub const FLUSH_DURATION: &str = "rotel_extension.flush.duration";
+pub const INVOCATIONS: &str = "rotel_extension.invocations";
+pub const LOGS_DROPPED: &str = "rotel_extension.platform.logs_dropped";
+pub const LOGS_SEND_TIMEOUTS: &str = "rotel_extension.logs.send_timeouts";
+pub const TELEMETRY_RESPONSES: &str = "rotel_extension.telemetry.responses";
+
+const FLUSH_STAGE_ATTR: &str = "flush.stage";
+const STATUS_CLASS_ATTR: &str = "http.response.status_class";
+
+/// Records the extension's own overhead, exported as OTLP metrics under a dedicated scope.
+/// Durations are reported as gauges in milliseconds, one data point per observation.
+pub struct InternalMetrics {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    start_time_unix_nano: u64,
+    invocations: u64,
+    logs_dropped: u64,
+    logs_send_timeouts: u64,
+    // Keyed by status class, so data points are in a stable order
+    telemetry_responses: BTreeMap<&'static str, u64>,
+    secrets_resolve: Vec<NumberDataPoint>,
+    coldstart: Vec<NumberDataPoint>,
+    flushes: Vec<NumberDataPoint>,
+}
+
+impl Default for InternalMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InternalMetrics {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                start_time_unix_nano: now_nanos(),
+                invocations: 0,
+                logs_dropped: 0,
+                logs_send_timeouts: 0,
+                telemetry_responses: BTreeMap::new(),
+                secrets_resolve: Vec::new(),
+                coldstart: Vec::new(),
+                flushes: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn record_secrets_resolution(&self, duration: Duration) {
+        let point = duration_point(duration, vec![]);
+        self.inner.lock().unwrap().secrets_resolve.push(point);
+    }
+
+    /// Record the time from the extension process starting at `start` until it is ready to
+    /// receive invocations
+    pub fn record_coldstart(&self, start: Instant) {
+        let point = duration_point(start.elapsed(), vec![]);
+        self.inner.lock().unwrap().coldstart.push(point);
+    }
+
+    /// Record how long a flush stage (logs, pipeline or exporters) took
+    pub fn record_flush(&self, stage: &str, duration: Duration) {
+        let point = duration_point(duration, vec![otel_string_attr(FLUSH_STAGE_ATTR, stage)]);
+        self.inner.lock().unwrap().flushes.push(point);
+    }
+
+    pub fn record_invocation(&self) {
+        self.inner.lock().unwrap().invocations += 1;
+    }
+
+    /// Record telemetry records that Lambda reported dropping via platform.logsDropped
+    pub fn record_logs_dropped(&self, records: u64) {
+        self.inner.lock().unwrap().logs_dropped += records;
+    }
+
+    /// Record a batch of logs dropped because the logs pipeline did not accept it in time
+    pub fn record_logs_send_timeout(&self) {
+        self.inner.lock().unwrap().logs_send_timeouts += 1;
+    }
+
+    /// Record a response returned by the TelemetryAPI receiver, under its status class
+    pub fn record_telemetry_response(&self, status: StatusCode) {
+        if let Some(class) = status_class(status) {
+            *self
+                .inner
+                .lock()
+                .unwrap()
+                .telemetry_responses
+                .entry(class)
+                .or_default() += 1;
+        }
+    }
+
+    /// Drain the recorded durations into a batch of metrics. The invocation count is
+    /// cumulative and included in every batch once an invocation has been seen.
+    pub fn take(&self, resource: &Resource) -> Option<ResourceMetrics> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut metrics = vec![];
+
+        if !inner.secrets_resolve.is_empty() {
+            metrics.push(gauge(
+                SECRETS_RESOLVE_DURATION,
+                "Time spent resolving secrets during cold start",
+                std::mem::take(&mut inner.secrets_resolve),
+            ));
+        }
+        if !inner.coldstart.is_empty() {
+            metrics.push(gauge(
+                COLDSTART_DURATION,
+                "Time from the extension starting until it was ready for invocations",
+                std::mem::take(&mut inner.coldstart),
+            ));
+        }
+        if !inner.flushes.is_empty() {
+            metrics.push(gauge(
+                FLUSH_DURATION,
+                "Time spent in each telemetry flush stage",
+                std::mem::take(&mut inner.flushes),
+            ));
+        }
+        if inner.invocations > 0 {
+            metrics.push(counter(
+                INVOCATIONS,
+                "Function invocations observed by the extension",
+                "{invocation}",
+                inner.start_time_unix_nano,
+                inner.invocations,
+            ));
+        }
+        if inner.logs_dropped > 0 {
+            metrics.push(counter(
+                LOGS_DROPPED,
+                "Telemetry records Lambda dropped because the extension fell behind",
+                "{record}",
+                inner.start_time_unix_nano,
+                inner.logs_dropped,
+            ));
+        }
+        if inner.logs_send_timeouts > 0 {
+            metrics.push(counter(
+                LOGS_SEND_TIMEOUTS,
+                "Log batches dropped because the logs pipeline did not accept them in time",
+                "{batch}",
+                inner.start_time_unix_nano,
+                inner.logs_send_timeouts,
+            ));
+        }
+        if !inner.telemetry_responses.is_empty() {
+            let start_time_unix_nano = inner.start_time_unix_nano;
+            metrics.push(sum(
+                TELEMETRY_RESPONSES,
+                "Responses returned by the TelemetryAPI receiver, by status class",
+                "{response}",
+                inner
+                    .telemetry_responses
+                    .iter()
+                    .map(|(class, count)| NumberDataPoint {
+                        attributes: vec![otel_string_attr(STATUS_CLASS_ATTR, class)],
+                        start_time_unix_nano,
+                        time_unix_nano: now_nanos(),
+                        value: Some(Value::AsInt(*count as i64)),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ));
+        }
+
+        if metrics.is_empty() {
+            return None;
+        }
+
+        Some(ResourceMetrics {
+            resource: Some(resource.clone()),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: INTERNAL_METRICS_SCOPE.to_string(),
+                    version: EXTENSION_VERSION.to_string(),
+                    ..Default::default()
+                }),
+                metrics,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+/// Sends internal metrics to the agent's own OTLP/HTTP receiver, so they flow through the
+/// same pipeline and exporters as function telemetry.
+pub async fn export_internal_metrics(
+    client: Client<HttpConnector, Full<Bytes>>,
+    receiver: SocketAddr,
+    resource_metrics: Vec<ResourceMetrics>,
+) -> Result<(), BoxError> {
+    let req = ExportMetricsServiceRequest { resource_metrics };
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/v1/metrics", loopback(receiver)))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(Bytes::from(serde_json::to_vec(&req)?)))?;
+
+    let resp = client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(format!("internal metrics export failed: {}", resp.status()).into());
+    }
+
+    Ok(())
+}
+
+// The receiver typically binds the unspecified address, which is not connectable
+pub(crate) fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+pub(crate) fn gauge(name: &str, description: &str, data_points: Vec<NumberDataPoint>) -> Metric {
+    Metric {
+        name: name.to_string(),
+        description: description.to_string(),
+        unit: "ms".to_string(),
+        data: Some(Data::Gauge(Gauge { data_points })),
+        ..Default::default()
+    }
+}
+
+fn counter(
+    name: &str,
+    description: &str,
+    unit: &str,
+    start_time_unix_nano: u64,
+    value: u64,
+) -> Metric {
+    sum(
+        name,
+        description,
+        unit,
+        vec![NumberDataPoint {
+            start_time_unix_nano,
+            time_unix_nano: now_nanos(),
+            value: Some(Value::AsInt(value as i64)),
+            ..Default::default()
+        }],
+    )
+}
+
+// A cumulative monotonic sum
+fn sum(name: &str, description: &str, unit: &str, data_points: Vec<NumberDataPoint>) -> Metric {
+    Metric {
+        name: name.to_string(),
+        description: description.to_string(),
+        unit: unit.to_string(),
+        data: Some(Data::Sum(Sum {
+            data_points,
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            is_monotonic: true,
+        })),
+        ..Default::default()
+    }
+}
+
+fn duration_point(duration: Duration, attributes: Vec<KeyValue>) -> NumberDataPoint {
+    NumberDataPoint {
+        attributes,
+        time_unix_nano: now_nanos(),
+        value: Some(Value::AsDouble(duration.as_secs_f64() * 1000.0)),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_metrics() {
+        let m = InternalMetrics::new();
+        assert!(m.take(&Resource::default()).is_none());
+
+        m.record_secrets_resolution(Duration::from_millis(120));
+        m.record_flush("logs", Duration::from_millis(2));
+        m.record_flush("exporters", Duration::from_millis(40));
+        m.record_invocation();
+        m.record_invocation();
+
+        let rm = m.take(&Resource::default()).unwrap();
+        let sm = &rm.scope_metrics[0];
+        assert_eq!(INTERNAL_METRICS_SCOPE, sm.scope.as_ref().unwrap().name);
+
+        let names: Vec<&str> = sm.metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(
+            vec![SECRETS_RESOLVE_DURATION, FLUSH_DURATION, INVOCATIONS],
+            names
+        );
+
+        match sm.metrics[0].data.as_ref().unwrap() {
+            Data::Gauge(g) => {
+                assert_eq!(1, g.data_points.len());
+                assert_eq!(Some(Value::AsDouble(120.0)), g.data_points[0].value);
+            }
+            _ => panic!("expected gauge"),
+        }
+        match sm.metrics[1].data.as_ref().unwrap() {
+            Data::Gauge(g) => {
+                assert_eq!(2, g.data_points.len());
+                assert_eq!(
+                    vec![otel_string_attr(FLUSH_STAGE_ATTR, "exporters")],
+                    g.data_points[1].attributes
+                );
+                assert_eq!(Some(Value::AsDouble(40.0)), g.data_points[1].value);
+            }
+            _ => panic!("expected gauge"),
+        }
+        match sm.metrics[2].data.as_ref().unwrap() {
+            Data::Sum(s) => {
+                assert!(s.is_monotonic);
+                assert_eq!(Some(Value::AsInt(2)), s.data_points[0].value);
+            }
+            _ => panic!("expected sum"),
+        }
+
+        // Durations are drained, the invocation count is cumulative
+        let rm = m.take(&Resource::default()).unwrap();
+        let names: Vec<&str> = rm.scope_metrics[0]
+            .metrics
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect();
+        assert_eq!(vec![INVOCATIONS], names);
+    }
+
+    #[test]
+    fn test_coldstart_duration() {
+        let m = InternalMetrics::new();
+        let start = Instant::now()
+            .checked_sub(Duration::from_millis(250))
+            .unwrap();
+        m.record_coldstart(start);
+
+        let rm = m.take(&Resource::default()).unwrap();
+        let metric = &rm.scope_metrics[0].metrics[0];
+        assert_eq!(COLDSTART_DURATION, metric.name);
+        assert_eq!("ms", metric.unit);
+        match metric.data.as_ref().unwrap() {
+            Data::Gauge(g) => {
+                assert_eq!(1, g.data_points.len());
+                match g.data_points[0].value {
+                    Some(Value::AsDouble(ms)) => assert!((250.0..10_000.0).contains(&ms), "{}", ms),
+                    ref v => panic!("unexpected value: {:?}", v),
+                }
+            }
+            _ => panic!("expected gauge"),
+        }
+
+        // Recorded once per cold start, so it is not exported again
+        assert!(m.take(&Resource::default()).is_none());
+    }
+
+    #[test]
+    fn test_loopback() {
+        assert_eq!(
+            "127.0.0.1:4318".parse::<SocketAddr>().unwrap(),
+            loopback("0.0.0.0:4318".parse().unwrap())
+        );
+        assert_eq!(
+            "[::1]:4318".parse::<SocketAddr>().unwrap(),
+            loopback("[::]:4318".parse().unwrap())
+        );
+        assert_eq!(
+            "10.0.0.1:4318".parse::<SocketAddr>().unwrap(),
+            loopback("10.0.0.1:4318".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use lambda_extension::InvokeEvent;
+use std::time::Duration;
+
+/// Details of the current function invocation, taken from an INVOKE next event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invocation {
+    pub request_id: String,
+    /// Invocation deadline in milliseconds since the epoch
+    pub deadline_ms: u64,
+}
+
+impl Invocation {
+    /// Time remaining until the invocation deadline, or zero if it has passed.
+    pub fn remaining(&self, now_millis: u64) -> Duration {
+        Duration::from_millis(self.deadline_ms.saturating_sub(now_millis))
+    }
+}
+
+impl From<&InvokeEvent> for Invocation {
+    fn from(evt: &InvokeEvent) -> Self {
+        Self {
+            request_id: evt.request_id.clone(),
+            deadline_ms: evt.deadline_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining() {
+        let inv = Invocation {
+            request_id: "1234abcd".to_string(),
+            deadline_ms: 10_000,
+        };
+
+        assert_eq!(Duration::from_millis(2_500), inv.remaining(7_500));
+        assert_eq!(Duration::ZERO, inv.remaining(10_000));
+        assert_eq!(Duration::ZERO, inv.remaining(12_000));
+    }
+}
@@ -46,6 +46,11 @@ impl InvocationRate {
         }
     }
 
+    /// Smoothed milliseconds between invocations, None until warmed up
+    pub fn interval_millis(&self) -> Option<u64> {
+        (self.count >= WARMUP_COUNT).then_some(self.value as u64)
+    }
+
     pub fn is_faster_than(&self, rate_millis: u64) -> Option<bool> {
         // not ready
         if self.count < WARMUP_COUNT {
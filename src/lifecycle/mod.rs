@@ -1,2 +1,7 @@
 pub mod flush_control;
+pub mod flush_trace;
+pub mod internal_metrics;
+pub mod invocation;
 mod invocation_rate;
+pub mod self_logs;
+pub mod status;
@@ -0,0 +1,276 @@
+use crate::lambda::otel_string_attr;
+use crate::lambda::telemetry_api::EXTENSION_VERSION;
+use crate::lifecycle::internal_metrics::now_nanos;
+use opentelemetry_proto::tonic::common::v1::any_value::Value::StringValue;
+use opentelemetry_proto::tonic::common::v1::{AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs, SeverityNumber};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+
+pub const SELF_LOGS_SCOPE: &str = "github.com/streamfold/rotel-lambda-extension/self";
+
+pub const DEFAULT_SELF_LOGS_MAX_RECORDS: usize = 1000;
+
+const TARGET_ATTR: &str = "log.target";
+
+/// The extension's own log events, converted to OTLP log records and held until the next
+/// flush sends them through the logs pipeline. Records are collected here rather than read
+/// back from the TelemetryAPI, so they never loop through the telemetry subscription.
+pub struct SelfLogs {
+    inner: Mutex<Inner>,
+    max_records: usize,
+}
+
+struct Inner {
+    records: Vec<LogRecord>,
+    dropped: u64,
+}
+
+impl SelfLogs {
+    pub fn new(max_records: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                records: Vec::new(),
+                dropped: 0,
+            }),
+            max_records: max_records.max(1),
+        }
+    }
+
+    /// Records beyond `max_records` are dropped until the next flush, so a failing export
+    /// that logs on every attempt can not grow the buffer without limit
+    fn push(&self, record: LogRecord) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.records.len() >= self.max_records {
+            inner.dropped += 1;
+            return;
+        }
+        inner.records.push(record);
+    }
+
+    /// Removes the buffered records, as a batch under the self-log scope
+    pub fn take(&self, resource: &Resource) -> Option<ResourceLogs> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.dropped > 0 {
+            let dropped = std::mem::take(&mut inner.dropped);
+            inner.records.push(LogRecord {
+                time_unix_nano: now_nanos(),
+                observed_time_unix_nano: now_nanos(),
+                severity_number: SeverityNumber::Warn as i32,
+                severity_text: Level::WARN.to_string(),
+                body: Some(string_value(format!(
+                    "dropped {} extension log records",
+                    dropped
+                ))),
+                ..Default::default()
+            });
+        }
+        if inner.records.is_empty() {
+            return None;
+        }
+
+        Some(ResourceLogs {
+            resource: Some(resource.clone()),
+            scope_logs: vec![ScopeLogs {
+                scope: Some(InstrumentationScope {
+                    name: SELF_LOGS_SCOPE.to_string(),
+                    version: EXTENSION_VERSION.to_string(),
+                    ..Default::default()
+                }),
+                log_records: std::mem::take(&mut inner.records),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+
+    /// Puts back a batch that was taken but could not be sent, ahead of the records logged
+    /// since. Records past `max_records` are dropped, as in `push`.
+    pub fn restore(&self, rl: ResourceLogs) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut records: Vec<LogRecord> = rl
+            .scope_logs
+            .into_iter()
+            .flat_map(|sl| sl.log_records)
+            .collect();
+        records.append(&mut inner.records);
+        if records.len() > self.max_records {
+            inner.dropped += (records.len() - self.max_records) as u64;
+            records.truncate(self.max_records);
+        }
+        inner.records = records;
+    }
+}
+
+/// A `tracing` layer that copies every event it sees into [`SelfLogs`]
+pub struct SelfLogsLayer {
+    logs: Arc<SelfLogs>,
+}
+
+impl SelfLogsLayer {
+    pub fn new(logs: Arc<SelfLogs>) -> Self {
+        Self { logs }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SelfLogsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut visitor = RecordVisitor::default();
+        event.record(&mut visitor);
+
+        let mut attributes = visitor.attributes;
+        attributes.push(otel_string_attr(TARGET_ATTR, meta.target()));
+
+        let now = now_nanos();
+        self.logs.push(LogRecord {
+            time_unix_nano: now,
+            observed_time_unix_nano: now,
+            severity_number: severity_number(meta.level()) as i32,
+            severity_text: meta.level().to_string(),
+            body: visitor.message.map(string_value),
+            attributes,
+            ..Default::default()
+        });
+    }
+}
+
+fn severity_number(level: &Level) -> SeverityNumber {
+    match *level {
+        Level::TRACE => SeverityNumber::Trace,
+        Level::DEBUG => SeverityNumber::Debug,
+        Level::INFO => SeverityNumber::Info,
+        Level::WARN => SeverityNumber::Warn,
+        Level::ERROR => SeverityNumber::Error,
+    }
+}
+
+fn string_value(s: String) -> AnyValue {
+    AnyValue {
+        value: Some(StringValue(s)),
+    }
+}
+
+// The message field becomes the body, other fields are string attributes
+#[derive(Default)]
+struct RecordVisitor {
+    message: Option<String>,
+    attributes: Vec<KeyValue>,
+}
+
+impl RecordVisitor {
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "message" => self.message = Some(value),
+            name => self.attributes.push(otel_string_attr(name, &value)),
+        }
+    }
+}
+
+impl Visit for RecordVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_self_logs_layer() {
+        let logs = Arc::new(SelfLogs::new(10));
+        let subscriber =
+            tracing_subscriber::Registry::default().with(SelfLogsLayer::new(logs.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!(stage = "logs", "finished flushing");
+            warn!("timeout waiting to flush");
+        });
+
+        let rl = logs.take(&Resource::default()).unwrap();
+        let sl = &rl.scope_logs[0];
+        assert_eq!(SELF_LOGS_SCOPE, sl.scope.as_ref().unwrap().name);
+        assert_eq!(2, sl.log_records.len());
+
+        let record = &sl.log_records[0];
+        assert_eq!(SeverityNumber::Info as i32, record.severity_number);
+        assert_eq!("INFO", record.severity_text);
+        assert_eq!(
+            Some(string_value("finished flushing".to_string())),
+            record.body
+        );
+        assert!(
+            record
+                .attributes
+                .contains(&otel_string_attr("stage", "logs"))
+        );
+        assert_eq!(
+            SeverityNumber::Warn as i32,
+            sl.log_records[1].severity_number
+        );
+
+        // Taken records are not sent again
+        assert!(logs.take(&Resource::default()).is_none());
+    }
+
+    #[test]
+    fn test_self_logs_max_records() {
+        let logs = Arc::new(SelfLogs::new(1));
+        let subscriber =
+            tracing_subscriber::Registry::default().with(SelfLogsLayer::new(logs.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!("first");
+            info!("second");
+            info!("third");
+        });
+
+        // The dropped records are reported by a record of their own
+        let rl = logs.take(&Resource::default()).unwrap();
+        let records = &rl.scope_logs[0].log_records;
+        assert_eq!(2, records.len());
+        assert_eq!(Some(string_value("first".to_string())), records[0].body);
+        assert_eq!(
+            Some(string_value("dropped 2 extension log records".to_string())),
+            records[1].body
+        );
+    }
+
+    #[test]
+    fn test_self_logs_restore() {
+        let logs = Arc::new(SelfLogs::new(2));
+        let subscriber =
+            tracing_subscriber::Registry::default().with(SelfLogsLayer::new(logs.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!("first");
+            let rl = logs.take(&Resource::default()).unwrap();
+            info!("second");
+            info!("third");
+            logs.restore(rl);
+        });
+
+        // Restored records come first, the newest are dropped past the cap
+        let rl = logs.take(&Resource::default()).unwrap();
+        let records = &rl.scope_logs[0].log_records;
+        assert_eq!(3, records.len());
+        assert_eq!(Some(string_value("first".to_string())), records[0].body);
+        assert_eq!(Some(string_value("second".to_string())), records[1].body);
+        assert_eq!(
+            Some(string_value("dropped 1 extension log records".to_string())),
+            records[2].body
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use http::StatusCode;
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// Flushing state of the lifecycle loop, shared with the TelemetryAPI so it can be reported
+/// on the health endpoint while a function is being tested.
+#[derive(Default)]
+pub struct ExtensionStatus {
+    inner: Mutex<StatusSnapshot>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StatusSnapshot {
+    /// after-call, periodic or every-n, unset until the first invocation
+    pub flush_mode: Option<&'static str>,
+    /// Time of the last flush where every stage completed, in milliseconds since the epoch
+    pub last_flush_unix_ms: Option<u64>,
+    /// Smoothed time between invocations, unset until enough invocations have been seen
+    pub invocation_interval_ms: Option<u64>,
+    /// Responses returned by the TelemetryAPI receiver, by status class
+    pub responses: ResponseCounts,
+}
+
+/// Counts of responses by status class, a spike in 4xx points to malformed or
+/// unauthenticated requests and 5xx to the extension failing to handle them
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ResponseCounts {
+    #[serde(rename = "2xx")]
+    pub success: u64,
+    #[serde(rename = "4xx")]
+    pub client_error: u64,
+    #[serde(rename = "5xx")]
+    pub server_error: u64,
+}
+
+/// The class of a status code that responses are counted under, other classes are not
+/// counted
+pub fn status_class(status: StatusCode) -> Option<&'static str> {
+    if status.is_success() {
+        Some("2xx")
+    } else if status.is_client_error() {
+        Some("4xx")
+    } else if status.is_server_error() {
+        Some("5xx")
+    } else {
+        None
+    }
+}
+
+impl ExtensionStatus {
+    pub fn set_flush_mode(&self, flush_mode: &'static str, invocation_interval_ms: Option<u64>) {
+        let mut g = self.inner.lock().unwrap();
+        g.flush_mode = Some(flush_mode);
+        g.invocation_interval_ms = invocation_interval_ms;
+    }
+
+    pub fn record_flush(&self, now_millis: u64) {
+        self.inner.lock().unwrap().last_flush_unix_ms = Some(now_millis);
+    }
+
+    pub fn record_response(&self, status: StatusCode) {
+        let mut g = self.inner.lock().unwrap();
+        match status_class(status) {
+            Some("2xx") => g.responses.success += 1,
+            Some("4xx") => g.responses.client_error += 1,
+            Some("5xx") => g.responses.server_error += 1,
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> StatusSnapshot {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_snapshot() {
+        let status = ExtensionStatus::default();
+        assert_eq!(StatusSnapshot::default(), status.snapshot());
+
+        status.set_flush_mode("after-call", None);
+        status.record_flush(1_000);
+        assert_eq!(
+            StatusSnapshot {
+                flush_mode: Some("after-call"),
+                last_flush_unix_ms: Some(1_000),
+                invocation_interval_ms: None,
+                responses: ResponseCounts::default(),
+            },
+            status.snapshot()
+        );
+
+        status.set_flush_mode("periodic", Some(250));
+        let json = serde_json::to_value(status.snapshot()).unwrap();
+        assert_eq!("periodic", json["flush_mode"]);
+        assert_eq!(1_000, json["last_flush_unix_ms"]);
+        assert_eq!(250, json["invocation_interval_ms"]);
+
+        status.record_response(StatusCode::OK);
+        status.record_response(StatusCode::BAD_REQUEST);
+        status.record_response(StatusCode::UNAUTHORIZED);
+        status.record_response(StatusCode::MOVED_PERMANENTLY);
+        let json = serde_json::to_value(status.snapshot()).unwrap();
+        assert_eq!(
+            serde_json::json!({"2xx": 1, "4xx": 2, "5xx": 0}),
+            json["responses"]
+        );
+    }
+}
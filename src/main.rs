@@ -1,15 +1,21 @@
 extern crate core;
 
+#[cfg(test)]
+#[path = "test_util/runtime_api.rs"]
+mod runtime_api;
+
 use bytes::Bytes;
 use clap::{Parser, ValueEnum};
 use dotenvy::Substitutor;
+use http::HeaderValue;
 use http_body_util::Full;
 use hyper_util::client::legacy::Client;
 use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::rt::{TokioExecutor, TokioTimer};
-use lambda_extension::{LambdaTelemetryRecord, NextEvent};
-use rotel::aws_api::creds::AwsCreds;
-use rotel::bounded_channel::bounded;
+use lambda_extension::{LambdaTelemetry, LambdaTelemetryRecord, NextEvent};
+use opentelemetry_proto::tonic::logs::v1::{ResourceLogs, SeverityNumber};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use rotel::bounded_channel::{BoundedReceiver, BoundedSender, bounded};
 use rotel::init::agent::Agent;
 use rotel::init::args::{AgentRun, Exporter};
 use rotel::init::misc::bind_endpoints;
@@ -17,33 +23,66 @@ use rotel::init::parse;
 use rotel::init::wait;
 use rotel::listener::Listener;
 use rotel::topology::flush_control::{FlushBroadcast, FlushSender};
-use rotel_extension::env::{EnvArnParser, resolve_secrets};
+use rotel::topology::payload::Message;
+use rotel_extension::env::{EnvArnParser, LookupError, resolve_secrets_map_with_optional};
 use rotel_extension::lambda;
-use rotel_extension::lambda::telemetry_api::TelemetryAPI;
+use rotel_extension::lambda::api::{
+    TELEMETRY_API_SCHEMA, TELEMETRY_DESTINATION_HOST, parse_telemetry_schema_version,
+};
+use rotel_extension::lambda::invocation_summary::InvocationSummaries;
+use rotel_extension::lambda::logs::{
+    LogsConfig, MarkedWriter, ObservedTimeSource, TimestampSource, parse_observed_time_source,
+    parse_severity, parse_severity_map, parse_timestamp_source,
+};
+use rotel_extension::lambda::logs_buffer::LogsBuffer;
+use rotel_extension::lambda::logs_sink::{LogsSink, parse_logs_sink};
+use rotel_extension::lambda::telemetry_api::{
+    DEFAULT_MAX_CONNECTIONS, EXTENSION_VERSION, TelemetryAPI, resource_from_env, tls_server_config,
+};
+use rotel_extension::lambda::types::RegisterResponseBody;
 use rotel_extension::lifecycle::flush_control::{
-    Clock, DEFAULT_FLUSH_INTERVAL_MILLIS, FlushControl, FlushMode,
+    Clock, DEFAULT_FLUSH_INTERVAL_MILLIS, FlushControl, FlushMode, SystemClock,
+};
+use rotel_extension::lifecycle::flush_trace::{FlushTraces, StageOutcome, export_flush_traces};
+use rotel_extension::lifecycle::internal_metrics::{InternalMetrics, export_internal_metrics};
+use rotel_extension::lifecycle::invocation::Invocation;
+use rotel_extension::lifecycle::self_logs::{
+    DEFAULT_SELF_LOGS_MAX_RECORDS, SelfLogs, SelfLogsLayer,
 };
+use rotel_extension::lifecycle::status::ExtensionStatus;
+use rotel_extension::secrets::client::AwsConfig;
+use rotel_extension::util::duration::parse_duration;
+use rotel_extension::util::http::{DEFAULT_USER_AGENT, parse_user_agent};
+use rotel_extension::util::retry::random_delay;
 use rustls::crypto::CryptoProvider;
 use std::collections::HashMap;
 use std::env;
+use std::fmt::Display;
+use std::future::Future;
 use std::net::SocketAddr;
-use std::ops::Add;
+use std::ops::{Add, Sub};
+use std::path::PathBuf;
 use std::process::ExitCode;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::task::JoinSet;
 use tokio::time::{Instant, Interval, timeout};
 use tokio::{pin, select};
 use tokio_util::sync::CancellationToken;
 use tower_http::BoxError;
 use tracing::level_filters::LevelFilter;
-use tracing::{debug, error, info, warn};
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+use tracing_subscriber::fmt;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::{DefaultFields, Format, Full};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Registry};
 
+// Default number of export batches the agent buffers for each exporter
 pub const SENDING_QUEUE_SIZE: usize = 10;
 
-//
-// todo: these constants should be configurable
+type JsonLambdaTelemetry = LambdaTelemetry<serde_json::Value>;
 
 pub const LOGS_QUEUE_SIZE: usize = 50;
 
@@ -51,13 +90,212 @@ pub const FLUSH_LOGS_TIMEOUT_MILLIS: u64 = 100; // can be short, simply forces b
 pub const FLUSH_PIPELINE_TIMEOUT_MILLIS: u64 = 500;
 pub const FLUSH_EXPORTERS_TIMEOUT_MILLIS: u64 = 3_000;
 
+// Flushes stop this far ahead of the invocation deadline so that the sandbox is not
+// frozen mid-flush
+pub const FLUSH_DEADLINE_SAFETY_MARGIN_MILLIS: u64 = 50;
+
+// Total time we allow ourselves to shutdown after SHUTDOWN is received, the most Lambda
+// grants extensions. ROTEL_SHUTDOWN_BUDGET_MS can lower it.
+pub const SHUTDOWN_BUDGET_MILLIS: u64 = 2_000;
+
+// Time reserved at shutdown for the agent to stop after the final flush
+pub const SHUTDOWN_AGENT_STOP_MILLIS: u64 = 500;
+
+// Upper bound on the random delay before resolving secrets, so it stays small next to a
+// cold start
+pub const MAX_SECRETS_STARTUP_JITTER_MILLIS: u64 = 1_000;
+
+// How often to check whether accepted logs have entered the logs channel at shutdown
+const DRAIN_POLL_MILLIS: u64 = 5;
+
+// Process exit codes, one per failure class
+pub const EXIT_RUNTIME_ERROR: u8 = 1;
+pub const EXIT_CONFIG_ERROR: u8 = 2;
+pub const EXIT_REGISTER_ERROR: u8 = 3;
+
 #[derive(Debug, Parser)]
 #[command(name = "rotel-lambda-extension")]
 #[command(bin_name = "rotel-lambda-extension")]
 struct Arguments {
-    #[arg(long, env = "ROTEL_TELEMETRY_ENDPOINT", default_value = "0.0.0.0:8990", value_parser = parse::parse_endpoint)]
+    /// Address for the TelemetryAPI receiver, the default port of 0 binds an ephemeral port
+    /// that is passed to Lambda when subscribing
+    #[arg(long, env = "ROTEL_TELEMETRY_ENDPOINT", default_value = "0.0.0.0:0", value_parser = parse::parse_endpoint)]
     telemetry_endpoint: SocketAddr,
 
+    /// Maximum size in bytes of a forwarded log body, longer bodies are truncated
+    #[arg(long, env = "ROTEL_LOG_MAX_BODY_BYTES")]
+    log_max_body_bytes: Option<usize>,
+
+    /// Destination for function logs: agent, stdout or file:<path>. The stdout and file
+    /// sinks write OTLP/JSON and bypass the configured exporters.
+    #[arg(long, env = "ROTEL_LOGS_SINK", default_value = "agent", value_parser = parse_logs_sink)]
+    logs_sink: LogsSink,
+
+    /// Severity for function logs with an unrecognized level, for example INFO
+    #[arg(long, env = "ROTEL_DEFAULT_LOG_SEVERITY", value_parser = parse_severity)]
+    default_log_severity: Option<SeverityNumber>,
+
+    /// Additional level names mapped to a severity, for example SEVERE=ERROR,CONFIG=DEBUG.
+    /// These take precedence over the built-in level names.
+    #[arg(long, env = "ROTEL_SEVERITY_MAP", value_parser = parse_severity_map)]
+    severity_map: Option<HashMap<String, SeverityNumber>>,
+
+    /// Add the other fields of JSON function logs as attributes, flattening nested objects
+    /// to this depth
+    #[arg(long, env = "ROTEL_LOG_FLATTEN_DEPTH")]
+    log_flatten_depth: Option<usize>,
+
+    /// Parse function log strings that hold an encoded JSON object as JSON logs
+    #[arg(long, env = "ROTEL_LOG_PARSE_NESTED_JSON")]
+    log_parse_nested_json: bool,
+
+    /// Add the original telemetry record of each log as a lambda.raw_record attribute, for
+    /// debugging how records are converted
+    #[arg(long, env = "ROTEL_LOG_INCLUDE_RAW")]
+    log_include_raw: bool,
+
+    /// Timestamp preferred for JSON function logs: record uses the log's own timestamp
+    /// field, event uses the time Lambda gave the telemetry event
+    #[arg(long, env = "ROTEL_LOG_TIMESTAMP_SOURCE", default_value = "record", value_parser = parse_timestamp_source)]
+    log_timestamp_source: TimestampSource,
+
+    /// Observed time of function logs: received uses the time the TelemetryAPI request
+    /// arrived, event uses the time Lambda gave the telemetry event, none leaves it unset
+    #[arg(long, env = "ROTEL_LOG_OBSERVED_TIME_SOURCE", default_value = "received", value_parser = parse_observed_time_source)]
+    log_observed_time_source: ObservedTimeSource,
+
+    /// Coalesce function logs until the next flush, sending early once this many records
+    /// are buffered. Logs are sent as each TelemetryAPI request arrives when unset.
+    #[arg(long, env = "ROTEL_LOG_COALESCE_MAX_RECORDS")]
+    log_coalesce_max_records: Option<usize>,
+
+    /// Wait a random delay up to this long before resolving secrets, so instances that cold
+    /// start together do not all call AWS at once. Limited to 1s, 0 disables it.
+    #[arg(long, env = "ROTEL_SECRETS_STARTUP_JITTER_MS", default_value = "0", value_parser = parse_duration)]
+    secrets_startup_jitter_ms: Duration,
+
+    /// How long a batch of logs may wait for the logs pipeline to accept it before it is
+    /// dropped. When unset the TelemetryAPI request waits until it is accepted. Accepts a
+    /// duration such as 250ms or 1s, plain numbers are milliseconds.
+    #[arg(long, env = "ROTEL_LOG_SEND_TIMEOUT_MS", value_parser = parse_duration)]
+    log_send_timeout_ms: Option<Duration>,
+
+    /// How long to wait for the TelemetryAPI to deliver remaining logs at shutdown, limited
+    /// to the time left after reserving time for the agent to stop. Accepts a duration
+    /// such as 250ms or 1s, plain numbers are milliseconds.
+    #[arg(long, env = "ROTEL_TELEMETRY_SHUTDOWN_GRACE_MS", default_value = "500ms", value_parser = parse_duration)]
+    telemetry_shutdown_grace_ms: Duration,
+
+    /// Total time allowed to shut down after the SHUTDOWN event, covering the final flush and
+    /// stopping the agent. Limited to the 2s Lambda grants extensions. Accepts a duration
+    /// such as 250ms or 1s, plain numbers are milliseconds.
+    #[arg(long, env = "ROTEL_SHUTDOWN_BUDGET_MS", default_value = "2s", value_parser = parse_duration)]
+    shutdown_budget_ms: Duration,
+
+    /// Maximum number of TelemetryAPI connections served concurrently
+    #[arg(long, env = "ROTEL_TELEMETRY_MAX_CONNECTIONS", default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    telemetry_max_connections: usize,
+
+    /// Close TelemetryAPI connections that have no request in progress for this long. Idle
+    /// connections are otherwise closed by the header read timeout. Accepts a duration
+    /// such as 250ms or 1s, plain numbers are milliseconds.
+    #[arg(long, env = "ROTEL_TELEMETRY_IDLE_TIMEOUT_MS", value_parser = parse_duration)]
+    telemetry_idle_timeout_ms: Option<Duration>,
+
+    /// Time allowed for Lambda to send a TelemetryAPI request's headers, including the wait
+    /// for the next request on a keep-alive connection. Accepts a duration such as 250ms or
+    /// 1s, plain numbers are milliseconds.
+    #[arg(long, env = "ROTEL_TELEMETRY_HEADER_TIMEOUT_MS", default_value = "3s", value_parser = parse_duration)]
+    telemetry_header_timeout_ms: Duration,
+
+    /// Fail to start when no exporter or OTLP exporter endpoint is configured, instead of
+    /// automatically selecting the blackhole exporter
+    #[arg(long, env = "ROTEL_REQUIRE_EXPORTER")]
+    require_exporter: bool,
+
+    /// Keep running the OTLP receiver when subscribing to the TelemetryAPI fails, rather than
+    /// exiting. Lambda logs and platform events are then not forwarded.
+    #[arg(long, env = "ROTEL_TELEMETRY_SUBSCRIBE_OPTIONAL")]
+    telemetry_subscribe_optional: bool,
+
+    /// TelemetryAPI schema version to subscribe with
+    #[arg(long, env = "ROTEL_TELEMETRY_SCHEMA_VERSION", default_value = TELEMETRY_API_SCHEMA, value_parser = parse_telemetry_schema_version)]
+    telemetry_schema_version: String,
+
+    /// Host Lambda delivers telemetry to, as part of the subscribe destination URI. Only
+    /// needs changing for emulators where sandbox.localdomain does not resolve.
+    #[arg(long, env = "ROTEL_TELEMETRY_DESTINATION_HOST", default_value = TELEMETRY_DESTINATION_HOST)]
+    telemetry_destination_host: String,
+
+    /// PEM certificate chain for receiving telemetry over HTTPS, the certificate must be
+    /// valid for sandbox.localdomain. Telemetry is received over plain HTTP when unset.
+    #[arg(long, env = "ROTEL_TELEMETRY_TLS_CERT", requires = "telemetry_tls_key")]
+    telemetry_tls_cert: Option<PathBuf>,
+
+    /// PEM private key for the TelemetryAPI TLS certificate
+    #[arg(long, env = "ROTEL_TELEMETRY_TLS_KEY", requires = "telemetry_tls_cert")]
+    telemetry_tls_key: Option<PathBuf>,
+
+    /// Connect timeout for Runtime API requests, so an unreachable API fails startup instead
+    /// of hanging. Requests themselves have no timeout, as the next event call blocks.
+    #[arg(long, env = "ROTEL_RUNTIME_CONNECT_TIMEOUT_MS", default_value = "1s", value_parser = parse_duration)]
+    runtime_connect_timeout_ms: Duration,
+
+    /// Skip checking that the Runtime API host is a loopback or link-local address, for
+    /// emulators that serve the API from another host
+    #[arg(long, env = "ROTEL_RUNTIME_API_ALLOW_ANY_HOST")]
+    runtime_api_allow_any_host: bool,
+
+    /// User-Agent sent on Runtime API and AWS requests
+    #[arg(long, env = "ROTEL_USER_AGENT", default_value = DEFAULT_USER_AGENT, value_parser = parse_user_agent)]
+    user_agent: HeaderValue,
+
+    /// Number of batches each exporter buffers while they are exported. Larger queues absorb
+    /// bursts without blocking the pipeline, but leave more to export within a flush.
+    #[arg(long, env = "ROTEL_SENDING_QUEUE_SIZE", default_value_t = SENDING_QUEUE_SIZE as u64, value_parser = clap::value_parser!(u64).range(1..=1000))]
+    sending_queue_size: u64,
+
+    /// Flush after every Nth invocation, instead of adapting between flushing after each
+    /// invocation and periodically based on the invocation rate
+    #[arg(long, env = "ROTEL_FLUSH_EVERY_N", value_parser = clap::value_parser!(u64).range(1..))]
+    flush_every_n: Option<u64>,
+
+    /// Minimum time between flushes, a flush that would start sooner after the previous one
+    /// is skipped so that its telemetry is sent with the next. Applies to every flush mode
+    /// but not to the final flush at shutdown. Accepts a duration such as 250ms or 1s, plain
+    /// numbers are milliseconds.
+    #[arg(long, env = "ROTEL_MIN_FLUSH_INTERVAL_MS", default_value = "0", value_parser = parse_duration)]
+    min_flush_interval_ms: Duration,
+
+    /// Run mode: full converts Lambda telemetry to OTLP, receiver-only skips the TelemetryAPI
+    /// subscription and logs pipeline and only runs the OTLP receiver for function telemetry
+    #[arg(value_enum, long, env = "ROTEL_MODE", default_value = "full")]
+    mode: ExtensionMode,
+
+    /// Export metrics about the extension's own overhead through the agent's OTLP receiver
+    #[arg(long, env = "ROTEL_EMIT_INTERNAL_METRICS")]
+    emit_internal_metrics: bool,
+
+    /// Export a span for each flush, with a child span per flush stage, through the agent's
+    /// traces pipeline
+    #[arg(long, env = "ROTEL_TRACE_FLUSH")]
+    trace_flush: bool,
+
+    /// Print platform.report metrics to stdout in CloudWatch Embedded Metric Format, so
+    /// CloudWatch creates metrics from them without an OTLP backend
+    #[arg(long, env = "ROTEL_EMIT_EMF")]
+    emit_emf: bool,
+
+    /// Also export the extension's own logs through the logs pipeline, under a dedicated
+    /// scope. They are always printed to stdout.
+    #[arg(long, env = "ROTEL_EXPORT_SELF_LOGS")]
+    export_self_logs: bool,
+
+    /// Export one summary data point per invocation, combining duration, cold start, billed
+    /// duration and memory utilization
+    #[arg(long, env = "ROTEL_EMIT_INVOCATION_SUMMARY")]
+    emit_invocation_summary: bool,
+
     #[arg(
         value_enum,
         long,
@@ -95,6 +333,57 @@ pub enum LogFormatArg {
     Json,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ExtensionMode {
+    /// Subscribe to the TelemetryAPI and forward Lambda logs alongside the OTLP receiver
+    Full,
+    /// Only run the OTLP receiver, for functions that send their own telemetry
+    ReceiverOnly,
+}
+
+impl ExtensionMode {
+    fn subscribes_to_telemetry(&self) -> bool {
+        *self == ExtensionMode::Full
+    }
+}
+
+/// Failures that decide the exit code, so that orchestration can tell errors that will not
+/// recover on retry from ones that might. Unclassified errors are runtime errors.
+#[derive(Debug)]
+enum ExtensionError {
+    /// Invalid configuration, including secrets that are invalid or do not exist
+    Config(BoxError),
+    /// Registering the extension or subscribing to the TelemetryAPI failed
+    Register(BoxError),
+}
+
+impl ExtensionError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            ExtensionError::Config(_) => EXIT_CONFIG_ERROR,
+            ExtensionError::Register(_) => EXIT_REGISTER_ERROR,
+        }
+    }
+}
+
+impl Display for ExtensionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtensionError::Config(e) | ExtensionError::Register(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ExtensionError {}
+
+// Errors that were not classified happened while running, after startup
+fn exit_code(err: &BoxError) -> u8 {
+    match err.downcast_ref::<ExtensionError>() {
+        Some(e) => e.exit_code(),
+        None => EXIT_RUNTIME_ERROR,
+    }
+}
+
 fn main() -> ExitCode {
     let start_time = Instant::now();
 
@@ -102,54 +391,118 @@ fn main() -> ExitCode {
     if let Some(env_file) = env_opt.env_file {
         if let Err(e) = load_env_file(&env_file) {
             eprintln!("Can not load envfile: {}", e);
-            return ExitCode::FAILURE;
+            return ExitCode::from(EXIT_CONFIG_ERROR);
         }
     }
 
     let opt = Arguments::parse();
 
-    let _guard = match setup_logging() {
+    let self_logs = opt
+        .export_self_logs
+        .then(|| Arc::new(SelfLogs::new(DEFAULT_SELF_LOGS_MAX_RECORDS)));
+    let _guard = match setup_logging(self_logs.clone()) {
         Ok(guard) => guard,
         Err(e) => {
             eprintln!("ERROR: failed to setup logging: {}", e);
-            return ExitCode::FAILURE;
+            return ExitCode::from(EXIT_CONFIG_ERROR);
         }
     };
 
+    let mut opts = ExtensionOptions::from_args(&opt);
+    opts.self_logs = self_logs;
     let agent = opt.agent_args;
-    let mut port_map = match bind_endpoints(&[
-        agent.otlp_receiver.otlp_grpc_endpoint,
-        agent.otlp_receiver.otlp_http_endpoint,
-        opt.telemetry_endpoint,
-    ]) {
-        Ok(ports) => ports,
+    let (port_map, telemetry_listener) = match bind_listeners(
+        &[
+            agent.otlp_receiver.otlp_grpc_endpoint,
+            agent.otlp_receiver.otlp_http_endpoint,
+        ],
+        opt.mode
+            .subscribes_to_telemetry()
+            .then_some(opt.telemetry_endpoint),
+    ) {
+        Ok(listeners) => listeners,
         Err(e) => {
             eprintln!("ERROR: {}", e);
 
-            return ExitCode::from(1);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
         }
     };
 
-    // Remove this, the rest are passed to the agent
-    let telemetry_listener = port_map.remove(&opt.telemetry_endpoint).unwrap();
-
     match run_extension(
         start_time,
         agent,
         port_map,
         telemetry_listener,
+        opts,
         &opt.environment,
     ) {
         Ok(_) => {}
         Err(e) => {
-            error!(error = ?e, "Failed to run agent.");
-            return ExitCode::from(1);
+            error!(error = %e, "Failed to run agent.");
+            return ExitCode::from(exit_code(&e));
         }
     }
 
     ExitCode::SUCCESS
 }
 
+// Binds the agent's OTLP receivers and, when subscribing, the TelemetryAPI endpoint. The
+// TelemetryAPI listener is returned separately, the rest are passed to the agent. Listeners
+// are keyed by address, so the TelemetryAPI can not share an address with a receiver.
+fn bind_listeners(
+    otlp_endpoints: &[SocketAddr],
+    telemetry_endpoint: Option<SocketAddr>,
+) -> Result<(HashMap<SocketAddr, Listener>, Option<Listener>), BoxError> {
+    let mut endpoints = otlp_endpoints.to_vec();
+    if let Some(telemetry) = telemetry_endpoint {
+        validate_telemetry_endpoint(otlp_endpoints, telemetry)?;
+        endpoints.push(telemetry);
+    }
+
+    let mut port_map = bind_endpoints(&endpoints).map_err(|e| e.to_string())?;
+
+    let telemetry_listener = match telemetry_endpoint {
+        Some(telemetry) => Some(
+            port_map
+                .remove(&telemetry)
+                .ok_or_else(|| format!("telemetry endpoint {} was not bound", telemetry))?,
+        ),
+        None => None,
+    };
+
+    Ok((port_map, telemetry_listener))
+}
+
+// Checked before binding, as a collision would otherwise surface as an address in use error,
+// or hand the TelemetryAPI listener to the agent
+fn validate_telemetry_endpoint(
+    otlp_endpoints: &[SocketAddr],
+    telemetry: SocketAddr,
+) -> Result<(), BoxError> {
+    match otlp_endpoints
+        .iter()
+        .find(|otlp| endpoints_collide(**otlp, telemetry))
+    {
+        Some(otlp) => Err(format!(
+            "telemetry endpoint {} collides with OTLP receiver endpoint {}, they must use different addresses",
+            telemetry, otlp
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+// Two endpoints collide when they are the same address, or share a port where either listens
+// on all interfaces. Port 0 is assigned on bind, so only an identical address collides.
+fn endpoints_collide(a: SocketAddr, b: SocketAddr) -> bool {
+    if a == b {
+        return true;
+    }
+    a.port() != 0
+        && a.port() == b.port()
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
 fn load_env_file(env_file: &String) -> Result<(), BoxError> {
     let subs = load_env_file_updates(env_file)?;
 
@@ -172,6 +525,51 @@ fn load_env_file_updates(env_file: &String) -> Result<Vec<(String, String)>, Box
     Ok(updates)
 }
 
+// Catch the default no config mode and default to the blackhole exporter instead of failing
+// to start, unless an exporter is required and the missing config is an error
+fn select_default_exporter(
+    agent_args: &mut AgentRun,
+    require_exporter: bool,
+) -> Result<(), BoxError> {
+    if agent_args.exporter.is_some() || agent_args.exporters.is_some() {
+        return Ok(());
+    }
+
+    let otlp = &agent_args.otlp_exporter.base;
+    if otlp.endpoint.is_some()
+        || otlp.traces_endpoint.is_some()
+        || otlp.metrics_endpoint.is_some()
+        || otlp.logs_endpoint.is_some()
+    {
+        return Ok(());
+    }
+
+    if require_exporter {
+        return Err("ROTEL_REQUIRE_EXPORTER is set but no exporter is configured, set ROTEL_EXPORTER, \
+            ROTEL_EXPORTERS or one of ROTEL_OTLP_EXPORTER_ENDPOINT, ROTEL_OTLP_EXPORTER_TRACES_ENDPOINT, \
+            ROTEL_OTLP_EXPORTER_METRICS_ENDPOINT or ROTEL_OTLP_EXPORTER_LOGS_ENDPOINT"
+            .into());
+    }
+
+    // todo: We should be able to startup with no config and not fail, identify best
+    // default mode.
+    info!("Automatically selecting blackhole exporter due to missing endpoint configs");
+    agent_args.exporter = Some(Exporter::Blackhole);
+    Ok(())
+}
+
+// Parse the agent arguments again from the process environment, used after secrets have
+// been substituted into ROTEL_ variables such as exporter headers
+fn reparse_agent_args<I, T>(args: I) -> Result<Box<AgentRun>, BoxError>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    Arguments::try_parse_from(args)
+        .map(|args| args.agent_args)
+        .map_err(|e| format!("Failed to parse arguments after resolving secrets: {}", e).into())
+}
+
 #[derive(Clone)]
 struct ArnEnvSubstitutor;
 impl Substitutor for ArnEnvSubstitutor {
@@ -190,339 +588,1051 @@ impl Substitutor for ArnEnvSubstitutor {
     }
 }
 
+// Extension settings, as opposed to the agent settings in AgentRun
+struct ExtensionOptions {
+    mode: ExtensionMode,
+    logs_config: LogsConfig,
+    emit_internal_metrics: bool,
+    trace_flush: bool,
+    emit_invocation_summary: bool,
+    emit_emf: bool,
+    // Set up with logging, before the options are parsed
+    self_logs: Option<Arc<SelfLogs>>,
+    shutdown_budget: Duration,
+    flush_every_n: Option<u64>,
+    min_flush_interval: Duration,
+    sending_queue_size: usize,
+    telemetry_shutdown_grace: Duration,
+    telemetry_schema_version: String,
+    telemetry_destination_host: String,
+    telemetry_subscribe_optional: bool,
+    require_exporter: bool,
+    telemetry_max_connections: usize,
+    telemetry_idle_timeout: Option<Duration>,
+    telemetry_header_timeout: Duration,
+    // Certificate and key paths
+    telemetry_tls: Option<(PathBuf, PathBuf)>,
+    runtime_connect_timeout: Duration,
+    runtime_api_allow_any_host: bool,
+    user_agent: HeaderValue,
+    log_coalesce_max_records: Option<usize>,
+    secrets_startup_jitter: Duration,
+}
+
+impl ExtensionOptions {
+    fn from_args(opt: &Arguments) -> Self {
+        let logs_config = LogsConfig {
+            max_body_bytes: opt.log_max_body_bytes,
+            sink: opt.logs_sink.clone(),
+            default_severity: opt.default_log_severity,
+            severity_map: opt.severity_map.clone().unwrap_or_default(),
+            flatten_depth: opt.log_flatten_depth,
+            timestamp_source: opt.log_timestamp_source,
+            send_timeout: opt.log_send_timeout_ms,
+            parse_nested_json: opt.log_parse_nested_json,
+            observed_time_source: opt.log_observed_time_source,
+            include_raw: opt.log_include_raw,
+        };
+
+        let shutdown_budget = shutdown_budget(opt.shutdown_budget_ms);
+
+        Self {
+            mode: opt.mode,
+            logs_config,
+            emit_internal_metrics: opt.emit_internal_metrics,
+            trace_flush: opt.trace_flush,
+            emit_invocation_summary: opt.emit_invocation_summary,
+            emit_emf: opt.emit_emf,
+            self_logs: None,
+            shutdown_budget,
+            flush_every_n: opt.flush_every_n,
+            sending_queue_size: opt.sending_queue_size as usize,
+            min_flush_interval: opt.min_flush_interval_ms,
+            telemetry_shutdown_grace: telemetry_shutdown_grace(
+                opt.telemetry_shutdown_grace_ms,
+                shutdown_budget,
+            ),
+            telemetry_schema_version: opt.telemetry_schema_version.clone(),
+            telemetry_destination_host: opt.telemetry_destination_host.clone(),
+            telemetry_subscribe_optional: opt.telemetry_subscribe_optional,
+            require_exporter: opt.require_exporter,
+            telemetry_max_connections: opt.telemetry_max_connections,
+            telemetry_idle_timeout: opt.telemetry_idle_timeout_ms,
+            telemetry_header_timeout: opt.telemetry_header_timeout_ms,
+            telemetry_tls: opt
+                .telemetry_tls_cert
+                .clone()
+                .zip(opt.telemetry_tls_key.clone()),
+            runtime_connect_timeout: opt.runtime_connect_timeout_ms,
+            runtime_api_allow_any_host: opt.runtime_api_allow_any_host,
+            user_agent: opt.user_agent.clone(),
+            log_coalesce_max_records: opt.log_coalesce_max_records,
+            secrets_startup_jitter: secrets_startup_jitter(opt.secrets_startup_jitter_ms),
+        }
+    }
+}
+
 #[tokio::main]
 async fn run_extension(
     start_time: Instant,
     mut agent_args: Box<AgentRun>,
     port_map: HashMap<SocketAddr, Listener>,
-    telemetry_listener: Listener,
+    telemetry_listener: Option<Listener>,
+    opts: ExtensionOptions,
     env: &String,
 ) -> Result<(), BoxError> {
     let mut tapi_join_set = JoinSet::new();
     let mut agent_join_set = JoinSet::new();
 
-    let client = build_hyper_client();
+    let client = build_hyper_client(opts.runtime_connect_timeout);
 
     let (bus_tx, mut bus_rx) = bounded(10);
-    let (logs_tx, logs_rx) = bounded(LOGS_QUEUE_SIZE);
-
-    let aws_creds = AwsCreds::from_env();
+    // Lambda logs are only converted when subscribed to the TelemetryAPI
+    let (logs_tx, logs_rx) = opts
+        .mode
+        .subscribes_to_telemetry()
+        .then(|| bounded(LOGS_QUEUE_SIZE))
+        .unzip();
+
+    let aws_config = AwsConfig {
+        user_agent: opts.user_agent.clone(),
+        ..AwsConfig::from_env()
+    };
+    let internal_metrics = opts
+        .emit_internal_metrics
+        .then(|| Arc::new(InternalMetrics::new()));
+    let invocation_summaries = opts
+        .emit_invocation_summary
+        .then(|| Arc::new(InvocationSummaries::new()));
 
     //
-    // Resolve secrets
+    // Resolve secrets and register
     //
-    let es = EnvArnParser::new();
-    let mut secure_arns = es.extract_arns_from_env();
-    if !secure_arns.is_empty() {
-        if CryptoProvider::get_default().is_none() {
-            rustls::crypto::aws_lc_rs::default_provider()
-                .install_default()
-                .unwrap();
-        }
-
-        resolve_secrets(aws_creds.clone(), &mut secure_arns).await?;
-        es.update_env_arn_secrets(secure_arns);
+    let (secrets_updated, r) = resolve_and_register(
+        resolve_env_secrets(
+            aws_config,
+            opts.secrets_startup_jitter,
+            internal_metrics.clone(),
+        ),
+        register_extension(
+            client.clone(),
+            opts.runtime_api_allow_any_host,
+            &opts.user_agent,
+        ),
+    )
+    .await?;
 
-        // We must reparse arguments now that the environment has been updated
-        agent_args = Arguments::parse().agent_args;
+    // We must reparse arguments now that the environment has been updated
+    if secrets_updated {
+        agent_args = reparse_agent_args(env::args_os()).map_err(ExtensionError::Config)?;
     }
 
-    let r = match lambda::api::register(client.clone()).await {
-        Ok(r) => r,
-        Err(e) => return Err(format!("Failed to register extension: {}", e).into()),
-    };
+    // Internal metrics are sent through the agent's own receiver
+    let otlp_http_endpoint = agent_args.otlp_receiver.otlp_http_endpoint;
 
-    let (mut flush_logs_tx, flush_logs_sub) = FlushBroadcast::new().into_parts();
+    let (flush_logs_tx, flush_logs_sub) = FlushBroadcast::new().into_parts();
     let (mut flush_pipeline_tx, flush_pipeline_sub) = FlushBroadcast::new().into_parts();
     let (mut flush_exporters_tx, flush_exporters_sub) = FlushBroadcast::new().into_parts();
 
     let agent_cancel = CancellationToken::new();
+    let agent_down = Arc::new(AtomicBool::new(false));
+    let logs_in_flight = Arc::new(AtomicUsize::new(0));
+    let status = Arc::new(ExtensionStatus::default());
     {
         // We control flushing manually, so set this to zero to disable the batch timer
         agent_args.batch.batch_timeout = Duration::ZERO;
 
-        // Catch the default no config mode and default to the blackhole exporter
-        // instead of failing to start
-        if agent_args.exporter.is_none() && agent_args.exporters.is_none() {
-            if agent_args.otlp_exporter.base.endpoint.is_none()
-                && agent_args.otlp_exporter.base.traces_endpoint.is_none()
-                && agent_args.otlp_exporter.base.metrics_endpoint.is_none()
-                && agent_args.otlp_exporter.base.logs_endpoint.is_none()
-            {
-                // todo: We should be able to startup with no config and not fail, identify best
-                // default mode.
-                info!("Automatically selecting blackhole exporter due to missing endpoint configs");
-                agent_args.exporter = Some(Exporter::Blackhole);
-            }
-        }
+        select_default_exporter(&mut agent_args, opts.require_exporter)
+            .map_err(ExtensionError::Config)?;
 
-        let agent = Agent::new(agent_args, port_map, SENDING_QUEUE_SIZE, env.clone())
-            .with_logs_rx(logs_rx, flush_logs_sub)
+        let mut agent = Agent::new(agent_args, port_map, opts.sending_queue_size, env.clone())
             .with_pipeline_flush(flush_pipeline_sub)
             .with_exporters_flush(flush_exporters_sub);
+        if let Some(logs_rx) = logs_rx {
+            agent = agent.with_logs_rx(logs_rx, flush_logs_sub);
+        }
         let token = agent_cancel.clone();
-        let agent_fut = async move { agent.run(token).await };
+        let agent_down = agent_down.clone();
+        let agent_fut = async move {
+            let res = agent.run(token).await;
+            // Stops the TelemetryAPI queueing logs that nothing will drain
+            agent_down.store(true, Ordering::Relaxed);
+            res
+        };
 
         agent_join_set.spawn(agent_fut);
     };
 
-    if let Err(e) = lambda::api::telemetry_subscribe(
-        client.clone(),
-        &r.extension_id,
-        &telemetry_listener.bound_address()?,
-    )
-    .await
-    {
-        return Err(format!("Failed to subscribe to telemetry: {}", e).into());
+    // The flusher sends coalesced logs into the logs channel before each flush
+    let logs_buffer = opts
+        .log_coalesce_max_records
+        .map(|max| Arc::new(Mutex::new(LogsBuffer::new(max))));
+    let buffered_logs = logs_buffer.clone().zip(logs_tx.clone());
+
+    // Without a TelemetryAPI subscription there is no logs pipeline to export them with
+    if opts.self_logs.is_some() && logs_tx.is_none() {
+        warn!("ROTEL_EXPORT_SELF_LOGS requires the TelemetryAPI subscription, not exporting");
     }
+    let self_logs = opts
+        .self_logs
+        .clone()
+        .zip(logs_tx.clone())
+        .map(|(logs, tx)| (logs, resource_from_env(), tx));
 
-    let telemetry = TelemetryAPI::new(telemetry_listener, logs_tx);
     let telemetry_cancel = CancellationToken::new();
-    {
-        let token = telemetry_cancel.clone();
-        let telemetry_fut = async move { telemetry.run(bus_tx.clone(), token).await };
-        tapi_join_set.spawn(telemetry_fut)
+    // Falls back to receiver-only when the subscription fails and that is allowed
+    let mut lifecycle_mode = opts.mode;
+    match (telemetry_listener, logs_tx) {
+        (Some(telemetry_listener), Some(logs_tx)) => {
+            let tls = match &opts.telemetry_tls {
+                Some((cert, key)) => {
+                    install_crypto_provider();
+                    Some(tls_server_config(cert, key).map_err(ExtensionError::Config)?)
+                }
+                None => None,
+            };
+
+            let subscribed = lambda::api::telemetry_subscribe(
+                client.clone(),
+                &r.extension_id,
+                &opts.telemetry_destination_host,
+                &telemetry_listener.bound_address()?,
+                &opts.telemetry_schema_version,
+                tls.is_some(),
+                &opts.user_agent,
+            )
+            .await;
+
+            match subscribed {
+                Ok(()) => {
+                    let telemetry =
+                        TelemetryAPI::new(telemetry_listener, logs_tx, opts.logs_config)
+                            .with_internal_metrics(internal_metrics.clone())
+                            .with_invocation_summaries(invocation_summaries.clone())
+                            .with_max_connections(opts.telemetry_max_connections)
+                            .with_idle_timeout(opts.telemetry_idle_timeout)
+                            .with_header_read_timeout(opts.telemetry_header_timeout)
+                            .with_tls(tls)
+                            .with_agent_down(agent_down)
+                            .with_logs_in_flight(logs_in_flight.clone())
+                            .with_logs_buffer(logs_buffer)
+                            .with_emit_emf(opts.emit_emf)
+                            .with_status(Some(status.clone()));
+                    let token = telemetry_cancel.clone();
+                    let telemetry_fut = async move { telemetry.run(bus_tx.clone(), token).await };
+                    tapi_join_set.spawn(telemetry_fut);
+                }
+                Err(e) if !opts.telemetry_subscribe_optional => {
+                    return Err(ExtensionError::Register(
+                        format!("Failed to subscribe to telemetry: {}", e).into(),
+                    )
+                    .into());
+                }
+                Err(e) => {
+                    error!(
+                        error = %e,
+                        "Failed to subscribe to telemetry, Lambda logs and platform events will \
+                        not be forwarded. Continuing with only the OTLP receiver."
+                    );
+                    lifecycle_mode = ExtensionMode::ReceiverOnly;
+
+                    // The logs pipeline was already started, its channel is held open until
+                    // shutdown along with the bus
+                    let token = telemetry_cancel.clone();
+                    tapi_join_set.spawn(async move {
+                        token.cancelled().await;
+                        drop(bus_tx);
+                        drop(logs_tx);
+                        Ok(())
+                    });
+                }
+            }
+        }
+        _ => {
+            info!("Running in receiver-only mode, not subscribing to the TelemetryAPI");
+
+            // Stands in for the TelemetryAPI until shutdown, holding the bus open so the
+            // lifecycle does not see it close
+            let token = telemetry_cancel.clone();
+            tapi_join_set.spawn(async move {
+                token.cancelled().await;
+                drop(bus_tx);
+                Ok(())
+            });
+        }
+    }
+
+    info!(
+        version = EXTENSION_VERSION,
+        "Rotel Lambda Extension started in {}ms",
+        start_time.elapsed().as_millis()
+    );
+    if let Some(m) = &internal_metrics {
+        m.record_coldstart(start_time.into_std());
+    }
+
+    let mut next_events = RuntimeApiNextEvents {
+        client: client.clone(),
+        extension_id: r.extension_id.clone(),
+        user_agent: opts.user_agent.clone(),
+        internal_metrics: internal_metrics.clone(),
+    };
+    let flush_traces = opts.trace_flush.then(|| Arc::new(FlushTraces::new()));
+    let mut flusher = PipelineFlusher {
+        logs_tx: opts.mode.subscribes_to_telemetry().then_some(flush_logs_tx),
+        buffered_logs,
+        self_logs,
+        pipeline_tx: flush_pipeline_tx,
+        exporters_tx: flush_exporters_tx,
+        internal_metrics: (internal_metrics.is_some()
+            || invocation_summaries.is_some()
+            || flush_traces.is_some())
+        .then(|| InternalMetricsExport {
+            metrics: internal_metrics,
+            invocation_summaries,
+            flush_traces,
+            client: client.clone(),
+            receiver: otlp_http_endpoint,
+            resource: resource_from_env(),
+        }),
+        status: status.clone(),
     };
 
+    run_lifecycle(
+        SystemClock {},
+        lifecycle_mode,
+        opts.flush_every_n,
+        opts.min_flush_interval,
+        &status,
+        &mut next_events,
+        &mut flusher,
+        &mut bus_rx,
+        &mut tapi_join_set,
+        &mut agent_join_set,
+    )
+    .await?;
+
+    // We have the shutdown budget to completely shutdown
+    let (final_stop, flush_deadline) = shutdown_deadlines(Instant::now(), opts.shutdown_budget);
+
+    // Wait for the TelemetryAPI to shutdown, this will stop the logs pipeline
+    telemetry_cancel.cancel();
+    wait::wait_for_tasks_with_timeout(&mut tapi_join_set, opts.telemetry_shutdown_grace).await?;
+
+    // Drain, flush and agent stop share the budget, leaving time for the agent to stop.
+    // Requests still being served may hold logs waiting for room in the logs channel, they
+    // must reach it before the final flush or they are lost when the agent is cancelled
+    if !drain_logs(&logs_in_flight, flush_deadline).await {
+        warn!(
+            batches = logs_in_flight.load(Ordering::Acquire),
+            "Logs were still waiting for the logs pipeline at shutdown"
+        );
+    }
+
+    // Flush the remaining telemetry under a single deadline for all stages
+    if !flusher.flush(Some(flush_deadline)).await {
+        warn!("Final flush did not complete before shutdown");
+    }
+
+    agent_cancel.cancel();
+
+    // Wait for agent
+    wait::wait_for_tasks_with_deadline(&mut agent_join_set, final_stop).await?;
+
+    Ok(())
+}
+
+// Waits until no accepted logs are waiting to enter the logs channel, returns false if
+// some still are at the deadline
+async fn drain_logs(in_flight: &AtomicUsize, deadline: Instant) -> bool {
+    loop {
+        if in_flight.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(DRAIN_POLL_MILLIS)).await;
+    }
+}
+
+// Drives the invocation lifecycle until a SHUTDOWN event is received, flushing telemetry
+// according to the current flush mode. Returns the reason given for the shutdown.
+async fn run_lifecycle<C, N, F>(
+    clock: C,
+    mode: ExtensionMode,
+    flush_every_n: Option<u64>,
+    min_flush_interval: Duration,
+    status: &ExtensionStatus,
+    next_events: &mut N,
+    flusher: &mut F,
+    bus_rx: &mut BoundedReceiver<JsonLambdaTelemetry>,
+    tapi_join_set: &mut JoinSet<Result<(), BoxError>>,
+    agent_join_set: &mut JoinSet<Result<(), BoxError>>,
+) -> Result<String, BoxError>
+where
+    C: Clock + Clone,
+    N: NextEventSource,
+    F: Flusher,
+{
     // Set up our global flush interval, will be reset when we flush periodically
     let mut default_flush_interval =
         tokio::time::interval(Duration::from_millis(DEFAULT_FLUSH_INTERVAL_MILLIS));
     default_flush_interval.tick().await; // first tick is instant
 
-    info!(
-        "Rotel Lambda Extension started in {}ms",
-        start_time.elapsed().as_millis()
-    );
-
     // Must perform next_request to get the first INVOKE call
-    let next_evt = match lambda::api::next_request(client.clone(), &r.extension_id).await {
+    let next_evt = match next_events.next_event().await {
         Ok(evt) => evt,
         Err(e) => return Err(format!("Failed to read next event: {}", e).into()),
     };
-    handle_next_response(next_evt);
-
-    let mut flush_control = FlushControl::new(SystemClock {});
+    let mut current_invocation = match handle_next_response(next_evt) {
+        NextResponse::Invoke(inv) => Some(inv),
+        NextResponse::Shutdown(reason) => {
+            info!(%reason, "Shutdown received, exiting");
+            return Ok(reason);
+        }
+    };
 
-    'outer: loop {
-        let mode = flush_control.pick();
-        let should_shutdown;
+    let mut flush_control = FlushControl::new(clock.clone())
+        .with_every_n(flush_every_n)
+        .with_min_flush_interval(min_flush_interval);
 
-        match mode {
-            FlushMode::AfterCall => {
-                'inner: loop {
-                    //
-                    // We must flush after every invocation
-                    //
-                    select! {
-                        msg = bus_rx.next() => {
-                            if let Some(evt) = msg {
-                                if let LambdaTelemetryRecord::PlatformRuntimeDone {..} = evt.record {
-                                    break 'inner;
+    loop {
+        let flush_mode = flush_control.pick();
+        status.set_flush_mode(
+            flush_mode.name(),
+            flush_control.invocation_interval_millis(),
+        );
+        // Tags the extension's own logs with the invocation they were written during
+        let span = invocation_span(&current_invocation);
+        let next_action = async {
+            let next_action;
+            match flush_mode {
+                // The Nth invocation of every-n mode is flushed like an after-call invocation
+                FlushMode::AfterCall | FlushMode::EveryN(true) => {
+                    // Without the TelemetryAPI there is no runtimeDone to wait for, so the
+                    // previous invocation's telemetry is flushed before asking for the next
+                    'inner: while mode.subscribes_to_telemetry() {
+                        //
+                        // We must flush after every invocation
+                        //
+                        select! {
+                            msg = bus_rx.next() => {
+                                if let Some(evt) = msg {
+                                    if let LambdaTelemetryRecord::PlatformRuntimeDone {..} = evt.record {
+                                        break 'inner;
+                                    }
                                 }
+                            },
+                            e = wait::wait_for_any_task(tapi_join_set) => {
+                                match e {
+                                    Ok(()) => warn!("Unexpected early exit of TelemetryAPI."),
+                                    Err(e) => return Err(e),
+                                }
+                            },
+                            e = wait::wait_for_any_task(agent_join_set) => {
+                                match e {
+                                    Ok(()) => warn!("Unexpected early exit of extension."),
+                                    Err(e) => return Err(e),
+                                }
+                            },
+                            _ = default_flush_interval.tick() => {
+                                force_flush(flusher, &flush_control, &mut default_flush_interval, invocation_deadline(&current_invocation, &clock)).await;
                             }
-                        },
-                        e = wait::wait_for_any_task(&mut tapi_join_set) => {
-                            match e {
-                                Ok(()) => warn!("Unexpected early exit of TelemetryAPI."),
-                                Err(e) => return Err(e),
-                            }
-                        },
-                        e = wait::wait_for_any_task(&mut agent_join_set) => {
-                            match e {
-                                Ok(()) => warn!("Unexpected early exit of extension."),
-                                Err(e) => return Err(e),
-                            }
-                        },
-                        _ = default_flush_interval.tick() => {
-                            force_flush(&mut flush_logs_tx, &mut flush_pipeline_tx, &mut flush_exporters_tx, &mut default_flush_interval).await;
                         }
                     }
-                }
 
-                //
-                // Force a flush
-                //
-                force_flush(
-                    &mut flush_logs_tx,
-                    &mut flush_pipeline_tx,
-                    &mut flush_exporters_tx,
-                    &mut default_flush_interval,
-                )
-                .await;
-
-                debug!("Received a platform runtime done message, invoking next request");
-                let next_evt =
-                    match lambda::api::next_request(client.clone(), &r.extension_id).await {
-                        Ok(evt) => evt,
-                        Err(e) => return Err(format!("Failed to read next event: {}", e).into()),
-                    };
+                    if let Some(inv) = &current_invocation {
+                        debug!(
+                            request_id = %inv.request_id,
+                            remaining = ?inv.remaining(clock.now()),
+                            "Flushing after invocation"
+                        );
+                    }
 
-                should_shutdown = handle_next_response(next_evt);
-            }
-            FlushMode::Periodic(mut control) => {
-                // Check if we need to force a flush, this should happen concurrently with the
-                // function invocation.
-                if control.should_flush() {
+                    //
+                    // Force a flush
+                    //
                     force_flush(
-                        &mut flush_logs_tx,
-                        &mut flush_pipeline_tx,
-                        &mut flush_exporters_tx,
+                        flusher,
+                        &flush_control,
                         &mut default_flush_interval,
+                        invocation_deadline(&current_invocation, &clock),
                     )
                     .await;
+
+                    debug!("Received a platform runtime done message, invoking next request");
+                    let next_evt = match next_events.next_event().await {
+                        Ok(evt) => evt,
+                        Err(e) => return Err(format!("Failed to read next event: {}", e).into()),
+                    };
+
+                    next_action = handle_next_response(next_evt);
                 }
+                // Invocations between those of every-n mode wait for the next event as in
+                // periodic mode, but flush on neither the periodic nor the default interval
+                FlushMode::Periodic(_) | FlushMode::EveryN(false) => {
+                    // Check if we need to force a flush, this should happen concurrently with the
+                    // function invocation.
+                    let periodic_flush = match flush_mode {
+                        FlushMode::Periodic(mut control) => control.should_flush(),
+                        _ => false,
+                    };
+                    let interval_flush = matches!(flush_mode, FlushMode::Periodic(_));
+                    if periodic_flush {
+                        force_flush(
+                            flusher,
+                            &flush_control,
+                            &mut default_flush_interval,
+                            invocation_deadline(&current_invocation, &clock),
+                        )
+                        .await;
+                    }
 
-                let next_event_fut = lambda::api::next_request(client.clone(), &r.extension_id);
-                pin!(next_event_fut);
+                    // Once the runtime is done the invocation is no longer in flight, so its
+                    // deadline does not bound flushes on the default interval
+                    let mut runtime_done = false;
 
-                'periodic_inner: loop {
-                    select! {
-                        biased;
+                    let next_event_fut = next_events.next_event();
+                    pin!(next_event_fut);
 
-                        next_resp = &mut next_event_fut => {
-                            // Reset the default flush timer on invocation, since we are checking whether to flush
-                            // at the top of the invocation anyways
-                            default_flush_interval.reset();
+                    'periodic_inner: loop {
+                        select! {
+                            biased;
 
-                            match next_resp {
-                                Err(e) => return Err(format!("Failed to read next event: {}", e).into()),
-                                Ok(next_evt) => {
-                                    should_shutdown = handle_next_response(next_evt);
+                            next_resp = &mut next_event_fut => {
+                                // Reset the default flush timer on invocation, since we are checking whether to flush
+                                // at the top of the invocation anyways
+                                default_flush_interval.reset();
 
-                                    break 'periodic_inner;
-                                }
+                                match next_resp {
+                                    Err(e) => return Err(format!("Failed to read next event: {}", e).into()),
+                                    Ok(next_evt) => {
+                                        next_action = handle_next_response(next_evt);
 
+                                        break 'periodic_inner;
+                                    }
+
+                                }
                             }
-                        }
 
-                        _ = bus_rx.next() => {
-                            // Mostly ignore these here for now
-                        },
+                            msg = bus_rx.next() => {
+                                if let Some(evt) = msg {
+                                    runtime_done |= ends_invocation(&evt, &current_invocation);
+                                }
+                            },
 
-                        e = wait::wait_for_any_task(&mut tapi_join_set) => {
-                            match e {
-                                Ok(()) => warn!("Unexpected early exit of TelemetryAPI."),
-                                Err(e) => return Err(e),
-                            }
-                        },
+                            e = wait::wait_for_any_task(tapi_join_set) => {
+                                match e {
+                                    Ok(()) => warn!("Unexpected early exit of TelemetryAPI."),
+                                    Err(e) => return Err(e),
+                                }
+                            },
 
-                        e = wait::wait_for_any_task(&mut agent_join_set) => {
-                            match e {
-                                Ok(()) => warn!("Unexpected early exit of extension."),
-                                Err(e) => return Err(e),
+                            e = wait::wait_for_any_task(agent_join_set) => {
+                                match e {
+                                    Ok(()) => warn!("Unexpected early exit of extension."),
+                                    Err(e) => return Err(e),
+                                }
+                            },
+
+                            _ = default_flush_interval.tick(), if interval_flush => {
+                                let deadline = if runtime_done {
+                                    None
+                                } else {
+                                    invocation_deadline(&current_invocation, &clock)
+                                };
+                                force_flush(flusher, &flush_control, &mut default_flush_interval, deadline).await;
                             }
-                        },
-
-                        _ = default_flush_interval.tick() => {
-                            force_flush(&mut flush_logs_tx, &mut flush_pipeline_tx, &mut flush_exporters_tx, &mut default_flush_interval).await;
                         }
                     }
                 }
             }
+            Ok::<_, BoxError>(next_action)
+        }
+        .instrument(span)
+        .await?;
+
+        match next_action {
+            NextResponse::Invoke(inv) => current_invocation = Some(inv),
+            NextResponse::Shutdown(reason) => {
+                info!(%reason, "Shutdown received, exiting");
+                return Ok(reason);
+            }
         }
+    }
+}
+
+// Source of the next Runtime API lifecycle event
+trait NextEventSource {
+    async fn next_event(&mut self) -> Result<NextEvent, BoxError>;
+}
+
+struct RuntimeApiNextEvents {
+    client: Client<HttpConnector, Full<Bytes>>,
+    extension_id: String,
+    user_agent: HeaderValue,
+    internal_metrics: Option<Arc<InternalMetrics>>,
+}
 
-        if should_shutdown {
-            info!("Shutdown received, exiting");
-            break 'outer;
+impl NextEventSource for RuntimeApiNextEvents {
+    async fn next_event(&mut self) -> Result<NextEvent, BoxError> {
+        let evt =
+            lambda::api::next_request(self.client.clone(), &self.extension_id, &self.user_agent)
+                .await?;
+        if let (NextEvent::Invoke(_), Some(m)) = (&evt, &self.internal_metrics) {
+            m.record_invocation();
         }
+        Ok(evt)
     }
+}
 
-    // We have two seconds to completely shutdown
-    let final_stop = Instant::now().add(Duration::from_secs(2));
+trait Flusher {
+    // Flush all telemetry, returns true if every stage completed
+    async fn flush(&mut self, deadline: Option<Instant>) -> bool;
+}
 
-    // Wait up to 500ms for the TelemetryAPI to shutdown, this will stop the logs pipeline
-    telemetry_cancel.cancel();
-    wait::wait_for_tasks_with_timeout(&mut tapi_join_set, Duration::from_millis(500)).await?;
+// Registration does not depend on secrets, so both run at once to shorten the cold start.
+// The agent is configured from the resolved environment, so it is started after this returns.
+async fn resolve_and_register<S, R, T>(secrets: S, register: R) -> Result<(bool, T), BoxError>
+where
+    S: Future<Output = Result<bool, BoxError>>,
+    R: Future<Output = Result<T, BoxError>>,
+{
+    let (secrets, registered) = tokio::join!(secrets, register);
+    // Invalid configuration is reported ahead of a registration failure
+    let secrets_updated = secrets?;
+    Ok((secrets_updated, registered?))
+}
 
-    agent_cancel.cancel();
+// Resolves the secrets referenced by the environment and updates it with their values,
+// returns whether there were any
+async fn resolve_env_secrets(
+    aws_config: AwsConfig,
+    startup_jitter: Duration,
+    internal_metrics: Option<Arc<InternalMetrics>>,
+) -> Result<bool, BoxError> {
+    let es = EnvArnParser::new();
+    let secure_arns = es.extract_arns_from_env();
+    if secure_arns.is_empty() {
+        return Ok(false);
+    }
+    es.check_secret_count(&secure_arns)
+        .map_err(ExtensionError::Config)?;
+    es.check_param_references()
+        .map_err(ExtensionError::Config)?;
+    install_crypto_provider();
+
+    let jitter = random_delay(startup_jitter);
+    if !jitter.is_zero() {
+        debug!(?jitter, "Delaying secrets resolution");
+        tokio::time::sleep(jitter).await;
+    }
 
-    // Wait for agent
-    wait::wait_for_tasks_with_deadline(&mut agent_join_set, final_stop).await?;
+    let secrets_start = Instant::now();
+    let optional_arns = es.optional_arns_from_env();
+    let resolved =
+        resolve_secrets_map_with_optional(aws_config, secure_arns.into_keys(), &optional_arns)
+            .await
+            .map_err(secrets_error)?;
+    if let Some(m) = &internal_metrics {
+        m.record_secrets_resolution(secrets_start.elapsed());
+    }
+    es.update_env_arn_secrets(resolved);
 
-    Ok(())
+    Ok(true)
 }
 
-async fn force_flush(
-    logs_tx: &mut FlushSender,
-    pipeline_tx: &mut FlushSender,
-    exporters_tx: &mut FlushSender,
-    default_flush: &mut Interval,
-) {
-    let start = Instant::now();
-    match timeout(
-        Duration::from_millis(FLUSH_LOGS_TIMEOUT_MILLIS),
-        logs_tx.broadcast(None),
-    )
-    .await
-    {
-        Err(_) => {
-            warn!("timeout waiting to logs");
-            return;
+// Secrets that are invalid or missing are a configuration error, a lookup that failed on
+// the service or the network is not and may succeed on the next start
+fn secrets_error(err: BoxError) -> BoxError {
+    if err.is::<LookupError>() {
+        err
+    } else {
+        ExtensionError::Config(err).into()
+    }
+}
+
+async fn register_extension(
+    client: Client<HttpConnector, Full<Bytes>>,
+    allow_any_host: bool,
+    user_agent: &HeaderValue,
+) -> Result<RegisterResponseBody, BoxError> {
+    lambda::api::validate_runtime_api_scheme().map_err(ExtensionError::Config)?;
+    if !allow_any_host {
+        lambda::api::validate_runtime_api_host().map_err(ExtensionError::Config)?;
+    }
+
+    lambda::api::register(client, user_agent)
+        .await
+        .map_err(|e| {
+            ExtensionError::Register(format!("Failed to register extension: {}", e).into()).into()
+        })
+}
+
+struct PipelineFlusher {
+    // Absent in receiver-only mode, which has no logs pipeline
+    logs_tx: Option<FlushSender>,
+    // Coalesced logs and the logs channel they are sent on
+    buffered_logs: Option<(Arc<Mutex<LogsBuffer>>, BoundedSender<Message<ResourceLogs>>)>,
+    // The extension's own logs, their resource and the logs channel they are sent on
+    self_logs: Option<(
+        Arc<SelfLogs>,
+        Resource,
+        BoundedSender<Message<ResourceLogs>>,
+    )>,
+    pipeline_tx: FlushSender,
+    exporters_tx: FlushSender,
+    internal_metrics: Option<InternalMetricsExport>,
+    status: Arc<ExtensionStatus>,
+}
+
+impl PipelineFlusher {
+    fn record_flush(&self, stage: &str, duration: Duration) {
+        if let Some(m) = self
+            .internal_metrics
+            .as_ref()
+            .and_then(|im| im.metrics.as_ref())
+        {
+            m.record_flush(stage, duration);
         }
-        Ok(Err(e)) => {
-            warn!("failed to flush logs: {}", e);
-            return;
+    }
+}
+
+impl Flusher for PipelineFlusher {
+    async fn flush(&mut self, deadline: Option<Instant>) -> bool {
+        // Push internal metrics into the receiver first so this flush carries them out
+        if let Some(im) = &self.internal_metrics {
+            im.export().await;
+            im.export_traces().await;
         }
-        _ => {}
+        let flush_traces = self
+            .internal_metrics
+            .as_ref()
+            .and_then(|im| im.flush_traces.clone());
+        let mut trace = flush_traces.as_ref().map(|t| t.start());
+        let mut flushed = true;
+
+        // Coalesced logs must be in the logs channel for the logs stage to flush them. A copy
+        // is sent so batches that miss this flush are put back for the next one, and the
+        // later stages still run.
+        if let Some((buffer, logs_tx)) = &self.buffered_logs {
+            let batches = buffer.lock().unwrap().take();
+            if !batches.is_empty() {
+                let fixed = Duration::from_millis(FLUSH_LOGS_TIMEOUT_MILLIS);
+                let send = logs_tx.send(Message::new(None, batches.clone(), None));
+                if !flush_stage("buffered_logs", fixed, deadline, send).await {
+                    buffer.lock().unwrap().restore(batches);
+                    flushed = false;
+                }
+            }
+        }
+
+        // As with the buffered logs, records that miss this flush are put back
+        if let Some((self_logs, resource, logs_tx)) = &self.self_logs {
+            if let Some(rl) = self_logs.take(resource) {
+                let fixed = Duration::from_millis(FLUSH_LOGS_TIMEOUT_MILLIS);
+                let send = logs_tx.send(Message::new(None, vec![rl.clone()], None));
+                if !flush_stage("self_logs", fixed, deadline, send).await {
+                    self_logs.restore(rl);
+                    flushed = false;
+                }
+            }
+        }
+
+        let stages = [
+            ("logs", FLUSH_LOGS_TIMEOUT_MILLIS, self.logs_tx.as_mut()),
+            (
+                "pipeline",
+                FLUSH_PIPELINE_TIMEOUT_MILLIS,
+                Some(&mut self.pipeline_tx),
+            ),
+            (
+                "exporters",
+                FLUSH_EXPORTERS_TIMEOUT_MILLIS,
+                Some(&mut self.exporters_tx),
+            ),
+        ];
+        let mut durations = Vec::with_capacity(stages.len());
+        for (stage, timeout_millis, tx) in stages {
+            let Some(tx) = tx else {
+                continue;
+            };
+            let start = Instant::now();
+            let fixed = Duration::from_millis(timeout_millis);
+            let outcome = run_flush_stage(stage, fixed, deadline, tx.broadcast(None)).await;
+            if let Some(trace) = &mut trace {
+                trace.stage(stage, start.elapsed(), outcome);
+            }
+            if outcome != StageOutcome::Done {
+                if let (Some(traces), Some(trace)) = (&flush_traces, trace) {
+                    traces.finish(trace);
+                }
+                return false;
+            }
+            durations.push((stage, start.elapsed()));
+        }
+        if let (Some(traces), Some(trace)) = (&flush_traces, trace) {
+            traces.finish(trace);
+        }
+        if !flushed {
+            return false;
+        }
+
+        for (stage, duration) in durations {
+            self.record_flush(stage, duration);
+        }
+        self.status.record_flush(SystemClock.now());
+        true
     }
-    let duration = Instant::now().duration_since(start);
-    debug!(?duration, "finished flushing logs");
+}
 
+fn invocation_span(invocation: &Option<Invocation>) -> Span {
+    match invocation {
+        Some(inv) => info_span!("invocation", requestId = %inv.request_id),
+        None => Span::none(),
+    }
+}
+
+// Runs a single flush stage, bounded by its own timeout and by the overall deadline so
+// that stages run in order and share whatever time remains
+async fn flush_stage<T, E: Display>(
+    stage: &str,
+    fixed: Duration,
+    deadline: Option<Instant>,
+    fut: impl Future<Output = Result<T, E>>,
+) -> bool {
+    run_flush_stage(stage, fixed, deadline, fut).await == StageOutcome::Done
+}
+
+// As flush_stage, telling a timeout apart from a failure
+async fn run_flush_stage<T, E: Display>(
+    stage: &str,
+    fixed: Duration,
+    deadline: Option<Instant>,
+    fut: impl Future<Output = Result<T, E>>,
+) -> StageOutcome {
     let start = Instant::now();
-    match timeout(
-        Duration::from_millis(FLUSH_PIPELINE_TIMEOUT_MILLIS),
-        pipeline_tx.broadcast(None),
-    )
-    .await
-    {
+    match timeout(clamp_flush_timeout(fixed, deadline, start), fut).await {
         Err(_) => {
-            warn!("timeout waiting to flush pipelines");
-            return;
+            warn!(stage, "timeout waiting to flush");
+            StageOutcome::TimedOut
         }
         Ok(Err(e)) => {
-            warn!("failed to flush pipelines: {}", e);
-            return;
+            warn!(stage, "failed to flush: {}", e);
+            StageOutcome::Failed
+        }
+        Ok(Ok(_)) => {
+            let duration = start.elapsed();
+            debug!(stage, ?duration, "finished flushing");
+            StageOutcome::Done
         }
-        _ => {}
     }
-    let duration = Instant::now().duration_since(start);
-    debug!(?duration, "finished flushing pipeline");
+}
 
-    let start = Instant::now();
-    match timeout(
-        Duration::from_millis(FLUSH_EXPORTERS_TIMEOUT_MILLIS),
-        exporters_tx.broadcast(None),
-    )
-    .await
-    {
-        Err(_) => {
-            warn!("timeout waiting to flush exporters");
+struct InternalMetricsExport {
+    metrics: Option<Arc<InternalMetrics>>,
+    invocation_summaries: Option<Arc<InvocationSummaries>>,
+    flush_traces: Option<Arc<FlushTraces>>,
+    client: Client<HttpConnector, Full<Bytes>>,
+    receiver: SocketAddr,
+    resource: Resource,
+}
+
+impl InternalMetricsExport {
+    async fn export(&self) {
+        let resource_metrics: Vec<_> = [
+            self.metrics.as_ref().and_then(|m| m.take(&self.resource)),
+            self.invocation_summaries
+                .as_ref()
+                .and_then(|s| s.take(&self.resource)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if resource_metrics.is_empty() {
             return;
         }
-        Ok(Err(e)) => {
-            warn!("failed to flush exporters: {}", e);
+
+        match timeout(
+            Duration::from_millis(FLUSH_LOGS_TIMEOUT_MILLIS),
+            export_internal_metrics(self.client.clone(), self.receiver, resource_metrics),
+        )
+        .await
+        {
+            Err(_) => warn!("timeout exporting internal metrics"),
+            Ok(Err(e)) => warn!("failed to export internal metrics: {}", e),
+            _ => {}
+        }
+    }
+
+    async fn export_traces(&self) {
+        let Some(rs) = self
+            .flush_traces
+            .as_ref()
+            .and_then(|t| t.take(&self.resource))
+        else {
             return;
+        };
+
+        match timeout(
+            Duration::from_millis(FLUSH_LOGS_TIMEOUT_MILLIS),
+            export_flush_traces(self.client.clone(), self.receiver, vec![rs]),
+        )
+        .await
+        {
+            Err(_) => warn!("timeout exporting flush traces"),
+            Ok(Err(e)) => warn!("failed to export flush traces: {}", e),
+            _ => {}
         }
-        _ => {}
     }
-    let duration = Instant::now().duration_since(start);
-    debug!(?duration, "finished flushing exporters");
-    default_flush.reset();
 }
 
-fn handle_next_response(evt: NextEvent) -> bool {
-    match evt {
-        NextEvent::Invoke(invoke) => debug!("Received an invoke request: {:?}", invoke),
-        NextEvent::Shutdown(_) => return true,
+// Flushes unless the previous flush was too recent, in which case its telemetry is left for
+// the next flush
+async fn force_flush<F: Flusher, C: Clock + Clone>(
+    flusher: &mut F,
+    flush_control: &FlushControl<C>,
+    default_flush: &mut Interval,
+    deadline: Option<Instant>,
+) {
+    if !flush_control.start_flush() {
+        debug!("Skipping flush within the minimum flush interval");
+        return;
+    }
+    if flusher.flush(deadline).await {
+        default_flush.reset();
+    }
+}
+
+// Limits a flush stage timeout so that it does not run past the deadline, less a
+// safety margin
+fn clamp_flush_timeout(fixed: Duration, deadline: Option<Instant>, now: Instant) -> Duration {
+    match deadline {
+        None => fixed,
+        Some(deadline) => fixed.min(
+            deadline
+                .saturating_duration_since(now)
+                .saturating_sub(Duration::from_millis(FLUSH_DEADLINE_SAFETY_MARGIN_MILLIS)),
+        ),
+    }
+}
+
+// The shutdown budget can be lowered, but not raised past what Lambda grants extensions
+fn shutdown_budget(configured: Duration) -> Duration {
+    let max = Duration::from_millis(SHUTDOWN_BUDGET_MILLIS);
+    if configured > max {
+        warn!(
+            ?configured,
+            ?max,
+            "Shutdown budget exceeds the time Lambda allows extensions, clamping"
+        );
+        return max;
     }
 
-    false
+    configured
+}
+
+// Time reserved for the agent to stop, at most the whole budget when it is smaller
+fn agent_stop_reserve(budget: Duration) -> Duration {
+    Duration::from_millis(SHUTDOWN_AGENT_STOP_MILLIS).min(budget)
+}
+
+// The final deadline the agent must stop by, and the earlier deadline for the final flush
+fn shutdown_deadlines(start: Instant, budget: Duration) -> (Instant, Instant) {
+    let final_stop = start.add(budget);
+    (final_stop, final_stop.sub(agent_stop_reserve(budget)))
+}
+
+// The telemetry grace period must leave time for the agent to stop within the overall
+// shutdown budget
+fn telemetry_shutdown_grace(configured: Duration, budget: Duration) -> Duration {
+    let max = budget - agent_stop_reserve(budget);
+    if configured > max {
+        warn!(
+            ?configured,
+            ?max,
+            "Telemetry shutdown grace exceeds the shutdown budget, clamping"
+        );
+        return max;
+    }
+
+    configured
+}
+
+fn secrets_startup_jitter(configured: Duration) -> Duration {
+    let max = Duration::from_millis(MAX_SECRETS_STARTUP_JITTER_MILLIS);
+    if configured > max {
+        warn!(
+            ?configured,
+            ?max,
+            "Secrets startup jitter is too long, clamping"
+        );
+        return max;
+    }
+
+    configured
+}
+
+// An invocation without a known deadline leaves flushes bounded only by their own timeouts,
+// rather than clamping them to nothing
+fn invocation_deadline<C: Clock>(invocation: &Option<Invocation>, clock: &C) -> Option<Instant> {
+    // An invocation past its deadline is no longer in flight, it must not clamp flushes to zero
+    let now = clock.now();
+    invocation
+        .as_ref()
+        .filter(|inv| inv.deadline_ms > 0 && inv.deadline_ms > now)
+        .map(|inv| Instant::now().add(inv.remaining(now)))
+}
+
+// Whether a telemetry event is the runtimeDone of the invocation, a late runtimeDone of an
+// earlier invocation does not end it
+fn ends_invocation(evt: &JsonLambdaTelemetry, invocation: &Option<Invocation>) -> bool {
+    match (&evt.record, invocation) {
+        (LambdaTelemetryRecord::PlatformRuntimeDone { request_id, .. }, Some(inv)) => {
+            *request_id == inv.request_id
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum NextResponse {
+    Invoke(Invocation),
+    // Reason given by Lambda: spindown, timeout or failure
+    Shutdown(String),
+}
+
+fn handle_next_response(evt: NextEvent) -> NextResponse {
+    match evt {
+        NextEvent::Invoke(invoke) => {
+            debug!("Received an invoke request: {:?}", invoke);
+            NextResponse::Invoke(Invocation::from(&invoke))
+        }
+        NextEvent::Shutdown(shutdown) => NextResponse::Shutdown(shutdown.shutdown_reason),
+    }
 }
 
 type LoggerGuard = tracing_appender::non_blocking::WorkerGuard;
 
+// JSON output also carries the current span, so logs written during an invocation include
+// its requestId
+fn fmt_layer<S, W>(writer: W) -> fmt::Layer<S, DefaultFields, Format<Full, ()>, W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fmt::layer()
+        .with_writer(writer)
+        // disable printing of the module
+        .with_target(false)
+        // cloudwatch will add time
+        .without_time()
+        // cloudwatch doesn't play nice with escape codes
+        .with_ansi(false)
+}
+
 // todo: match logging to the recommended lambda extension approach
-fn setup_logging() -> Result<LoggerGuard, BoxError> {
+fn setup_logging(self_logs: Option<Arc<SelfLogs>>) -> Result<LoggerGuard, BoxError> {
     let (non_blocking_writer, guard) = tracing_appender::non_blocking(std::io::stdout());
 
     let filter = EnvFilter::builder()
@@ -536,43 +1646,63 @@ fn setup_logging() -> Result<LoggerGuard, BoxError> {
         .to_uppercase()
         == "JSON";
 
-    let layer = tracing_subscriber::fmt::layer()
-        .with_writer(non_blocking_writer)
-        // disable printing of the module
-        .with_target(false)
-        // cloudwatch will add time
-        .without_time()
-        // cloudwatch doesn't play nice with escape codes
-        .with_ansi(false);
+    // Tag our output so it is not forwarded again when it returns as extension telemetry
+    let layer = fmt_layer(move || MarkedWriter::new(non_blocking_writer.clone(), is_json));
 
     if is_json {
         let file_layer = layer.json();
 
-        let subscriber = Registry::default().with(filter).with(file_layer);
+        let subscriber = Registry::default()
+            .with(filter)
+            .with(file_layer)
+            .with(self_logs.map(SelfLogsLayer::new));
         tracing::subscriber::set_global_default(subscriber).unwrap();
     } else {
         let file_layer = layer.compact();
 
-        let subscriber = Registry::default().with(filter).with(file_layer);
+        let subscriber = Registry::default()
+            .with(filter)
+            .with(file_layer)
+            .with(self_logs.map(SelfLogsLayer::new));
         tracing::subscriber::set_global_default(subscriber).unwrap();
     }
 
     Ok(guard)
 }
 
-fn build_hyper_client() -> Client<HttpConnector, Full<Bytes>> {
+fn build_hyper_client(connect_timeout: Duration) -> Client<HttpConnector, Full<Bytes>> {
     hyper_util::client::legacy::Client::builder(TokioExecutor::new())
         // todo: make configurable
         .pool_idle_timeout(Duration::from_secs(30))
         .pool_max_idle_per_host(5)
         .timer(TokioTimer::new())
-        .build::<_, Full<Bytes>>(HttpConnector::new())
+        .build::<_, Full<Bytes>>(runtime_connector(connect_timeout))
+}
+
+// Both secret resolution and the TelemetryAPI TLS listener need a process-wide provider
+fn install_crypto_provider() {
+    if CryptoProvider::get_default().is_none() {
+        rustls::crypto::aws_lc_rs::default_provider()
+            .install_default()
+            .unwrap();
+    }
+}
+
+// Only connecting is bounded, the next event request may legitimately block for a long time
+fn runtime_connector(connect_timeout: Duration) -> HttpConnector {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(connect_timeout));
+    connector
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::runtime_api::{MockCall, MockEvent, MockRuntimeApi};
+    use rotel::bounded_channel::BoundedSender;
+    use std::cell::Cell;
     use std::io::Write;
+    use std::rc::Rc;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -584,6 +1714,7 @@ mod test {
             "ROTEL_ESCAPED=\"NotMe\\${TEAM}\"",
         ]);
 
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         unsafe { std::env::set_var("TOKEN", "123abc") };
         unsafe { std::env::set_var("TEAM", "frontend") };
 
@@ -604,6 +1735,833 @@ mod test {
         );
     }
 
+    // Removes an environment variable when dropped, so a failed assertion does not leave it
+    // set for the other tests
+    struct RemoveEnvOnDrop(&'static str);
+
+    impl Drop for RemoveEnvOnDrop {
+        fn drop(&mut self) {
+            unsafe { env::remove_var(self.0) }
+        }
+    }
+
+    #[test]
+    fn test_secret_in_exporter_headers() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let _unset = RemoveEnvOnDrop("ROTEL_OTLP_EXPORTER_CUSTOM_HEADERS");
+        unsafe {
+            env::set_var(
+                "ROTEL_OTLP_EXPORTER_CUSTOM_HEADERS",
+                "api-key=${arn:aws:secretsmanager:us-east-1:123456789012:secret:otlp-key}",
+            )
+        }
+
+        let es = EnvArnParser::new();
+        let mut arns = es.extract_arns_from_env();
+        let key = "arn:aws:secretsmanager:us-east-1:123456789012:secret:otlp-key";
+        assert!(arns.contains_key(key));
+
+        arns.insert(key.to_string(), "resolved-api-key".to_string());
+        es.update_env_arn_secrets(arns);
+        assert_eq!(
+            "api-key=resolved-api-key",
+            env::var("ROTEL_OTLP_EXPORTER_CUSTOM_HEADERS").unwrap()
+        );
+
+        // The agent sees the resolved header value once arguments are reparsed
+        let agent_args = format!(
+            "{:?}",
+            reparse_agent_args(["rotel-lambda-extension"]).unwrap()
+        );
+        assert!(agent_args.contains("resolved-api-key"));
+        assert!(!agent_args.contains("${arn:"));
+    }
+
+    #[test]
+    fn test_select_default_exporter() {
+        let parse = |args: &[&str]| {
+            let mut all = vec!["rotel-lambda-extension"];
+            all.extend(args);
+            Arguments::try_parse_from(all).unwrap().agent_args
+        };
+
+        // Without any exporter config the blackhole exporter is selected by default
+        let mut agent_args = parse(&[]);
+        select_default_exporter(&mut agent_args, false).unwrap();
+        assert!(matches!(agent_args.exporter, Some(Exporter::Blackhole)));
+
+        // A missing exporter config is an error when one is required
+        let mut agent_args = parse(&[]);
+        let err = select_default_exporter(&mut agent_args, true).unwrap_err();
+        assert!(err.to_string().contains("ROTEL_OTLP_EXPORTER_ENDPOINT"));
+        assert!(agent_args.exporter.is_none());
+
+        // Any endpoint satisfies the requirement and is left untouched
+        let mut agent_args = parse(&["--otlp-exporter-logs-endpoint=http://127.0.0.1:4318"]);
+        select_default_exporter(&mut agent_args, true).unwrap();
+        assert!(agent_args.exporter.is_none());
+
+        assert!(
+            Arguments::try_parse_from(["rotel-lambda-extension", "--require-exporter"])
+                .unwrap()
+                .require_exporter
+        );
+    }
+
+    #[test]
+    fn test_bind_listeners_overlapping_endpoints() {
+        let otlp = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let err = bind_listeners(&[otlp], Some(otlp)).unwrap_err();
+        assert_eq!(
+            format!(
+                "telemetry endpoint {} collides with OTLP receiver endpoint {}, they must use different addresses",
+                otlp, otlp
+            ),
+            err.to_string()
+        );
+
+        let telemetry: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (port_map, telemetry_listener) = bind_listeners(&[otlp], Some(telemetry)).unwrap();
+        assert!(port_map.contains_key(&otlp));
+        assert!(!port_map.contains_key(&telemetry));
+        assert!(telemetry_listener.is_some());
+
+        // Receiver-only mode binds no TelemetryAPI listener
+        let (_, telemetry_listener) = bind_listeners(&[], None).unwrap();
+        assert!(telemetry_listener.is_none());
+    }
+
+    #[test]
+    fn test_validate_telemetry_endpoint() {
+        let grpc: SocketAddr = "0.0.0.0:4317".parse().unwrap();
+        let http: SocketAddr = "127.0.0.1:4318".parse().unwrap();
+        let otlp = [grpc, http];
+
+        // The same address, or the same port on all interfaces
+        for telemetry in [
+            "127.0.0.1:4318",
+            "127.0.0.1:4317",
+            "0.0.0.0:4318",
+            "[::]:4318",
+        ] {
+            let telemetry: SocketAddr = telemetry.parse().unwrap();
+            let err = validate_telemetry_endpoint(&otlp, telemetry).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("telemetry endpoint {} collides", telemetry)),
+                "{}",
+                err
+            );
+        }
+
+        // Distinct ports, or the same port on different interfaces
+        for telemetry in ["0.0.0.0:4447", "127.0.0.2:4318", "127.0.0.1:0"] {
+            let telemetry: SocketAddr = telemetry.parse().unwrap();
+            assert!(validate_telemetry_endpoint(&otlp, telemetry).is_ok());
+        }
+        assert!(
+            validate_telemetry_endpoint(
+                &["127.0.0.1:0".parse().unwrap()],
+                "127.0.0.1:0".parse().unwrap()
+            )
+            .is_err()
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_logs_before_agent_cancel() {
+        let (logs_tx, mut logs_rx) = bounded::<u32>(1);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+
+        // The channel is full, so a second batch waits for room like a TelemetryAPI request
+        logs_tx.send(1).await.unwrap();
+        in_flight.fetch_add(1, Ordering::AcqRel);
+        let sender = {
+            let (logs_tx, in_flight) = (logs_tx.clone(), in_flight.clone());
+            tokio::spawn(async move {
+                logs_tx.send(2).await.unwrap();
+                in_flight.fetch_sub(1, Ordering::AcqRel);
+            })
+        };
+
+        // The agent is slow to make room
+        let agent = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let first = logs_rx.next().await.unwrap();
+            (first, logs_rx)
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        assert!(drain_logs(&in_flight, deadline).await);
+
+        // Once drained, the in-flight batch is in the channel for the final flush to carry
+        // to the agent before it is cancelled
+        sender.await.unwrap();
+        let (first, mut logs_rx) = agent.await.unwrap();
+        assert_eq!(1, first);
+        assert_eq!(Some(2), logs_rx.next().await);
+
+        // Gives up at the deadline when nothing makes room
+        in_flight.fetch_add(1, Ordering::AcqRel);
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(!drain_logs(&in_flight, deadline).await);
+    }
+
+    #[tokio::test]
+    async fn test_logs_carry_request_id() {
+        let buf = LogBuffer::default();
+        let writer = buf.clone();
+        let subscriber = Registry::default().with(fmt_layer(move || writer.clone()).json());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let clock = TestClock::new(1_000);
+        let (bus_tx, mut bus_rx) = bounded(10);
+        let mut next_events = TestNextEvents {
+            clock: clock.clone(),
+            bus_tx,
+            invocations: 1,
+            interval_millis: 1_000,
+            runtime_done: true,
+        };
+        let mut flusher = TestFlusher {
+            clock: clock.clone(),
+            flushes: vec![],
+        };
+        let mut tapi_join_set = JoinSet::new();
+        tapi_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+        let mut agent_join_set = JoinSet::new();
+        agent_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+
+        run_lifecycle(
+            clock.clone(),
+            ExtensionMode::Full,
+            None,
+            Duration::ZERO,
+            &ExtensionStatus::default(),
+            &mut next_events,
+            &mut flusher,
+            &mut bus_rx,
+            &mut tapi_join_set,
+            &mut agent_join_set,
+        )
+        .await
+        .unwrap();
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let flushing = output
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .find(|l| l["fields"]["message"] == "Flushing after invocation")
+            .expect("no flush log");
+        assert_eq!(
+            "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa",
+            flushing["span"]["requestId"]
+        );
+    }
+
+    #[test]
+    fn test_handle_next_response_invoke() {
+        let evt: NextEvent = serde_json::from_str(
+            r#"{
+    "eventType": "INVOKE",
+    "deadlineMs": 1676051295123,
+    "requestId": "3da1f2dc-3222-475e-9205-e2e6c6318895",
+    "invokedFunctionArn": "arn:aws:lambda:us-east-1:123456789012:function:ExtensionTest",
+    "tracing": {
+        "type": "X-Amzn-Trace-Id",
+        "value": "Root=1-5f35ae12-0c0fec141ab77a00bc047aa2;Parent=2be948a625588e32;Sampled=1"
+    }
+}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            NextResponse::Invoke(Invocation {
+                request_id: "3da1f2dc-3222-475e-9205-e2e6c6318895".to_string(),
+                deadline_ms: 1676051295123,
+            }),
+            handle_next_response(evt)
+        );
+    }
+
+    #[test]
+    fn test_invocation_deadline() {
+        let clock = TestClock::new(1_000);
+        let inv = |deadline_ms| {
+            Some(Invocation {
+                request_id: "3da1f2dc-3222-475e-9205-e2e6c6318895".to_string(),
+                deadline_ms,
+            })
+        };
+
+        let deadline = invocation_deadline(&inv(4_000), &clock).unwrap();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_millis(2_900));
+        assert!(remaining <= Duration::from_millis(3_000));
+
+        // A missing deadline must not clamp flushes to zero
+        assert!(invocation_deadline(&inv(0), &clock).is_none());
+        assert!(invocation_deadline(&None, &clock).is_none());
+
+        // Nor must the deadline of an invocation that has passed it
+        clock.advance(3_000);
+        assert!(invocation_deadline(&inv(4_000), &clock).is_none());
+    }
+
+    #[test]
+    fn test_ends_invocation() {
+        let runtime_done = |request_id: &str| -> JsonLambdaTelemetry {
+            serde_json::from_value(serde_json::json!({
+                "time": "2022-10-12T00:00:00.000Z",
+                "type": "platform.runtimeDone",
+                "record": {"requestId": request_id, "status": "success"},
+            }))
+            .unwrap()
+        };
+        let inv = Some(Invocation {
+            request_id: "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa".to_string(),
+            deadline_ms: 4_000,
+        });
+
+        assert!(ends_invocation(
+            &runtime_done("6d68ca91-49c9-448d-89b8-7ca3e6dc66aa"),
+            &inv
+        ));
+        // The late runtimeDone of the previous invocation
+        assert!(!ends_invocation(
+            &runtime_done("3da1f2dc-3222-475e-9205-e2e6c6318895"),
+            &inv
+        ));
+        assert!(!ends_invocation(
+            &runtime_done("6d68ca91-49c9-448d-89b8-7ca3e6dc66aa"),
+            &None
+        ));
+    }
+
+    #[test]
+    fn test_handle_next_response_shutdown() {
+        for reason in ["spindown", "timeout", "failure"] {
+            let evt: NextEvent = serde_json::from_str(&format!(
+                r#"{{"eventType": "SHUTDOWN", "shutdownReason": "{}", "deadlineMs": 1676051295123}}"#,
+                reason
+            ))
+            .unwrap();
+
+            assert_eq!(
+                NextResponse::Shutdown(reason.to_string()),
+                handle_next_response(evt)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_stages_share_deadline() {
+        let start = Instant::now();
+        let deadline = start.add(Duration::from_millis(400));
+
+        // The first stage uses part of the budget and completes
+        let first = flush_stage(
+            "pipeline",
+            Duration::from_millis(FLUSH_PIPELINE_TIMEOUT_MILLIS),
+            Some(deadline),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok::<_, BoxError>(())
+            },
+        )
+        .await;
+        assert!(first);
+
+        // The second stage only gets what remains, not its own 3s timeout
+        let second = flush_stage(
+            "exporters",
+            Duration::from_millis(FLUSH_EXPORTERS_TIMEOUT_MILLIS),
+            Some(deadline),
+            async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok::<_, BoxError>(())
+            },
+        )
+        .await;
+        assert!(!second);
+
+        assert!(start.elapsed() < Duration::from_millis(1_000));
+        assert!(Instant::now() <= deadline);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_and_register_overlap() {
+        let delay = Duration::from_millis(200);
+        let secrets_done = Arc::new(Mutex::new(None));
+
+        let done = secrets_done.clone();
+        let secrets = async move {
+            tokio::time::sleep(delay).await;
+            *done.lock().unwrap() = Some(Instant::now());
+            Ok(true)
+        };
+        let register = async move {
+            tokio::time::sleep(delay).await;
+            Ok::<_, BoxError>("mock-extension-id")
+        };
+
+        let start = Instant::now();
+        let (secrets_updated, id) = resolve_and_register(secrets, register).await.unwrap();
+        let returned = Instant::now();
+
+        // Both ran at once, rather than one after the other
+        assert!(returned - start < delay * 2, "{:?}", returned - start);
+        assert!(secrets_updated);
+        assert_eq!("mock-extension-id", id);
+        // The agent starts after this returns, so only once secrets are resolved
+        assert!(secrets_done.lock().unwrap().unwrap() <= returned);
+
+        // A secrets failure is a configuration error, even when registration also fails
+        let err = resolve_and_register(
+            async { Err::<bool, BoxError>(ExtensionError::Config("invalid secret".into()).into()) },
+            async { Err::<(), BoxError>(ExtensionError::Register("403".into()).into()) },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(EXIT_CONFIG_ERROR, exit_code(&err));
+
+        let err = resolve_and_register(async { Ok(false) }, async {
+            Err::<(), BoxError>(ExtensionError::Register("403".into()).into())
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(EXIT_REGISTER_ERROR, exit_code(&err));
+    }
+
+    #[test]
+    fn test_secrets_startup_jitter() {
+        let ms = Duration::from_millis;
+        assert_eq!(ms(200), secrets_startup_jitter(ms(200)));
+        assert_eq!(
+            ms(MAX_SECRETS_STARTUP_JITTER_MILLIS),
+            secrets_startup_jitter(ms(30_000))
+        );
+
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension"]).unwrap();
+        assert_eq!(Duration::ZERO, opt.secrets_startup_jitter_ms);
+    }
+
+    #[test]
+    fn test_telemetry_shutdown_grace() {
+        let ms = Duration::from_millis;
+        let budget = ms(SHUTDOWN_BUDGET_MILLIS);
+        assert_eq!(ms(500), telemetry_shutdown_grace(ms(500), budget));
+        assert_eq!(ms(100), telemetry_shutdown_grace(ms(100), budget));
+        assert_eq!(
+            Duration::ZERO,
+            telemetry_shutdown_grace(Duration::ZERO, budget)
+        );
+        assert_eq!(
+            ms(SHUTDOWN_BUDGET_MILLIS - SHUTDOWN_AGENT_STOP_MILLIS),
+            telemetry_shutdown_grace(ms(10_000), budget)
+        );
+
+        // A lower budget lowers the limit
+        assert_eq!(ms(300), telemetry_shutdown_grace(ms(500), ms(800)));
+        assert_eq!(Duration::ZERO, telemetry_shutdown_grace(ms(500), ms(400)));
+
+        let opt = Arguments::try_parse_from([
+            "rotel-lambda-extension",
+            "--telemetry-shutdown-grace-ms",
+            "250",
+        ])
+        .unwrap();
+        assert_eq!(ms(250), opt.telemetry_shutdown_grace_ms);
+
+        let opt = Arguments::try_parse_from([
+            "rotel-lambda-extension",
+            "--telemetry-shutdown-grace-ms",
+            "1s",
+        ])
+        .unwrap();
+        assert_eq!(ms(1_000), opt.telemetry_shutdown_grace_ms);
+
+        assert!(
+            Arguments::try_parse_from([
+                "rotel-lambda-extension",
+                "--telemetry-shutdown-grace-ms",
+                "2x",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_user_agent_arg() {
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension"]).unwrap();
+        assert_eq!(DEFAULT_USER_AGENT, &opt.user_agent);
+
+        let opt =
+            Arguments::try_parse_from(["rotel-lambda-extension", "--user-agent", "custom/1.0"])
+                .unwrap();
+        assert_eq!("custom/1.0", &opt.user_agent);
+
+        // A blank value keeps the default, an invalid one fails at startup
+        let opt =
+            Arguments::try_parse_from(["rotel-lambda-extension", "--user-agent", " "]).unwrap();
+        assert_eq!(DEFAULT_USER_AGENT, &opt.user_agent);
+        assert!(
+            Arguments::try_parse_from(["rotel-lambda-extension", "--user-agent", "bad\nagent"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_shutdown_budget() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension"]).unwrap();
+        assert_eq!(ms(SHUTDOWN_BUDGET_MILLIS), opt.shutdown_budget_ms);
+
+        // The configured budget sets the deadline the agent is waited on until
+        let opt =
+            Arguments::try_parse_from(["rotel-lambda-extension", "--shutdown-budget-ms", "750ms"])
+                .unwrap();
+        let (final_stop, flush_deadline) =
+            shutdown_deadlines(start, shutdown_budget(opt.shutdown_budget_ms));
+        assert_eq!(start + ms(750), final_stop);
+        assert_eq!(start + ms(250), flush_deadline);
+
+        // Clamped to what Lambda allows
+        let (final_stop, flush_deadline) = shutdown_deadlines(start, shutdown_budget(ms(5_000)));
+        assert_eq!(start + ms(SHUTDOWN_BUDGET_MILLIS), final_stop);
+        assert_eq!(
+            start + ms(SHUTDOWN_BUDGET_MILLIS - SHUTDOWN_AGENT_STOP_MILLIS),
+            flush_deadline
+        );
+
+        // A budget smaller than the agent stop reserve is all given to the agent
+        let (final_stop, flush_deadline) = shutdown_deadlines(start, shutdown_budget(ms(200)));
+        assert_eq!(start + ms(200), final_stop);
+        assert_eq!(start, flush_deadline);
+    }
+
+    #[test]
+    fn test_clamp_flush_timeout() {
+        let now = Instant::now();
+        let fixed = Duration::from_millis(FLUSH_EXPORTERS_TIMEOUT_MILLIS);
+
+        // No deadline, use the fixed timeout
+        assert_eq!(fixed, clamp_flush_timeout(fixed, None, now));
+
+        // Deadline far away
+        let deadline = now.add(Duration::from_secs(60));
+        assert_eq!(fixed, clamp_flush_timeout(fixed, Some(deadline), now));
+
+        // Deadline is near, clamp to the remaining time less the margin
+        let deadline = now.add(Duration::from_millis(1_000));
+        assert_eq!(
+            Duration::from_millis(1_000 - FLUSH_DEADLINE_SAFETY_MARGIN_MILLIS),
+            clamp_flush_timeout(fixed, Some(deadline), now)
+        );
+
+        // Deadline within the margin or passed
+        let deadline = now.add(Duration::from_millis(
+            FLUSH_DEADLINE_SAFETY_MARGIN_MILLIS / 2,
+        ));
+        assert_eq!(
+            Duration::ZERO,
+            clamp_flush_timeout(fixed, Some(deadline), now)
+        );
+        assert_eq!(
+            Duration::ZERO,
+            clamp_flush_timeout(fixed, Some(now), now.add(Duration::from_secs(1)))
+        );
+    }
+
+    #[derive(Clone)]
+    struct TestClock {
+        time: Rc<Cell<u64>>,
+    }
+
+    impl TestClock {
+        fn new(initial_time: u64) -> Self {
+            Self {
+                time: Rc::new(Cell::new(initial_time)),
+            }
+        }
+
+        fn advance(&self, millis: u64) {
+            self.time.set(self.time.get() + millis);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> u64 {
+            self.time.get()
+        }
+    }
+
+    // Returns a fixed number of invocations, each one completing immediately, followed
+    // by a shutdown
+    struct TestNextEvents {
+        clock: TestClock,
+        bus_tx: BoundedSender<JsonLambdaTelemetry>,
+        invocations: usize,
+        interval_millis: u64,
+        // Whether the TelemetryAPI is simulated, sending runtimeDone for each invocation
+        runtime_done: bool,
+    }
+
+    impl NextEventSource for TestNextEvents {
+        async fn next_event(&mut self) -> Result<NextEvent, BoxError> {
+            if self.invocations == 0 {
+                return Ok(serde_json::from_str(
+                    r#"{"eventType": "SHUTDOWN", "shutdownReason": "spindown", "deadlineMs": 0}"#,
+                )?);
+            }
+            self.invocations -= 1;
+            self.clock.advance(self.interval_millis);
+
+            if self.runtime_done {
+                let done: JsonLambdaTelemetry = serde_json::from_str(
+                    r#"{
+    "time": "2022-10-12T00:00:00.000Z",
+    "type": "platform.runtimeDone",
+    "record": {"requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa", "status": "success"}
+}"#,
+                )?;
+                self.bus_tx
+                    .send(done)
+                    .await
+                    .map_err(|e| format!("failed to send: {}", e))?;
+            }
+
+            Ok(serde_json::from_str(
+                format!(
+                    r#"{{
+    "eventType": "INVOKE",
+    "deadlineMs": {},
+    "requestId": "6d68ca91-49c9-448d-89b8-7ca3e6dc66aa",
+    "invokedFunctionArn": "arn:aws:lambda:us-east-1:123456789012:function:ExtensionTest",
+    "tracing": {{"type": "X-Amzn-Trace-Id", "value": "Root=1-5f35ae12-0c0fec141ab77a00bc047aa2"}}
+}}"#,
+                    self.clock.now() + 3_000
+                )
+                .as_str(),
+            )?)
+        }
+    }
+
+    // Records the clock time of every flush
+    struct TestFlusher {
+        clock: TestClock,
+        flushes: Vec<u64>,
+    }
+
+    impl Flusher for TestFlusher {
+        async fn flush(&mut self, _deadline: Option<Instant>) -> bool {
+            self.flushes.push(self.clock.now());
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_transitions_to_periodic_flush() {
+        let clock = TestClock::new(1_000);
+        let (bus_tx, mut bus_rx) = bounded(10);
+
+        let mut next_events = TestNextEvents {
+            clock: clock.clone(),
+            bus_tx,
+            invocations: 50,
+            interval_millis: 1_000,
+            runtime_done: true,
+        };
+        let mut flusher = TestFlusher {
+            clock: clock.clone(),
+            flushes: vec![],
+        };
+
+        let mut tapi_join_set = JoinSet::new();
+        tapi_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+        let mut agent_join_set = JoinSet::new();
+        agent_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+
+        let status = ExtensionStatus::default();
+        let reason = run_lifecycle(
+            clock.clone(),
+            ExtensionMode::Full,
+            None,
+            Duration::ZERO,
+            &status,
+            &mut next_events,
+            &mut flusher,
+            &mut bus_rx,
+            &mut tapi_join_set,
+            &mut agent_join_set,
+        )
+        .await
+        .unwrap();
+        assert_eq!("spindown", reason);
+
+        // The first 19 invocations warm up the invocation rate and flush after every call
+        let mut expected: Vec<u64> = (0..19).map(|i| 2_000 + i * 1_000).collect();
+        // Invocations are fast, so we switch to periodic mode and flush once the periodic
+        // flush interval has passed since the last after-call flush
+        expected.push(41_000);
+
+        assert_eq!(expected, flusher.flushes);
+        assert_eq!(0, next_events.invocations);
+
+        let snapshot = status.snapshot();
+        assert_eq!(Some("periodic"), snapshot.flush_mode);
+        assert!(snapshot.invocation_interval_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_flush_every_n() {
+        let opt =
+            Arguments::try_parse_from(["rotel-lambda-extension", "--flush-every-n", "3"]).unwrap();
+        assert_eq!(Some(3), opt.flush_every_n);
+        assert!(
+            Arguments::try_parse_from(["rotel-lambda-extension", "--flush-every-n", "0"]).is_err()
+        );
+
+        let clock = TestClock::new(1_000);
+        let (bus_tx, mut bus_rx) = bounded(10);
+        let mut next_events = TestNextEvents {
+            clock: clock.clone(),
+            bus_tx,
+            invocations: 9,
+            interval_millis: 1_000,
+            runtime_done: true,
+        };
+        let mut flusher = TestFlusher {
+            clock: clock.clone(),
+            flushes: vec![],
+        };
+
+        let mut tapi_join_set = JoinSet::new();
+        tapi_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+        let mut agent_join_set = JoinSet::new();
+        agent_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+
+        let status = ExtensionStatus::default();
+        let reason = run_lifecycle(
+            clock.clone(),
+            ExtensionMode::Full,
+            opt.flush_every_n,
+            Duration::ZERO,
+            &status,
+            &mut next_events,
+            &mut flusher,
+            &mut bus_rx,
+            &mut tapi_join_set,
+            &mut agent_join_set,
+        )
+        .await
+        .unwrap();
+        assert_eq!("spindown", reason);
+
+        // Invocation k starts at 1_000 + k * 1_000, only the 3rd, 6th and 9th are flushed
+        assert_eq!(vec![4_000, 7_000, 10_000], flusher.flushes);
+        assert_eq!(Some("every-n"), status.snapshot().flush_mode);
+    }
+
+    #[tokio::test]
+    async fn test_force_flush_min_interval() {
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension"]).unwrap();
+        assert_eq!(Duration::ZERO, opt.min_flush_interval_ms);
+
+        let clock = TestClock::new(1_000);
+        let flush_control =
+            FlushControl::new(clock.clone()).with_min_flush_interval(Duration::from_millis(500));
+        let mut flusher = TestFlusher {
+            clock: clock.clone(),
+            flushes: vec![],
+        };
+        let mut default_flush = tokio::time::interval(Duration::from_secs(60));
+
+        force_flush(&mut flusher, &flush_control, &mut default_flush, None).await;
+
+        // Requested again within the minimum interval, so skipped
+        clock.advance(200);
+        force_flush(&mut flusher, &flush_control, &mut default_flush, None).await;
+
+        clock.advance(300);
+        force_flush(&mut flusher, &flush_control, &mut default_flush, None).await;
+
+        assert_eq!(vec![1_000, 1_500], flusher.flushes);
+    }
+
+    #[tokio::test]
+    async fn test_receiver_only_mode() {
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension"]).unwrap();
+        assert_eq!(ExtensionMode::Full, opt.mode);
+        assert!(opt.mode.subscribes_to_telemetry());
+
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension", "--mode", "receiver-only"])
+            .unwrap();
+        assert_eq!(ExtensionMode::ReceiverOnly, opt.mode);
+        // No TelemetryAPI subscription, so no logs channel or logs flush stage
+        assert!(!opt.mode.subscribes_to_telemetry());
+
+        // Invocations complete without a runtimeDone from the TelemetryAPI, flushing
+        // before each next event
+        let clock = TestClock::new(1_000);
+        let (bus_tx, mut bus_rx) = bounded(10);
+        let mut next_events = TestNextEvents {
+            clock: clock.clone(),
+            bus_tx,
+            invocations: 3,
+            interval_millis: 1_000,
+            runtime_done: false,
+        };
+        let mut flusher = TestFlusher {
+            clock: clock.clone(),
+            flushes: vec![],
+        };
+
+        let mut tapi_join_set = JoinSet::new();
+        tapi_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+        let mut agent_join_set = JoinSet::new();
+        agent_join_set.spawn(futures::future::pending::<Result<(), BoxError>>());
+
+        let reason = timeout(
+            Duration::from_secs(5),
+            run_lifecycle(
+                clock.clone(),
+                ExtensionMode::ReceiverOnly,
+                None,
+                Duration::ZERO,
+                &ExtensionStatus::default(),
+                &mut next_events,
+                &mut flusher,
+                &mut bus_rx,
+                &mut tapi_join_set,
+                &mut agent_join_set,
+            ),
+        )
+        .await
+        .expect("lifecycle waited for runtimeDone")
+        .unwrap();
+        assert_eq!("spindown", reason);
+        assert_eq!(vec![2_000, 3_000, 4_000], flusher.flushes);
+    }
+
     fn write_env_file(envs: Vec<&str>) -> NamedTempFile {
         let mut tf = NamedTempFile::new().unwrap();
 
@@ -614,16 +2572,210 @@ mod test {
 
         tf
     }
-}
 
-#[derive(Clone)]
-struct SystemClock;
+    #[test]
+    fn test_exit_codes() {
+        fn startup(err: ExtensionError) -> Result<(), BoxError> {
+            Err(err)?;
+            Ok(())
+        }
 
-impl Clock for SystemClock {
-    fn now(&self) -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64
+        let err = startup(ExtensionError::Config("invalid secret".into())).unwrap_err();
+        assert_eq!(EXIT_CONFIG_ERROR, exit_code(&err));
+        assert_eq!("invalid secret", err.to_string());
+
+        let err = startup(ExtensionError::Register("403 Forbidden".into())).unwrap_err();
+        assert_eq!(EXIT_REGISTER_ERROR, exit_code(&err));
+
+        let err: BoxError = "agent exited".into();
+        assert_eq!(EXIT_RUNTIME_ERROR, exit_code(&err));
+
+        let err = secrets_error("Unknown secret ARN service name: s3".into());
+        assert_eq!(EXIT_CONFIG_ERROR, exit_code(&err));
+        let err = secrets_error(Box::new(LookupError(
+            "Unable to resolve ARNs from secrets manager".to_string(),
+        )));
+        assert_eq!(EXIT_RUNTIME_ERROR, exit_code(&err));
+    }
+
+    #[tokio::test]
+    async fn test_runtime_connect_timeout() {
+        use tower::Service;
+
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension"]).unwrap();
+        assert_eq!(Duration::from_secs(1), opt.runtime_connect_timeout_ms);
+
+        // Once the backlog of a listener that never accepts is full, further connections
+        // wait on the handshake, so the connector must give up after its timeout rather than
+        // waiting on the OS
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = vec![];
+        while let Ok(stream) = timeout(
+            Duration::from_millis(100),
+            tokio::net::TcpStream::connect(addr),
+        )
+        .await
+        {
+            queued.push(stream.unwrap());
+            assert!(queued.len() < 64, "listen backlog was never filled");
+        }
+
+        let mut connector = runtime_connector(Duration::from_millis(50));
+        let start = Instant::now();
+        let res = timeout(
+            Duration::from_secs(5),
+            connector.call(format!("http://{}", addr).parse().unwrap()),
+        )
+        .await
+        .expect("connect was not bounded by the connect timeout");
+
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    // Held by every test that changes the process environment. run_extension scans it for
+    // secret references and clap reads its defaults from it, so the extension tests must not
+    // overlap with a test that sets an unresolvable reference or points AWS_LAMBDA_RUNTIME_API
+    // at another mock.
+    static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    // Drives run_extension against the mock Runtime API through a cold start, one
+    // invocation and shutdown
+    fn run_with_mock_runtime_api(
+        extra_args: &[&str],
+        setup: impl FnOnce(&MockRuntimeApi),
+    ) -> (Result<(), BoxError>, Vec<MockCall>) {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mock = rt.block_on(MockRuntimeApi::start(vec![
+            MockEvent::Invoke("6d68ca91-49c9-448d-89b8-7ca3e6dc66aa".to_string()),
+            MockEvent::Shutdown,
+        ]));
+        setup(&mock);
+        unsafe { env::set_var("AWS_LAMBDA_RUNTIME_API", mock.addr().to_string()) }
+
+        // The receivers need distinct addresses, as the agent looks its listeners up by them
+        let free_addr = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string()
+        };
+        let mut args = vec![
+            "rotel-lambda-extension".to_string(),
+            "--telemetry-endpoint=127.0.0.1:0".to_string(),
+            format!("--otlp-grpc-endpoint={}", free_addr()),
+            format!("--otlp-http-endpoint={}", free_addr()),
+            "--exporter=otlp".to_string(),
+            "--otlp-exporter-protocol=http".to_string(),
+            format!("--otlp-exporter-endpoint=http://{}", mock.addr()),
+        ];
+        args.extend(extra_args.iter().map(|a| a.to_string()));
+        let opt = Arguments::try_parse_from(args).unwrap();
+
+        let agent = &opt.agent_args;
+        let (port_map, telemetry_listener) = bind_listeners(
+            &[
+                agent.otlp_receiver.otlp_grpc_endpoint,
+                agent.otlp_receiver.otlp_http_endpoint,
+            ],
+            Some(opt.telemetry_endpoint),
+        )
+        .unwrap();
+
+        let opts = ExtensionOptions::from_args(&opt);
+        let res = run_extension(
+            Instant::now(),
+            opt.agent_args,
+            port_map,
+            telemetry_listener,
+            opts,
+            &opt.environment,
+        );
+
+        unsafe { env::remove_var("AWS_LAMBDA_RUNTIME_API") }
+        (res, mock.calls())
+    }
+
+    #[test]
+    fn test_extension_lifecycle() {
+        let (res, calls) = run_with_mock_runtime_api(&[], |_| {});
+        res.unwrap();
+
+        assert_eq!(
+            vec![
+                MockCall::Register,
+                MockCall::Subscribe,
+                MockCall::Next,
+                MockCall::Telemetry
+            ],
+            calls[..4]
+        );
+
+        // The function log is exported by the flush that follows runtimeDone, before the
+        // next event is requested
+        let logs_export = calls
+            .iter()
+            .position(|c| *c == MockCall::Export("/v1/logs".to_string()))
+            .expect("logs were not exported");
+        let shutdown_next = calls.iter().rposition(|c| *c == MockCall::Next).unwrap();
+        assert!(logs_export < shutdown_next, "{:?}", calls);
+        assert_eq!(2, calls.iter().filter(|c| **c == MockCall::Next).count());
+    }
+
+    #[test]
+    fn test_extension_lifecycle_sending_queue_size() {
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension", "--sending-queue-size=100"])
+            .unwrap();
+        assert_eq!(100, ExtensionOptions::from_args(&opt).sending_queue_size);
+        let opt = Arguments::try_parse_from(["rotel-lambda-extension"]).unwrap();
+        assert_eq!(
+            SENDING_QUEUE_SIZE,
+            ExtensionOptions::from_args(&opt).sending_queue_size
+        );
+        assert!(
+            Arguments::try_parse_from(["rotel-lambda-extension", "--sending-queue-size=0"])
+                .is_err()
+        );
+
+        // With a larger queue, logs are still exported by the flush that follows runtimeDone,
+        // before the next event is requested
+        let (res, calls) = run_with_mock_runtime_api(&["--sending-queue-size=100"], |_| {});
+        res.unwrap();
+
+        let logs_export = calls
+            .iter()
+            .position(|c| *c == MockCall::Export("/v1/logs".to_string()))
+            .expect("logs were not exported");
+        let shutdown_next = calls.iter().rposition(|c| *c == MockCall::Next).unwrap();
+        assert!(logs_export < shutdown_next, "{:?}", calls);
+    }
+
+    #[test]
+    fn test_extension_subscribe_failure() {
+        // By default a failed subscription stops the extension
+        let (res, calls) = run_with_mock_runtime_api(&[], |mock| mock.fail_subscribe());
+        assert_eq!(EXIT_REGISTER_ERROR, exit_code(&res.unwrap_err()));
+        assert_eq!(vec![MockCall::Register, MockCall::Subscribe], calls);
+
+        // When optional, the agent keeps running in receiver-only mode through the
+        // invocation and shutdown
+        let (res, calls) = run_with_mock_runtime_api(&["--telemetry-subscribe-optional"], |mock| {
+            mock.fail_subscribe()
+        });
+        res.unwrap();
+        assert_eq!(
+            vec![
+                MockCall::Register,
+                MockCall::Subscribe,
+                MockCall::Next,
+                MockCall::Next
+            ],
+            calls
+        );
     }
 }
@@ -0,0 +1,214 @@
+use crate::secrets::client::AwsClient;
+use crate::secrets::error::Error;
+use crate::secrets::{APPCONFIG_DATA_SERVICE, APPCONFIG_SIGNING_SERVICE};
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{HeaderValue, Method, Uri};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use rotel::aws_api::auth::{AwsRequestSigner, SystemClock};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+
+pub const APPCONFIG_PREFIX: &str = "appconfig://";
+
+// Unreserved characters are left as-is, matching the SigV4 canonical query encoding
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// A configuration profile referenced as `appconfig://<application>/<environment>/<profile>`.
+/// Each part may be the name or the id of the AppConfig resource.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AppConfigRef {
+    pub application: String,
+    pub environment: String,
+    pub profile: String,
+}
+
+impl AppConfigRef {
+    pub fn parse(reference: &str) -> Result<Self, String> {
+        let path = reference
+            .strip_prefix(APPCONFIG_PREFIX)
+            .ok_or_else(|| format!("AppConfig reference must start with {}", APPCONFIG_PREFIX))?;
+
+        let parts: Vec<&str> = path.split('/').collect();
+        match parts.as_slice() {
+            [application, environment, profile] if parts.iter().all(|p| !p.is_empty()) => {
+                Ok(Self {
+                    application: application.to_string(),
+                    environment: environment.to_string(),
+                    profile: profile.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "invalid AppConfig reference '{}', expected {}<application>/<environment>/<profile>",
+                reference, APPCONFIG_PREFIX
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AppConfigRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}/{}/{}",
+            APPCONFIG_PREFIX, self.application, self.environment, self.profile
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StartConfigurationSessionResponse {
+    #[serde(rename = "InitialConfigurationToken")]
+    pub initial_configuration_token: String,
+}
+
+pub struct AppConfig<'a> {
+    client: &'a AwsClient,
+}
+
+impl<'a> AppConfig<'a> {
+    pub(crate) fn new(client: &'a AwsClient) -> Self {
+        Self { client }
+    }
+
+    /// Fetch the deployed content of a configuration profile. A session is started for the
+    /// profile and its initial token used to retrieve the latest configuration once, the
+    /// session is not polled afterwards.
+    pub async fn get_configuration(
+        &self,
+        reference: &AppConfigRef,
+        region: &str,
+    ) -> Result<String, Error> {
+        let endpoint = self.client.region_endpoint(APPCONFIG_DATA_SERVICE, region);
+
+        let token = self
+            .start_configuration_session(&endpoint, reference, region)
+            .await?;
+
+        let uri = format!(
+            "{}/configuration?configuration_token={}",
+            endpoint,
+            utf8_percent_encode(&token, QUERY_ENCODE_SET)
+        )
+        .parse::<Uri>()?;
+
+        let signer = AwsRequestSigner::new(APPCONFIG_SIGNING_SERVICE, region, SystemClock);
+        let signed_request = signer.sign(
+            uri,
+            Method::GET,
+            self.client.signed_headers(),
+            Bytes::new(),
+            &self.client.signing_creds().await?,
+        )?;
+
+        let response = self.client.perform(signed_request).await?;
+
+        String::from_utf8(response.to_vec())
+            .map_err(|_| Error::InvalidSecrets(vec![reference.to_string()]))
+    }
+
+    async fn start_configuration_session(
+        &self,
+        endpoint: &str,
+        reference: &AppConfigRef,
+        region: &str,
+    ) -> Result<String, Error> {
+        let uri = format!("{}/configurationsessions", endpoint).parse::<Uri>()?;
+
+        let payload = json!({
+            "ApplicationIdentifier": reference.application,
+            "EnvironmentIdentifier": reference.environment,
+            "ConfigurationProfileIdentifier": reference.profile,
+        });
+
+        let payload_bytes = Bytes::from(serde_json::to_vec(&payload)?);
+
+        let mut hdrs = self.client.signed_headers();
+        hdrs.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        let signer = AwsRequestSigner::new(APPCONFIG_SIGNING_SERVICE, region, SystemClock);
+        let creds = self.client.signing_creds().await?;
+        let signed_request = signer.sign(uri, Method::POST, hdrs, payload_bytes, &creds)?;
+
+        let response = self.client.perform(signed_request).await?;
+
+        let session: StartConfigurationSessionResponse = serde_json::from_slice(response.as_ref())?;
+        Ok(session.initial_configuration_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::client::stub_client;
+
+    const TEST_REF: &str = "appconfig://my-app/prod/flags";
+
+    #[test]
+    fn test_parse_ref() {
+        let r = AppConfigRef::parse(TEST_REF).unwrap();
+        assert_eq!("my-app", r.application);
+        assert_eq!("prod", r.environment);
+        assert_eq!("flags", r.profile);
+        assert_eq!(TEST_REF, r.to_string());
+
+        assert!(AppConfigRef::parse("appconfig://my-app/prod").is_err());
+        assert!(AppConfigRef::parse("appconfig://my-app//flags").is_err());
+        assert!(AppConfigRef::parse("appconfig://my-app/prod/flags/extra").is_err());
+        assert!(AppConfigRef::parse("secret://my-app/prod/flags").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_configuration_session_flow() {
+        let session = json!({"InitialConfigurationToken": "token/with+chars="}).to_string();
+        let (client, stub) = stub_client(vec![(201, &session), (200, r#"{"enabled":true}"#)]);
+
+        let r = AppConfigRef::parse(TEST_REF).unwrap();
+        let config = client
+            .appconfig()
+            .get_configuration(&r, "us-east-1")
+            .await
+            .unwrap();
+        assert_eq!(r#"{"enabled":true}"#, config);
+
+        // The session is started for the profile, then its token is exchanged for the content
+        assert_eq!(
+            vec![
+                "https://appconfigdata.us-east-1.amazonaws.com/configurationsessions".to_string(),
+                "https://appconfigdata.us-east-1.amazonaws.com/configuration?configuration_token=token%2Fwith%2Bchars%3D".to_string(),
+            ],
+            *stub.uris.lock().unwrap()
+        );
+
+        let requests = stub.requests.lock().unwrap();
+        let req: serde_json::Value = serde_json::from_slice(&requests[0]).unwrap();
+        assert_eq!(
+            json!({
+                "ApplicationIdentifier": "my-app",
+                "EnvironmentIdentifier": "prod",
+                "ConfigurationProfileIdentifier": "flags",
+            }),
+            req
+        );
+        assert!(requests[1].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_configuration_session_error() {
+        let body = r#"{"Message":"Application not found"}"#;
+        let (client, stub) = stub_client(vec![(404, body)]);
+
+        let r = AppConfigRef::parse(TEST_REF).unwrap();
+        match client.appconfig().get_configuration(&r, "us-east-1").await {
+            Err(Error::AwsError { code, .. }) => assert_eq!("404", code),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // No configuration is requested without a session
+        assert_eq!(1, stub.requests.lock().unwrap().len());
+    }
+}
@@ -1,32 +1,214 @@
+use crate::secrets::appconfig::AppConfig;
 use crate::secrets::error::Error;
 use crate::secrets::paramstore::ParameterStore;
 use crate::secrets::secretsmanager::SecretsManager;
-use crate::util::http::response_string;
+use crate::secrets::sigv4_debug::dump_signing;
+use crate::secrets::sts::{AssumedCredentials, Sts};
+use crate::util::http::{DEFAULT_USER_AGENT, response_string};
+use crate::util::retry::{RetryPolicy, retry};
 use bytes::Bytes;
-use http::Request;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use http::header::USER_AGENT;
+use http::{HeaderMap, HeaderValue, Request};
 use http_body_util::{BodyExt, Full};
 use hyper_rustls::ConfigBuilderExt;
 use hyper_rustls::HttpsConnector;
 use hyper_util::client::legacy::Client as HyperClient;
-use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::connect::{HttpConnector, HttpInfo};
 use hyper_util::rt::{TokioExecutor, TokioTimer};
+use rotel::aws_api::arn::AwsArn;
 use rotel::aws_api::creds::AwsCreds;
-use rustls::ClientConfig;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use rustls::{ClientConfig, RootCertStore};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tower::BoxError;
+use tracing::{debug, trace};
+
+const AWS_MAX_ATTEMPTS: u32 = 3;
+
+/// Configuration for the AWS client
+#[derive(Clone)]
+pub struct AwsConfig {
+    pub creds: AwsCreds,
+    /// Use the dualstack (IPv4 and IPv6) service endpoints
+    pub use_dualstack: bool,
+    /// PEM file of trusted root certificates, replaces the native roots when set
+    pub ca_bundle: Option<PathBuf>,
+    /// Decrypt SecureString parameters, roles without kms:Decrypt can disable this to
+    /// read plain String parameters
+    pub ssm_with_decryption: bool,
+    /// Log the SigV4 canonical request and string-to-sign of each request at trace level
+    pub debug_sigv4: bool,
+    /// User-Agent of each request, included in the signed headers
+    pub user_agent: HeaderValue,
+    /// Role to assume with `creds` before looking up secrets, for secrets in another account
+    pub assume_role_arn: Option<String>,
+}
+
+impl AwsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            creds: AwsCreds::from_env(),
+            use_dualstack: env_flag("ROTEL_AWS_USE_DUALSTACK"),
+            ca_bundle: std::env::var_os("ROTEL_AWS_CA_BUNDLE").map(PathBuf::from),
+            ssm_with_decryption: std::env::var("ROTEL_SSM_WITH_DECRYPTION").is_err()
+                || env_flag("ROTEL_SSM_WITH_DECRYPTION"),
+            debug_sigv4: env_flag("ROTEL_DEBUG_SIGV4"),
+            user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+            assume_role_arn: std::env::var("ROTEL_SECRETS_ASSUME_ROLE_ARN")
+                .ok()
+                .filter(|arn| !arn.is_empty()),
+        }
+    }
+}
+
+/// Counts of pooled connections used by the client. A connection is identified by its
+/// local socket address, so a response over a previously seen address is counted as reused.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub new_connections: usize,
+    pub reused_connections: usize,
+}
+
+#[derive(Default)]
+struct ConnectionTracker {
+    seen: HashSet<SocketAddr>,
+    stats: ConnectionStats,
+}
+
+/// Sends a single signed request and returns the successful response body. Non-success
+/// responses are returned as `Error::AwsError` with the status code and response body.
+pub trait Perform: Send + Sync {
+    fn perform(&self, req: Request<Full<Bytes>>) -> BoxFuture<'_, Result<Bytes, Error>>;
+}
+
+/// Transport backed by a pooled hyper HTTPS client
+struct HyperTransport {
+    client: HyperClient<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    connections: Arc<Mutex<ConnectionTracker>>,
+}
+
+impl Perform for HyperTransport {
+    fn perform(&self, req: Request<Full<Bytes>>) -> BoxFuture<'_, Result<Bytes, Error>> {
+        Box::pin(async move {
+            let resp = self.client.request(req).await?;
+
+            if let Some(info) = resp.extensions().get::<HttpInfo>() {
+                self.record_connection(info.local_addr());
+            }
+
+            // Handle AWS errors
+            let (parts, body) = resp.into_parts();
+            if !parts.status.is_success() {
+                let error_body = response_string(body).await?;
+
+                return Err(Error::AwsError {
+                    code: parts.status.as_str().to_string(),
+                    message: error_body,
+                });
+            }
+
+            // Parse success response
+            Ok(body.collect().await?.to_bytes())
+        })
+    }
+}
+
+impl HyperTransport {
+    fn record_connection(&self, local_addr: SocketAddr) {
+        let mut g = self.connections.lock().unwrap();
+        if g.seen.insert(local_addr) {
+            g.stats.new_connections += 1;
+        } else {
+            g.stats.reused_connections += 1;
+        }
+    }
+}
 
 /// Main client for AWS services
 pub struct AwsClient {
     pub(crate) creds: AwsCreds,
-    client: HyperClient<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    use_dualstack: bool,
+    pub(crate) ssm_with_decryption: bool,
+    debug_sigv4: bool,
+    user_agent: HeaderValue,
+    transport: Box<dyn Perform>,
+    connections: Arc<Mutex<ConnectionTracker>>,
+    assume_role_arn: Option<String>,
+    // Credentials of the assumed role, reused until they are close to expiring
+    pub(crate) assumed: Mutex<Option<AssumedCredentials>>,
 }
 
 impl AwsClient {
     /// Create a new AWS client
     pub fn new(creds: AwsCreds) -> Result<Self, BoxError> {
-        let client = build_hyper_client()?;
+        Self::from_config(AwsConfig {
+            creds,
+            use_dualstack: false,
+            ca_bundle: None,
+            ssm_with_decryption: true,
+            debug_sigv4: false,
+            user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+            assume_role_arn: None,
+        })
+    }
+
+    /// Create a new AWS client from the given config
+    pub fn from_config(config: AwsConfig) -> Result<Self, BoxError> {
+        let client = build_hyper_client(config.ca_bundle.as_deref())?;
+        let connections = Arc::new(Mutex::new(ConnectionTracker::default()));
+        let transport = HyperTransport {
+            client,
+            connections: connections.clone(),
+        };
+
+        Ok(Self {
+            creds: config.creds,
+            use_dualstack: config.use_dualstack,
+            ssm_with_decryption: config.ssm_with_decryption,
+            debug_sigv4: config.debug_sigv4,
+            user_agent: config.user_agent,
+            transport: Box::new(transport),
+            connections,
+            assume_role_arn: config.assume_role_arn,
+            assumed: Mutex::new(None),
+        })
+    }
+
+    /// Create a client that sends requests through the given transport instead of a real
+    /// HTTPS client, primarily so tests can return canned responses. Connection stats are
+    /// not tracked for custom transports.
+    pub fn with_transport(creds: AwsCreds, transport: Box<dyn Perform>) -> Self {
+        Self {
+            creds,
+            use_dualstack: false,
+            ssm_with_decryption: true,
+            debug_sigv4: false,
+            user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+            transport,
+            connections: Arc::new(Mutex::new(ConnectionTracker::default())),
+            assume_role_arn: None,
+            assumed: Mutex::new(None),
+        }
+    }
+
+    /// Set whether Parameter Store lookups decrypt SecureString parameters
+    pub fn with_ssm_decryption(mut self, ssm_with_decryption: bool) -> Self {
+        self.ssm_with_decryption = ssm_with_decryption;
+        self
+    }
 
-        Ok(Self { client, creds })
+    /// Assume the given role with the client's credentials and sign secret lookups with the
+    /// temporary credentials it returns
+    pub fn with_assume_role(mut self, role_arn: impl Into<String>) -> Self {
+        self.assume_role_arn = Some(role_arn.into());
+        self
     }
 
     /// Get an instance of the SecretsManager service
@@ -39,30 +221,176 @@ impl AwsClient {
         ParameterStore::new(self)
     }
 
-    pub async fn perform(&self, req: Request<Full<Bytes>>) -> Result<Bytes, Error> {
-        let resp = self.client.request(req).await?;
+    /// Get an instance of the STS service
+    pub fn sts(&self) -> Sts<'_> {
+        Sts::new(self)
+    }
+
+    /// Get an instance of the AppConfig data service
+    pub fn appconfig(&self) -> AppConfig<'_> {
+        AppConfig::new(self)
+    }
+
+    /// Credentials to sign secret lookups with. When a role is configured these are the
+    /// assumed role's temporary credentials, which are cached and only refreshed from STS
+    /// once they are close to expiring.
+    pub(crate) async fn signing_creds(&self) -> Result<AwsCreds, Error> {
+        let Some(role_arn) = &self.assume_role_arn else {
+            return Ok(self.creds.clone());
+        };
+
+        if let Some(assumed) = self.assumed.lock().unwrap().as_ref() {
+            if !assumed.is_expiring(Utc::now()) {
+                return Ok(assumed.creds.clone());
+            }
+        }
+
+        // STS is called in the function's region, the role ARN is global
+        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let assumed = self
+            .sts()
+            .assume_role(role_arn, &region)
+            .await
+            .map_err(|e| Error::AssumeRole(Box::new(e)))?;
+        debug!(
+            role_arn,
+            expiration = %assumed.expiration,
+            "Assumed role for secrets lookups"
+        );
+
+        let creds = assumed.creds.clone();
+        *self.assumed.lock().unwrap() = Some(assumed);
+        Ok(creds)
+    }
+
+    /// Headers to sign with every request, before any service specific headers are added
+    pub(crate) fn signed_headers(&self) -> HeaderMap {
+        let mut hdrs = HeaderMap::new();
+        hdrs.insert(USER_AGENT, self.user_agent.clone());
+        hdrs
+    }
+
+    /// Endpoint for the service and region of the ARN. The signing service name and
+    /// region are not affected by the endpoint choice.
+    pub(crate) fn endpoint(&self, arn: &AwsArn) -> String {
+        service_endpoint(arn, self.use_dualstack)
+    }
 
-        // Handle AWS errors
-        let (parts, body) = resp.into_parts();
-        if !parts.status.is_success() {
-            let error_body = response_string(body).await?;
+    /// Endpoint for a service that is not addressed by ARN, the partition is taken from the
+    /// region name
+    pub(crate) fn region_endpoint(&self, service: &str, region: &str) -> String {
+        let partition = if region.starts_with("cn-") {
+            "aws-cn"
+        } else if region.starts_with("us-isob-") {
+            "aws-iso-b"
+        } else if region.starts_with("us-iso-") {
+            "aws-iso"
+        } else {
+            "aws"
+        };
+        partition_endpoint(partition, service, region, self.use_dualstack)
+    }
+
+    /// Connection pool usage across all requests performed so far
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.connections.lock().unwrap().stats.clone()
+    }
 
-            return Err(Error::AwsError {
-                code: parts.status.as_str().to_string(),
-                message: error_body,
-            });
+    /// Send a signed request, retrying throttling, server and connection errors
+    pub async fn perform(&self, req: Request<Full<Bytes>>) -> Result<Bytes, Error> {
+        let (parts, body) = req.into_parts();
+        if self.debug_sigv4 {
+            let payload = body.clone().collect().await.map(|b| b.to_bytes());
+            if let Some(dump) = dump_signing(&parts, &payload.unwrap_or_default()) {
+                trace!(uri = %parts.uri, "SigV4 signing details\n{}", dump);
+            }
         }
 
-        // Parse success response
-        Ok(body.collect().await?.to_bytes())
+        retry(&aws_retry_policy(), || {
+            let mut req = Request::new(body.clone());
+            *req.method_mut() = parts.method.clone();
+            *req.uri_mut() = parts.uri.clone();
+            *req.version_mut() = parts.version;
+            *req.headers_mut() = parts.headers.clone();
+            self.transport.perform(req)
+        })
+        .await
     }
 }
 
-fn build_hyper_client() -> Result<HyperClient<HttpsConnector<HttpConnector>, Full<Bytes>>, BoxError>
-{
-    let tls_config = ClientConfig::builder()
-        .with_native_roots()?
-        .with_no_client_auth();
+// The ARN partition decides the endpoint domain. The region prefix is only consulted for the
+// generic aws partition, so a mismatched region can not move a request out of its partition.
+fn service_endpoint(arn: &AwsArn, use_dualstack: bool) -> String {
+    let partition = arn.partition().to_string();
+    let partition = match partition.as_str() {
+        "aws" if arn.region().starts_with("cn-") => "aws-cn",
+        p => p,
+    };
+
+    partition_endpoint(partition, arn.service(), arn.region(), use_dualstack)
+}
+
+fn partition_endpoint(partition: &str, service: &str, region: &str, use_dualstack: bool) -> String {
+    // https://docs.aws.amazon.com/general/latest/gr/rande.html#dual-stack-endpoints
+    let domain = match (partition, use_dualstack) {
+        ("aws-cn", false) => "amazonaws.com.cn",
+        ("aws-cn", true) => "api.amazonwebservices.com.cn",
+        ("aws-iso", _) => "c2s.ic.gov",
+        ("aws-iso-b", _) => "sc2s.sgov.gov",
+        (_, false) => "amazonaws.com",
+        (_, true) => "api.aws",
+    };
+
+    format!("https://{}.{}.{}", service, region, domain)
+}
+
+fn aws_retry_policy() -> RetryPolicy<Error> {
+    RetryPolicy {
+        max_attempts: AWS_MAX_ATTEMPTS,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        jitter: 0.2,
+        retryable: is_retryable,
+    }
+}
+
+fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::HttpError(_) => true,
+        Error::AwsError { code, .. } => code == "429" || code.starts_with('5'),
+        _ => false,
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| v.eq_ignore_ascii_case("true") || v == "1")
+}
+
+fn tls_config(ca_bundle: Option<&Path>) -> Result<ClientConfig, BoxError> {
+    let builder = ClientConfig::builder();
+    let Some(path) = ca_bundle else {
+        return Ok(builder.with_native_roots()?.with_no_client_auth());
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path)
+        .map_err(|e| format!("unable to read CA bundle {}: {}", path.display(), e))?
+    {
+        let cert = cert.map_err(|e| format!("invalid certificate in {}: {}", path.display(), e))?;
+        roots.add(cert)?;
+    }
+
+    if roots.is_empty() {
+        return Err(format!("no certificates found in CA bundle {}", path.display()).into());
+    }
+
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+fn build_hyper_client(
+    ca_bundle: Option<&Path>,
+) -> Result<HyperClient<HttpsConnector<HttpConnector>, Full<Bytes>>, BoxError> {
+    let tls_config = tls_config(ca_bundle)?;
 
     let https = hyper_rustls::HttpsConnectorBuilder::new()
         .with_tls_config(tls_config)
@@ -78,3 +406,362 @@ fn build_hyper_client() -> Result<HyperClient<HttpsConnector<HttpConnector>, Ful
 
     Ok(client)
 }
+
+/// Transport that replays canned responses in order and records each request body
+#[cfg(test)]
+pub(crate) struct StubTransport {
+    responses: Mutex<std::collections::VecDeque<(u16, String)>>,
+    pub(crate) requests: Mutex<Vec<Bytes>>,
+    // X-Amz-Target of each request, naming the API action
+    pub(crate) targets: Mutex<Vec<String>>,
+    pub(crate) uris: Mutex<Vec<String>>,
+    pub(crate) headers: Mutex<Vec<HeaderMap>>,
+}
+
+#[cfg(test)]
+impl StubTransport {
+    pub(crate) fn new(responses: Vec<(u16, &str)>) -> Self {
+        Self {
+            responses: Mutex::new(
+                responses
+                    .into_iter()
+                    .map(|(status, body)| (status, body.to_string()))
+                    .collect(),
+            ),
+            requests: Mutex::new(Vec::new()),
+            targets: Mutex::new(Vec::new()),
+            uris: Mutex::new(Vec::new()),
+            headers: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Perform for Arc<StubTransport> {
+    fn perform(&self, req: Request<Full<Bytes>>) -> BoxFuture<'_, Result<Bytes, Error>> {
+        Box::pin(async move {
+            if let Some(target) = req.headers().get("X-Amz-Target") {
+                let target = target.to_str().unwrap().to_string();
+                self.targets.lock().unwrap().push(target);
+            }
+            self.uris.lock().unwrap().push(req.uri().to_string());
+            self.headers.lock().unwrap().push(req.headers().clone());
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            self.requests.lock().unwrap().push(body);
+
+            let (status, body) = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("unexpected request to stub transport");
+            if !(200..300).contains(&status) {
+                return Err(Error::AwsError {
+                    code: status.to_string(),
+                    message: body,
+                });
+            }
+            Ok(Bytes::from(body))
+        })
+    }
+}
+
+/// Client that sends requests to a stub transport replaying `responses`
+#[cfg(test)]
+pub(crate) fn stub_client(responses: Vec<(u16, &str)>) -> (AwsClient, Arc<StubTransport>) {
+    let stub = Arc::new(StubTransport::new(responses));
+    let client = AwsClient::with_transport(crate::test_util::test_creds(), Box::new(stub.clone()));
+    (client, stub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{init_crypto, test_creds};
+    use chrono::{DateTime, TimeZone, Utc};
+    use http::header::AUTHORIZATION;
+    use http::{Method, Response, StatusCode};
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use rotel::aws_api::auth::{AwsRequestSigner, Clock};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_dualstack_endpoint() {
+        let arn = "arn:aws:secretsmanager:us-east-1:123456789012:secret:my-secret-r1l7G9"
+            .parse::<AwsArn>()
+            .unwrap();
+
+        assert_eq!(
+            "https://secretsmanager.us-east-1.amazonaws.com",
+            service_endpoint(&arn, false)
+        );
+        assert_eq!(
+            "https://secretsmanager.us-east-1.api.aws",
+            service_endpoint(&arn, true)
+        );
+
+        // Signing inputs are taken from the ARN and are unchanged
+        assert_eq!("secretsmanager", arn.service().to_string());
+        assert_eq!("us-east-1", arn.region().to_string());
+
+        let arn = "arn:aws-cn:ssm:cn-north-1:123456789012:parameter/my-param"
+            .parse::<AwsArn>()
+            .unwrap();
+        assert_eq!(
+            "https://ssm.cn-north-1.api.amazonwebservices.com.cn",
+            service_endpoint(&arn, true)
+        );
+    }
+
+    #[test]
+    fn test_region_endpoint() {
+        let client =
+            AwsClient::with_transport(test_creds(), Box::new(Arc::new(StubTransport::new(vec![]))));
+
+        assert_eq!(
+            "https://appconfigdata.us-east-1.amazonaws.com",
+            client.region_endpoint("appconfigdata", "us-east-1")
+        );
+        assert_eq!(
+            "https://appconfigdata.cn-north-1.amazonaws.com.cn",
+            client.region_endpoint("appconfigdata", "cn-north-1")
+        );
+        assert_eq!(
+            "https://appconfigdata.us-iso-east-1.c2s.ic.gov",
+            client.region_endpoint("appconfigdata", "us-iso-east-1")
+        );
+    }
+
+    #[test]
+    fn test_endpoint_partition() {
+        let endpoint = |arn: &str| service_endpoint(&arn.parse::<AwsArn>().unwrap(), false);
+
+        assert_eq!(
+            "https://ssm.cn-north-1.amazonaws.com.cn",
+            endpoint("arn:aws-cn:ssm:cn-north-1:123456789012:parameter/my-param")
+        );
+        assert_eq!(
+            "https://ssm.us-gov-west-1.amazonaws.com",
+            endpoint("arn:aws-us-gov:ssm:us-gov-west-1:123456789012:parameter/my-param")
+        );
+
+        // The partition wins over a conflicting region prefix
+        assert_eq!(
+            "https://ssm.us-east-1.amazonaws.com.cn",
+            endpoint("arn:aws-cn:ssm:us-east-1:123456789012:parameter/my-param")
+        );
+        assert_eq!(
+            "https://ssm.cn-north-1.amazonaws.com",
+            endpoint("arn:aws-us-gov:ssm:cn-north-1:123456789012:parameter/my-param")
+        );
+
+        // The generic partition falls back to the region prefix
+        assert_eq!(
+            "https://ssm.cn-north-1.amazonaws.com.cn",
+            endpoint("arn:aws:ssm:cn-north-1:123456789012:parameter/my-param")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_reuse_stats() {
+        init_crypto();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let svc = service_fn(|_req| async {
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("{}"))))
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), svc)
+                        .await;
+                });
+            }
+        });
+
+        let client = AwsClient::new(AwsCreds::from_env()).unwrap();
+        for _ in 0..2 {
+            let req = Request::get(format!("http://{}/", addr))
+                .body(Full::default())
+                .unwrap();
+            client.perform(req).await.unwrap();
+        }
+
+        assert_eq!(
+            ConnectionStats {
+                new_connections: 1,
+                reused_connections: 1,
+            },
+            client.connection_stats()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_perform_retries_server_errors() {
+        init_crypto();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let calls = server_calls.clone();
+                tokio::spawn(async move {
+                    let svc = service_fn(move |_req| {
+                        let n = calls.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            let status = match n {
+                                0 => StatusCode::SERVICE_UNAVAILABLE,
+                                1 => StatusCode::OK,
+                                _ => StatusCode::BAD_REQUEST,
+                            };
+                            let mut resp = Response::new(Full::new(Bytes::from("{}")));
+                            *resp.status_mut() = status;
+                            Ok::<_, Infallible>(resp)
+                        }
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), svc)
+                        .await;
+                });
+            }
+        });
+
+        let client = AwsClient::new(AwsCreds::from_env()).unwrap();
+        let req = Request::post(format!("http://{}/", addr))
+            .body(Full::new(Bytes::from("{}")))
+            .unwrap();
+        client.perform(req).await.unwrap();
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        // Client errors are not retried
+        let req = Request::post(format!("http://{}/", addr))
+            .body(Full::new(Bytes::from("{}")))
+            .unwrap();
+        assert!(client.perform(req).await.is_err());
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    const TEST_CA_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBiDCCAS2gAwIBAgIUYiBi2NsHSxS3klkwvJZr9ym/n7UwCgYIKoZIzj0EAwIw
+GDEWMBQGA1UEAwwNcm90ZWwtdGVzdC1jYTAgFw0yNjEwMTYwMDA1MzFaGA8yMTI2
+MDkyMjAwMDUzMVowGDEWMBQGA1UEAwwNcm90ZWwtdGVzdC1jYTBZMBMGByqGSM49
+AgEGCCqGSM49AwEHA0IABIKDukCdMeBO9Lg9uyMU4u4xwgLGZo+ywaMndNoQg6mx
+N2iYoUrgKotiVzUOKj584/JnKxrTOOXHHP8I6qJzhNmjUzBRMB0GA1UdDgQWBBT6
+9YHeS6LVHajbY9hf382MPQdtDDAfBgNVHSMEGDAWgBT69YHeS6LVHajbY9hf382M
+PQdtDDAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0kAMEYCIQCZlX1nvB8X
+JwItUz2DyahI76qy5mM72ZVDeciqEHkwpAIhAKCugKJJslFs097ZoG7TAf+vN8oq
+Z59rRozIfmgz6qk5
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_ca_bundle() {
+        init_crypto();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, TEST_CA_PEM).unwrap();
+
+        assert!(tls_config(Some(&path)).is_ok());
+        assert!(
+            AwsClient::from_config(AwsConfig {
+                creds: AwsCreds::from_env(),
+                use_dualstack: false,
+                ca_bundle: Some(path),
+                ssm_with_decryption: true,
+                debug_sigv4: false,
+                user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+                assume_role_arn: None,
+            })
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_ca_bundle_invalid() {
+        init_crypto();
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.pem");
+        let err = tls_config(Some(&missing)).unwrap_err().to_string();
+        assert!(err.contains("unable to read CA bundle"), "{}", err);
+
+        let empty = dir.path().join("empty.pem");
+        std::fs::write(&empty, "not a certificate").unwrap();
+        let err = tls_config(Some(&empty)).unwrap_err().to_string();
+        assert!(err.contains("no certificates found"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_stub_transport_retries() {
+        let stub = Arc::new(StubTransport::new(vec![(500, "oops"), (200, "{}")]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+
+        let req = Request::post("https://secretsmanager.us-east-1.amazonaws.com/")
+            .body(Full::new(Bytes::from("{}")))
+            .unwrap();
+        assert_eq!(Bytes::from("{}"), client.perform(req).await.unwrap());
+        assert_eq!(2, stub.requests.lock().unwrap().len());
+        assert_eq!(ConnectionStats::default(), client.connection_stats());
+    }
+
+    #[derive(Clone)]
+    struct FixedClock(DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_signing_date_scope_at_day_boundary() {
+        // One second before midnight UTC, the credential scope date must match the
+        // request date rather than rolling over to the next day
+        let now = Utc.with_ymd_and_hms(2024, 12, 31, 23, 59, 59).unwrap();
+        let signer = AwsRequestSigner::new("secretsmanager", "us-east-1", FixedClock(now));
+
+        let signed = signer
+            .sign(
+                "https://secretsmanager.us-east-1.amazonaws.com/"
+                    .parse()
+                    .unwrap(),
+                Method::POST,
+                HeaderMap::new(),
+                Bytes::from("{}"),
+                &test_creds(),
+            )
+            .unwrap();
+
+        let amz_date = signed
+            .headers()
+            .get("x-amz-date")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!("20241231T235959Z", amz_date);
+
+        let auth = signed
+            .headers()
+            .get(AUTHORIZATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let scope_date = auth
+            .split("Credential=")
+            .nth(1)
+            .and_then(|cred| cred.split('/').nth(1))
+            .unwrap();
+        assert_eq!("20241231", scope_date);
+        assert!(amz_date.starts_with(scope_date));
+    }
+}
@@ -13,6 +13,19 @@ pub enum Error {
     InvalidSecrets(Vec<String>),
     SigningError(rotel::aws_api::error::Error),
     SerdeError(serde_json::Error),
+    AssumeRole(Box<Error>),
+}
+
+impl Error {
+    /// Whether the request was rejected for what it asked for, a secret that does not exist
+    /// or can not be read, rather than failing on the service or the way to it
+    pub fn is_rejected(&self) -> bool {
+        match self {
+            Error::InvalidSecrets(_) => true,
+            Error::AwsError { code, .. } => code.starts_with('4') && code != "429",
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -31,6 +44,7 @@ impl fmt::Display for Error {
                 write!(f, "Failed to sign request: {}", e)
             }
             Error::SerdeError(e) => write!(f, "Serialization error: {}", e),
+            Error::AssumeRole(e) => write!(f, "Failed to assume role: {}", e),
         }
     }
 }
@@ -1,10 +1,17 @@
+pub mod appconfig;
 pub mod client;
-mod error;
+pub mod error;
 mod paramstore;
 mod secretsmanager;
+mod sigv4_debug;
+pub mod sts;
 
 pub const SECRETS_MANAGER_SERVICE: &str = "secretsmanager";
 pub const PARAM_STORE_SERVICE: &str = "ssm";
+pub const STS_SERVICE: &str = "sts";
+// AppConfig data is served from its own endpoint, but signed as the appconfig service
+pub const APPCONFIG_DATA_SERVICE: &str = "appconfigdata";
+pub const APPCONFIG_SIGNING_SERVICE: &str = "appconfig";
 
 // This is the minimum of what SecretsManager and ParamStore supports for
 // batch calls. It would be surprising to have > 10 secrets.
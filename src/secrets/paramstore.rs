@@ -3,7 +3,8 @@ use crate::secrets::client::AwsClient;
 use crate::secrets::error::Error;
 use bytes::Bytes;
 use http::header::CONTENT_TYPE;
-use http::{HeaderMap, HeaderValue, Method, Uri};
+use http::{HeaderValue, Method, Uri};
+use percent_encoding::percent_decode_str;
 use rotel::aws_api::arn::AwsArn;
 use rotel::aws_api::auth::{AwsRequestSigner, SystemClock};
 use serde::Deserialize;
@@ -49,9 +50,9 @@ pub struct Parameter {
     #[serde(rename = "Name")]
     pub name: String,
 
-    // /// The unique identifier for the parameter version.
-    // #[serde(rename = "Selector")]
-    // pub selector: Option<String>,
+    /// The label or version the parameter was selected with, e.g. ":prod" or ":3".
+    #[serde(rename = "Selector")]
+    pub selector: Option<String>,
 
     // /// The parameter source.
     // #[serde(rename = "SourceResult")]
@@ -91,7 +92,7 @@ impl<'a> ParameterStore<'a> {
             }
 
             arns_by_endpoint
-                .entry(arn.get_endpoint())
+                .entry(self.client.endpoint(arn))
                 .or_insert_with(|| Vec::new())
                 .push(arn);
         }
@@ -100,14 +101,21 @@ impl<'a> ParameterStore<'a> {
         for (endpoint, arns) in &arns_by_endpoint {
             let endpoint = endpoint.parse::<Uri>()?;
 
+            // SSM names parameters by their decoded ARN, responses are mapped back to the
+            // ARN as it was requested
+            let requested: HashMap<String, String> = arns
+                .iter()
+                .map(|arn| (decoded_arn(arn), arn.to_string()))
+                .collect();
+
             let payload = json!({
-                "Names": arns.iter().map(|arn| arn.to_string()).collect::<Vec<String>>(),
-                "WithDecryption": true,
+                "Names": arns.iter().map(|arn| decoded_arn(arn)).collect::<Vec<String>>(),
+                "WithDecryption": self.client.ssm_with_decryption,
             });
 
             let payload_bytes = Bytes::from(serde_json::to_vec(&payload)?);
 
-            let mut hdrs = HeaderMap::new();
+            let mut hdrs = self.client.signed_headers();
             hdrs.insert(
                 "X-Amz-Target",
                 HeaderValue::from_static("AmazonSSM.GetParameters"),
@@ -124,7 +132,7 @@ impl<'a> ParameterStore<'a> {
                 Method::POST,
                 hdrs,
                 payload_bytes,
-                &self.client.creds,
+                &self.client.signing_creds().await?,
             )?;
 
             // Send the request
@@ -137,7 +145,7 @@ impl<'a> ParameterStore<'a> {
                     result
                         .invalid_parameters
                         .into_iter()
-                        .map(|i| i.name)
+                        .map(|i| requested.get(&i.name).cloned().unwrap_or(i.name))
                         .collect(),
                 ));
             }
@@ -150,7 +158,15 @@ impl<'a> ParameterStore<'a> {
                     ));
                 }
 
-                let arn = param.arn.clone().unwrap();
+                // A selected parameter is returned under its plain ARN, the selector is
+                // reported separately
+                let mut arn = param.arn.clone().unwrap();
+                if let Some(selector) = &param.selector {
+                    if !arn.ends_with(selector.as_str()) {
+                        arn.push_str(selector);
+                    }
+                }
+                let arn = requested.get(&arn).cloned().unwrap_or(arn);
                 res.insert(arn, param);
             }
         }
@@ -159,12 +175,304 @@ impl<'a> ParameterStore<'a> {
     }
 }
 
+// Parameter names may be percent-encoded in the ARN, e.g. parameter/my%2Fpath
+fn decoded_arn(arn: &AwsArn) -> String {
+    percent_decode_str(&arn.to_string())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// The label or version selector of a parameter ARN, e.g. `prod` for
+/// `arn:aws:ssm:us-east-1:123456789012:parameter/name:prod`
+pub fn parameter_selector(arn: &str) -> Option<&str> {
+    let (_, name) = arn.split_once(":parameter/")?;
+    name.split_once(':').map(|(_, selector)| selector)
+}
+
+/// Checks a parameter selector is either a version number or a valid label. Labels may
+/// contain letters, numbers, periods, hyphens and underscores, and can not start with a
+/// number, `aws` or `ssm`.
+pub fn validate_selector(selector: &str) -> Result<(), String> {
+    if selector.is_empty() {
+        return Err("selector is empty".to_string());
+    }
+
+    if selector.starts_with(|c: char| c.is_ascii_digit()) {
+        return match selector.parse::<u64>() {
+            Ok(v) if v > 0 => Ok(()),
+            _ => Err(format!("invalid parameter version: {}", selector)),
+        };
+    }
+
+    if selector.len() > MAX_LABEL_LEN {
+        return Err(format!("label is longer than {} characters", MAX_LABEL_LEN));
+    }
+    let lower = selector.to_ascii_lowercase();
+    if lower.starts_with("aws") || lower.starts_with("ssm") {
+        return Err(format!("label can not start with aws or ssm: {}", selector));
+    }
+    if !selector
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+    {
+        return Err(format!("invalid parameter label: {}", selector));
+    }
+
+    Ok(())
+}
+
+const MAX_LABEL_LEN: usize = 100;
+
 #[cfg(test)]
 mod tests {
     use rotel::aws_api::creds::AwsCreds;
 
     use super::*;
-    use crate::test_util::{init_crypto, parse_test_arns};
+    use crate::secrets::client::{StubTransport, stub_client};
+    use crate::test_util::{init_crypto, parse_test_arns, test_creds};
+    use std::sync::Arc;
+
+    const TEST_ARN: &str = "arn:aws:ssm:us-east-1:123456789012:parameter/test";
+
+    #[tokio::test]
+    async fn test_get_parameters_stubbed() {
+        let body = json!({
+            "InvalidParameters": [],
+            "Parameters": [{
+                "ARN": TEST_ARN,
+                "Name": "test",
+                "Type": "SecureString",
+                "Value": "hunter2",
+                "Version": 3,
+            }],
+        })
+        .to_string();
+        let (client, stub) = stub_client(vec![(200, &body)]);
+
+        let arns = vec![TEST_ARN.parse::<AwsArn>().unwrap()];
+        let res = client
+            .parameter_store()
+            .get_parameters(&arns)
+            .await
+            .unwrap();
+        let param = res.get(TEST_ARN).unwrap();
+        assert_eq!("hunter2", param.value);
+        assert_eq!(Some(3), param.version);
+
+        let req: serde_json::Value =
+            serde_json::from_slice(&stub.requests.lock().unwrap()[0]).unwrap();
+        assert_eq!(json!({"Names": [TEST_ARN], "WithDecryption": true}), req);
+    }
+
+    #[tokio::test]
+    async fn test_get_parameters_without_decryption() {
+        let body = json!({
+            "InvalidParameters": [],
+            "Parameters": [{
+                "ARN": TEST_ARN,
+                "Name": "test",
+                "Type": "String",
+                "Value": "plain",
+            }],
+        })
+        .to_string();
+        let stub = Arc::new(StubTransport::new(vec![(200, &body)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()))
+            .with_ssm_decryption(false);
+
+        let arns = vec![TEST_ARN.parse::<AwsArn>().unwrap()];
+        let res = client
+            .parameter_store()
+            .get_parameters(&arns)
+            .await
+            .unwrap();
+        assert_eq!("plain", res.get(TEST_ARN).unwrap().value);
+
+        let req: serde_json::Value =
+            serde_json::from_slice(&stub.requests.lock().unwrap()[0]).unwrap();
+        assert_eq!(json!({"Names": [TEST_ARN], "WithDecryption": false}), req);
+    }
+
+    #[tokio::test]
+    async fn test_get_parameters_encoded_name() {
+        let encoded = "arn:aws:ssm:us-east-1:123456789012:parameter/my%2Fpath";
+        let decoded = "arn:aws:ssm:us-east-1:123456789012:parameter/my/path";
+
+        // The ARN keeps its encoding through parse and display
+        let arn = encoded.parse::<AwsArn>().unwrap();
+        assert_eq!(encoded, arn.to_string());
+
+        let body = json!({
+            "InvalidParameters": [],
+            "Parameters": [{
+                "ARN": decoded,
+                "Name": "/my/path",
+                "Type": "String",
+                "Value": "nested",
+            }],
+        })
+        .to_string();
+        let (client, stub) = stub_client(vec![(200, &body)]);
+
+        let res = client
+            .parameter_store()
+            .get_parameters(&[arn.clone()])
+            .await
+            .unwrap();
+        assert_eq!("nested", res.get(encoded).unwrap().value);
+
+        let req: serde_json::Value =
+            serde_json::from_slice(&stub.requests.lock().unwrap()[0]).unwrap();
+        assert_eq!(json!([decoded]), req["Names"]);
+
+        // Invalid names are reported as requested
+        let body = json!({
+            "InvalidParameters": [{"Name": decoded}],
+            "Parameters": [],
+        })
+        .to_string();
+        let (client, _) = stub_client(vec![(200, &body)]);
+        match client.parameter_store().get_parameters(&[arn]).await {
+            Err(Error::InvalidSecrets(names)) => assert_eq!(vec![encoded.to_string()], names),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_parameters_label() {
+        let labeled = format!("{}:prod", TEST_ARN);
+        let body = json!({
+            "InvalidParameters": [],
+            "Parameters": [{
+                "ARN": TEST_ARN,
+                "Name": "test",
+                "Selector": ":prod",
+                "Type": "String",
+                "Value": "labeled",
+                "Version": 4,
+            }],
+        })
+        .to_string();
+        let (client, stub) = stub_client(vec![(200, &body)]);
+
+        let res = client
+            .parameter_store()
+            .get_parameters(&[labeled.parse::<AwsArn>().unwrap()])
+            .await
+            .unwrap();
+        assert_eq!("labeled", res.get(&labeled).unwrap().value);
+
+        let req: serde_json::Value =
+            serde_json::from_slice(&stub.requests.lock().unwrap()[0]).unwrap();
+        assert_eq!(json!([labeled]), req["Names"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_parameters_version() {
+        let pinned = format!("{}:3", TEST_ARN);
+        let body = json!({
+            "InvalidParameters": [],
+            "Parameters": [
+                {
+                    "ARN": TEST_ARN,
+                    "Name": "test",
+                    "Selector": ":3",
+                    "Type": "String",
+                    "Value": "pinned",
+                    "Version": 3,
+                },
+                {
+                    "ARN": TEST_ARN,
+                    "Name": "test",
+                    "Type": "String",
+                    "Value": "latest",
+                    "Version": 5,
+                },
+            ],
+        })
+        .to_string();
+        let (client, _) = stub_client(vec![(200, &body)]);
+
+        // The same parameter can be requested pinned and unpinned
+        let arns = vec![
+            pinned.parse::<AwsArn>().unwrap(),
+            TEST_ARN.parse::<AwsArn>().unwrap(),
+        ];
+        let res = client
+            .parameter_store()
+            .get_parameters(&arns)
+            .await
+            .unwrap();
+        assert_eq!("pinned", res.get(&pinned).unwrap().value);
+        assert_eq!(Some(3), res.get(&pinned).unwrap().version);
+        assert_eq!("latest", res.get(TEST_ARN).unwrap().value);
+    }
+
+    #[test]
+    fn test_parameter_selector() {
+        assert_eq!(None, parameter_selector(TEST_ARN));
+        assert_eq!(
+            Some("prod"),
+            parameter_selector("arn:aws:ssm:us-east-1:123456789012:parameter/a/b:prod")
+        );
+        assert_eq!(
+            Some("3"),
+            parameter_selector("arn:aws:ssm:us-east-1:123456789012:parameter/test:3")
+        );
+
+        assert!(validate_selector("prod").is_ok());
+        assert!(validate_selector("release-1.2_b").is_ok());
+        assert!(validate_selector("3").is_ok());
+        assert!(validate_selector("").is_err());
+        assert!(validate_selector("0").is_err());
+        assert!(validate_selector("3a").is_err());
+        assert!(validate_selector("aws-prod").is_err());
+        assert!(validate_selector("SSM").is_err());
+        assert!(validate_selector("prod label").is_err());
+        assert!(validate_selector(&"a".repeat(101)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_parameters_partial_errors() {
+        let missing = "arn:aws:ssm:us-east-1:123456789012:parameter/missing";
+        let body = json!({
+            "InvalidParameters": [{"Name": missing}],
+            "Parameters": [{
+                "ARN": TEST_ARN,
+                "Name": "test",
+                "Type": "String",
+                "Value": "hunter2",
+            }],
+        })
+        .to_string();
+        let (client, _) = stub_client(vec![(200, &body)]);
+
+        let arns = vec![
+            TEST_ARN.parse::<AwsArn>().unwrap(),
+            missing.parse::<AwsArn>().unwrap(),
+        ];
+        match client.parameter_store().get_parameters(&arns).await {
+            Err(Error::InvalidSecrets(names)) => assert_eq!(vec![missing.to_string()], names),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_parameters_aws_error() {
+        let body = r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#;
+        let (client, stub) = stub_client(vec![(429, body), (429, body), (429, body)]);
+
+        let arns = vec![TEST_ARN.parse::<AwsArn>().unwrap()];
+        match client.parameter_store().get_parameters(&arns).await {
+            Err(Error::AwsError { code, message }) => {
+                assert_eq!("429", code);
+                assert_eq!(body, message);
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // Throttling is retried until attempts run out
+        assert_eq!(3, stub.requests.lock().unwrap().len());
+    }
 
     #[tokio::test]
     async fn test_basic_paramstore_retrieval() {
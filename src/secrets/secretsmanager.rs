@@ -3,7 +3,7 @@ use crate::secrets::client::AwsClient;
 use crate::secrets::error::Error;
 use bytes::Bytes;
 use http::header::CONTENT_TYPE;
-use http::{HeaderMap, HeaderValue, Method, Uri};
+use http::{HeaderValue, Method, Uri};
 use rotel::aws_api::arn::AwsArn;
 use rotel::aws_api::auth::{AwsRequestSigner, SystemClock};
 use serde::Deserialize;
@@ -79,7 +79,7 @@ impl<'a> SecretsManager<'a> {
             }
 
             arns_by_endpoint
-                .entry(arn.get_endpoint())
+                .entry(self.client.endpoint(arn))
                 .or_insert_with(|| Vec::new())
                 .push(arn);
         }
@@ -94,7 +94,7 @@ impl<'a> SecretsManager<'a> {
 
             let payload_bytes = Bytes::from(serde_json::to_vec(&payload)?);
 
-            let mut hdrs = HeaderMap::new();
+            let mut hdrs = self.client.signed_headers();
             hdrs.insert(
                 "X-Amz-Target",
                 HeaderValue::from_static("secretsmanager.BatchGetSecretValue"),
@@ -111,7 +111,7 @@ impl<'a> SecretsManager<'a> {
                 Method::POST,
                 hdrs,
                 payload_bytes,
-                &self.client.creds,
+                &self.client.signing_creds().await?,
             )?;
 
             // Send the request
@@ -146,6 +146,51 @@ impl<'a> SecretsManager<'a> {
 
         Ok(res)
     }
+
+    /// Get a single secret pinned to a VersionId, which the batch API does not support
+    pub async fn get_secret_value(
+        &self,
+        secret_arn: &AwsArn,
+        version_id: &str,
+    ) -> Result<ResponseSecret, Error> {
+        if secret_arn.service() != self.service_name {
+            return Err(Error::InvalidService(secret_arn.service().clone()));
+        }
+
+        let endpoint = self.client.endpoint(secret_arn).parse::<Uri>()?;
+
+        let payload = json!({
+            "SecretId": secret_arn.to_string(),
+            "VersionId": version_id,
+        });
+
+        let payload_bytes = Bytes::from(serde_json::to_vec(&payload)?);
+
+        let mut hdrs = self.client.signed_headers();
+        hdrs.insert(
+            "X-Amz-Target",
+            HeaderValue::from_static("secretsmanager.GetSecretValue"),
+        );
+        hdrs.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-amz-json-1.1"),
+        );
+
+        // Sign the request
+        let signer = AwsRequestSigner::new(self.service_name, secret_arn.region(), SystemClock);
+        let signed_request = signer.sign(
+            endpoint,
+            Method::POST,
+            hdrs,
+            payload_bytes,
+            &self.client.signing_creds().await?,
+        )?;
+
+        // Send the request
+        let response = self.client.perform(signed_request).await?;
+
+        Ok(serde_json::from_slice(response.as_ref())?)
+    }
 }
 
 #[cfg(test)]
@@ -153,8 +198,130 @@ mod tests {
     use rotel::aws_api::creds::AwsCreds;
 
     use super::*;
+    use crate::secrets::client::stub_client;
     use crate::test_util::{init_crypto, parse_test_arns};
 
+    const TEST_ARN: &str = "arn:aws:secretsmanager:us-east-1:123456789012:secret:test-abc123";
+
+    #[tokio::test]
+    async fn test_batch_get_secret_stubbed() {
+        let body = json!({
+            "Errors": [],
+            "SecretValues": [{
+                "ARN": TEST_ARN,
+                "CreatedDate": 1.7e9,
+                "Name": "test",
+                "SecretString": "hunter2",
+                "VersionId": "v1",
+            }],
+        })
+        .to_string();
+        let (client, stub) = stub_client(vec![(200, &body)]);
+
+        let arns = vec![TEST_ARN.parse::<AwsArn>().unwrap()];
+        let res = client
+            .secrets_manager()
+            .batch_get_secret(&arns)
+            .await
+            .unwrap();
+        assert_eq!("hunter2", res.get(TEST_ARN).unwrap().secret_string);
+
+        let req: serde_json::Value =
+            serde_json::from_slice(&stub.requests.lock().unwrap()[0]).unwrap();
+        assert_eq!(json!({"SecretIdList": [TEST_ARN]}), req);
+    }
+
+    #[tokio::test]
+    async fn test_signed_user_agent() {
+        let body = json!({
+            "Errors": [],
+            "SecretValues": [{
+                "ARN": TEST_ARN,
+                "CreatedDate": 1.7e9,
+                "Name": "test",
+                "SecretString": "hunter2",
+                "VersionId": "v1",
+            }],
+        })
+        .to_string();
+        let (client, stub) = stub_client(vec![(200, &body)]);
+
+        let arns = vec![TEST_ARN.parse::<AwsArn>().unwrap()];
+        client
+            .secrets_manager()
+            .batch_get_secret(&arns)
+            .await
+            .unwrap();
+
+        let headers = stub.headers.lock().unwrap();
+        assert_eq!(
+            crate::util::http::DEFAULT_USER_AGENT,
+            headers[0]
+                .get(http::header::USER_AGENT)
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        let auth = headers[0]
+            .get(http::header::AUTHORIZATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let signed = auth
+            .split("SignedHeaders=")
+            .nth(1)
+            .and_then(|s| s.split(',').next())
+            .unwrap();
+        assert!(signed.split(';').any(|h| h == "user-agent"), "{}", auth);
+    }
+
+    #[tokio::test]
+    async fn test_batch_get_secret_partial_errors() {
+        let missing = "arn:aws:secretsmanager:us-east-1:123456789012:secret:missing-abc123";
+        let body = json!({
+            "Errors": [{
+                "ErrorCode": "ResourceNotFoundException",
+                "Message": "Secrets Manager can't find the specified secret.",
+                "SecretId": missing,
+            }],
+            "SecretValues": [{
+                "ARN": TEST_ARN,
+                "CreatedDate": 1.7e9,
+                "Name": "test",
+                "SecretString": "hunter2",
+                "VersionId": "v1",
+            }],
+        })
+        .to_string();
+        let (client, _) = stub_client(vec![(200, &body)]);
+
+        let arns = vec![
+            TEST_ARN.parse::<AwsArn>().unwrap(),
+            missing.parse::<AwsArn>().unwrap(),
+        ];
+        match client.secrets_manager().batch_get_secret(&arns).await {
+            Err(Error::InvalidSecrets(ids)) => assert_eq!(vec![missing.to_string()], ids),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_get_secret_aws_error() {
+        let body = r#"{"__type":"AccessDeniedException","Message":"not authorized"}"#;
+        let (client, stub) = stub_client(vec![(400, body)]);
+
+        let arns = vec![TEST_ARN.parse::<AwsArn>().unwrap()];
+        match client.secrets_manager().batch_get_secret(&arns).await {
+            Err(Error::AwsError { code, message }) => {
+                assert_eq!("400", code);
+                assert_eq!(body, message);
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // Client errors are not retried
+        assert_eq!(1, stub.requests.lock().unwrap().len());
+    }
+
     #[tokio::test]
     async fn test_basic_secret_retrieval() {
         // TEST_SECRETSMANAGER_ARNS should be set to a comma-separated list of k=v pairs,
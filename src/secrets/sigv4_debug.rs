@@ -0,0 +1,158 @@
+use http::header::{AUTHORIZATION, HOST};
+use http::request::Parts;
+use sha2::{Digest, Sha256};
+
+const SIGV4_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const SECURITY_TOKEN_HEADER: &str = "x-amz-security-token";
+const REDACTED: &str = "<redacted>";
+
+/// Rebuilds the SigV4 canonical request and string-to-sign of a signed request, so they can
+/// be compared against the ones AWS reports in a signature mismatch error. The session token
+/// is redacted, the secret key is never part of the request. Returns None when the request
+/// does not carry a SigV4 Authorization header.
+pub(crate) fn dump_signing(parts: &Parts, payload: &[u8]) -> Option<String> {
+    let auth = parts.headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let auth = auth.strip_prefix(SIGV4_ALGORITHM)?.trim();
+
+    let mut credential = None;
+    let mut signed_headers = None;
+    for field in auth.split(',') {
+        let field = field.trim();
+        if let Some(v) = field.strip_prefix("Credential=") {
+            credential = Some(v);
+        } else if let Some(v) = field.strip_prefix("SignedHeaders=") {
+            signed_headers = Some(v);
+        }
+    }
+    let credential = credential?;
+    let signed_headers = signed_headers?;
+    // The credential is <access key>/<scope>, only the scope is signed
+    let (_, scope) = credential.split_once('/')?;
+
+    let amz_date = header_value(parts, "x-amz-date").unwrap_or_default();
+    let payload_hash = header_value(parts, "x-amz-content-sha256")
+        .unwrap_or_else(|| hex::encode(Sha256::digest(payload)));
+
+    let mut headers = String::new();
+    let mut redacted_headers = String::new();
+    for name in signed_headers.split(';') {
+        let value = match name {
+            "host" => header_value(parts, HOST.as_str())
+                .or_else(|| parts.uri.authority().map(|a| a.to_string()))
+                .unwrap_or_default(),
+            _ => header_value(parts, name).unwrap_or_default(),
+        };
+        headers.push_str(&format!("{}:{}\n", name, value));
+
+        let value = if name == SECURITY_TOKEN_HEADER {
+            REDACTED.to_string()
+        } else {
+            value
+        };
+        redacted_headers.push_str(&format!("{}:{}\n", name, value));
+    }
+
+    let canonical = |headers: &str| {
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            parts.method,
+            canonical_path(parts),
+            canonical_query(parts),
+            headers,
+            signed_headers,
+            payload_hash
+        )
+    };
+
+    // The hash is over the real canonical request, only the printed copy is redacted
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        SIGV4_ALGORITHM,
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical(&headers).as_bytes()))
+    );
+
+    Some(format!(
+        "Credential: {}\n\nCanonicalRequest:\n{}\n\nStringToSign:\n{}",
+        credential,
+        canonical(&redacted_headers),
+        string_to_sign
+    ))
+}
+
+// Whitespace is trimmed and runs of spaces collapsed, as in the signed form
+fn header_value(parts: &Parts, name: &str) -> Option<String> {
+    let value = parts.headers.get(name)?.to_str().ok()?;
+    Some(value.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+fn canonical_path(parts: &Parts) -> &str {
+    match parts.uri.path() {
+        "" => "/",
+        p => p,
+    }
+}
+
+fn canonical_query(parts: &Parts) -> String {
+    let mut pairs: Vec<&str> = parts
+        .uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .collect();
+    pairs.sort();
+    pairs.join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Request;
+
+    #[test]
+    fn test_dump_signing() {
+        let (parts, _) = Request::post("https://ssm.us-east-1.amazonaws.com/")
+            .header("host", "ssm.us-east-1.amazonaws.com")
+            .header("x-amz-date", "20241231T235959Z")
+            .header("x-amz-security-token", "session-token-value")
+            .header("x-amz-target", "AmazonSSM.GetParameters")
+            .header(
+                AUTHORIZATION,
+                "AWS4-HMAC-SHA256 \
+                 Credential=AKIDEXAMPLE/20241231/us-east-1/ssm/aws4_request, \
+                 SignedHeaders=host;x-amz-date;x-amz-security-token;x-amz-target, \
+                 Signature=abcdef",
+            )
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let dump = dump_signing(&parts, b"{}").unwrap();
+
+        let body_hash = hex::encode(Sha256::digest(b"{}"));
+        let canonical = format!(
+            "POST\n/\n\n\
+             host:ssm.us-east-1.amazonaws.com\n\
+             x-amz-date:20241231T235959Z\n\
+             x-amz-security-token:<redacted>\n\
+             x-amz-target:AmazonSSM.GetParameters\n\n\
+             host;x-amz-date;x-amz-security-token;x-amz-target\n{}",
+            body_hash
+        );
+        assert!(dump.contains(&canonical), "{}", dump);
+        assert!(dump.contains("Credential: AKIDEXAMPLE/20241231/us-east-1/ssm/aws4_request"));
+        assert!(dump.contains(
+            "StringToSign:\nAWS4-HMAC-SHA256\n20241231T235959Z\n20241231/us-east-1/ssm/aws4_request\n"
+        ));
+        assert!(!dump.contains("session-token-value"));
+
+        // Unsigned requests have nothing to dump
+        let (parts, _) = Request::post("https://ssm.us-east-1.amazonaws.com/")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert!(dump_signing(&parts, b"{}").is_none());
+    }
+}
@@ -0,0 +1,338 @@
+use crate::secrets::STS_SERVICE;
+use crate::secrets::client::AwsClient;
+use crate::secrets::error::Error;
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{HeaderValue, Method, Uri};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use rotel::aws_api::auth::{AwsRequestSigner, SystemClock};
+use rotel::aws_api::creds::AwsCreds;
+use serde::Deserialize;
+
+const STS_API_VERSION: &str = "2011-06-15";
+
+/// Session name of the assumed role, shown in the CloudTrail events of the secret lookups
+pub const ROLE_SESSION_NAME: &str = "rotel-lambda-extension";
+
+// Assumed credentials are refreshed this long before they expire, so a lookup never signs
+// with credentials that expire in flight
+const EXPIRY_MARGIN: TimeDelta = TimeDelta::minutes(5);
+
+#[derive(Debug, Deserialize)]
+struct AssumeRoleEnvelope {
+    #[serde(rename = "AssumeRoleResponse")]
+    response: AssumeRoleResponse,
+}
+
+#[derive(Debug, Deserialize)]
+struct AssumeRoleResponse {
+    #[serde(rename = "AssumeRoleResult")]
+    result: AssumeRoleResult,
+}
+
+#[derive(Debug, Deserialize)]
+struct AssumeRoleResult {
+    #[serde(rename = "Credentials")]
+    credentials: Credentials,
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: String,
+
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: String,
+
+    #[serde(rename = "SessionToken")]
+    session_token: String,
+
+    #[serde(rename = "Expiration")]
+    expiration: Expiration,
+}
+
+// The JSON protocol returns the expiration as epoch seconds, accept a timestamp string too
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Expiration {
+    EpochSeconds(f64),
+    Timestamp(String),
+}
+
+impl Expiration {
+    fn to_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Expiration::EpochSeconds(secs) => {
+                DateTime::from_timestamp_millis((secs * 1000.0) as i64)
+            }
+            Expiration::Timestamp(ts) => DateTime::parse_from_rfc3339(ts)
+                .ok()
+                .map(|ts| ts.with_timezone(&Utc)),
+        }
+    }
+}
+
+/// Temporary credentials returned by AssumeRole
+#[derive(Clone)]
+pub struct AssumedCredentials {
+    pub creds: AwsCreds,
+    pub expiration: DateTime<Utc>,
+}
+
+impl AssumedCredentials {
+    /// Whether the credentials expire within the refresh margin of `now`
+    pub fn is_expiring(&self, now: DateTime<Utc>) -> bool {
+        now + EXPIRY_MARGIN >= self.expiration
+    }
+}
+
+pub struct Sts<'a> {
+    client: &'a AwsClient,
+}
+
+impl<'a> Sts<'a> {
+    pub(crate) fn new(client: &'a AwsClient) -> Self {
+        Self { client }
+    }
+
+    /// Assume the role with the client's base credentials, calling the regional STS
+    /// endpoint of `region`
+    pub async fn assume_role(
+        &self,
+        role_arn: &str,
+        region: &str,
+    ) -> Result<AssumedCredentials, Error> {
+        let endpoint = self
+            .client
+            .region_endpoint(STS_SERVICE, region)
+            .parse::<Uri>()?;
+
+        let payload_bytes = Bytes::from(assume_role_body(role_arn, ROLE_SESSION_NAME));
+
+        let mut hdrs = self.client.signed_headers();
+        hdrs.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded; charset=utf-8"),
+        );
+        // STS responds with XML unless JSON is requested
+        hdrs.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+        let signer = AwsRequestSigner::new(STS_SERVICE, region, SystemClock);
+        let signed_request = signer.sign(
+            endpoint,
+            Method::POST,
+            hdrs,
+            payload_bytes,
+            &self.client.creds,
+        )?;
+
+        let response = self.client.perform(signed_request).await?;
+
+        parse_assume_role_response(&response)
+    }
+}
+
+fn assume_role_body(role_arn: &str, session_name: &str) -> String {
+    format!(
+        "Action=AssumeRole&RoleArn={}&RoleSessionName={}&Version={}",
+        utf8_percent_encode(role_arn, NON_ALPHANUMERIC),
+        utf8_percent_encode(session_name, NON_ALPHANUMERIC),
+        STS_API_VERSION
+    )
+}
+
+fn parse_credentials(body: &[u8]) -> Result<Credentials, Error> {
+    let envelope: AssumeRoleEnvelope = serde_json::from_slice(body)?;
+    Ok(envelope.response.result.credentials)
+}
+
+fn parse_assume_role_response(body: &[u8]) -> Result<AssumedCredentials, Error> {
+    let creds = parse_credentials(body)?;
+
+    let expiration = creds.expiration.to_datetime().ok_or_else(|| {
+        Error::HttpResponseErrorParse("invalid AssumeRole credential expiration".into())
+    })?;
+
+    Ok(AssumedCredentials {
+        creds: AwsCreds::new(
+            creds.access_key_id,
+            creds.secret_access_key,
+            Some(creds.session_token),
+        ),
+        expiration,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::client::StubTransport;
+    use crate::test_util::test_creds;
+    use http::header::AUTHORIZATION;
+    use rotel::aws_api::arn::AwsArn;
+    use std::sync::Arc;
+
+    const ROLE_ARN: &str = "arn:aws:iam::210987654321:role/secrets-reader";
+
+    fn assume_role_response(access_key_id: &str, expiration: DateTime<Utc>) -> String {
+        serde_json::json!({
+            "AssumeRoleResponse": {
+                "AssumeRoleResult": {
+                    "AssumedRoleUser": {
+                        "Arn": "arn:aws:sts::210987654321:assumed-role/secrets-reader/rotel-lambda-extension",
+                        "AssumedRoleId": "AROAEXAMPLE:rotel-lambda-extension",
+                    },
+                    "Credentials": {
+                        "AccessKeyId": access_key_id,
+                        "SecretAccessKey": "assumed-secret",
+                        "SessionToken": "assumed-token",
+                        "Expiration": expiration.timestamp() as f64,
+                    },
+                },
+                "ResponseMetadata": {"RequestId": "c6104cbe-af31-11e0-8154-cbc7ccf896c7"},
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_assume_role_body() {
+        assert_eq!(
+            "Action=AssumeRole&RoleArn=arn%3Aaws%3Aiam%3A%3A210987654321%3Arole%2Fsecrets%2Dreader&RoleSessionName=rotel%2Dlambda%2Dextension&Version=2011-06-15",
+            assume_role_body(ROLE_ARN, ROLE_SESSION_NAME)
+        );
+    }
+
+    #[test]
+    fn test_parse_assume_role_response() {
+        let expiration = DateTime::from_timestamp(1_767_225_600, 0).unwrap();
+        let body = assume_role_response("ASIAEXAMPLE", expiration);
+
+        let creds = parse_credentials(body.as_bytes()).unwrap();
+        assert_eq!("ASIAEXAMPLE", creds.access_key_id);
+        assert_eq!("assumed-secret", creds.secret_access_key);
+        assert_eq!("assumed-token", creds.session_token);
+
+        let assumed = parse_assume_role_response(body.as_bytes()).unwrap();
+        assert_eq!(expiration, assumed.expiration);
+
+        assert!(!assumed.is_expiring(expiration - TimeDelta::minutes(6)));
+        assert!(assumed.is_expiring(expiration - TimeDelta::minutes(4)));
+
+        // Timestamp formatted expirations are accepted as well
+        let body = body.replace("1767225600.0", "\"2026-01-01T00:00:00Z\"");
+        let assumed = parse_assume_role_response(body.as_bytes()).unwrap();
+        assert_eq!(expiration, assumed.expiration);
+
+        assert!(parse_assume_role_response(br#"{"AssumeRoleResponse":{}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_assume_role_request() {
+        let expiration = Utc::now() + TimeDelta::hours(1);
+        let body = assume_role_response("ASIAEXAMPLE", expiration);
+        let stub = Arc::new(StubTransport::new(vec![(200, &body)]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+
+        let assumed = client
+            .sts()
+            .assume_role(ROLE_ARN, "eu-west-1")
+            .await
+            .unwrap();
+        assert_eq!(expiration.timestamp(), assumed.expiration.timestamp());
+
+        assert_eq!(
+            vec!["https://sts.eu-west-1.amazonaws.com/".to_string()],
+            *stub.uris.lock().unwrap()
+        );
+        let requests = stub.requests.lock().unwrap();
+        assert_eq!(
+            assume_role_body(ROLE_ARN, ROLE_SESSION_NAME).as_bytes(),
+            requests[0].as_ref()
+        );
+
+        // Signed for STS with the base credentials
+        let headers = stub.headers.lock().unwrap();
+        let auth = headers[0].get(AUTHORIZATION).unwrap().to_str().unwrap();
+        let base_key = std::env::var("AWS_ACCESS_KEY_ID").unwrap();
+        assert!(auth.contains(&format!("Credential={}/", base_key)));
+        assert!(auth.contains("/eu-west-1/sts/aws4_request"));
+        assert_eq!("application/json", headers[0].get(ACCEPT).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_assumed_credentials_sign_lookups() {
+        let param_arn = "arn:aws:ssm:us-east-1:210987654321:parameter/test";
+        let params = serde_json::json!({
+            "InvalidParameters": [],
+            "Parameters": [{"ARN": param_arn, "Name": "test", "Type": "String", "Value": "v"}],
+        })
+        .to_string();
+        let first = assume_role_response("ASIAFIRST", Utc::now() + TimeDelta::hours(1));
+        let refreshed = assume_role_response("ASIASECOND", Utc::now() + TimeDelta::hours(1));
+        let stub = Arc::new(StubTransport::new(vec![
+            (200, &first),
+            (200, &params),
+            (200, &refreshed),
+            (200, &params),
+            (200, &params),
+        ]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()))
+            .with_assume_role(ROLE_ARN);
+
+        let arns = vec![param_arn.parse::<AwsArn>().unwrap()];
+        client
+            .parameter_store()
+            .get_parameters(&arns)
+            .await
+            .unwrap();
+
+        // Expire the cached credentials within the refresh margin
+        client.assumed.lock().unwrap().as_mut().unwrap().expiration =
+            Utc::now() + TimeDelta::minutes(1);
+        client
+            .parameter_store()
+            .get_parameters(&arns)
+            .await
+            .unwrap();
+        // Fresh credentials are reused without another AssumeRole call
+        client
+            .parameter_store()
+            .get_parameters(&arns)
+            .await
+            .unwrap();
+
+        let uris = stub.uris.lock().unwrap();
+        assert_eq!(5, uris.len());
+        assert!(uris[0].starts_with("https://sts."));
+        assert!(uris[2].starts_with("https://sts."));
+
+        let headers = stub.headers.lock().unwrap();
+        let credential = |i: usize| {
+            headers[i]
+                .get(AUTHORIZATION)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        assert!(credential(1).contains("Credential=ASIAFIRST/"));
+        assert!(credential(3).contains("Credential=ASIASECOND/"));
+        assert!(credential(4).contains("Credential=ASIASECOND/"));
+        assert_eq!(
+            "assumed-token",
+            headers[1].get("x-amz-security-token").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_base_credentials_without_role() {
+        let stub = Arc::new(StubTransport::new(vec![]));
+        let client = AwsClient::with_transport(test_creds(), Box::new(stub.clone()));
+
+        client.signing_creds().await.unwrap();
+        // No role is assumed, so STS is never called
+        assert!(stub.uris.lock().unwrap().is_empty());
+    }
+}
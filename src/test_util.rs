@@ -1,4 +1,6 @@
-use std::sync::Once;
+use rotel::aws_api::creds::AwsCreds;
+use std::ffi::OsString;
+use std::sync::{Mutex, MutexGuard, Once};
 
 static INIT_CRYPTO: Once = Once::new();
 pub fn init_crypto() {
@@ -23,3 +25,51 @@ pub fn parse_test_arns(test_arns: String) -> Vec<(String, String)> {
         })
         .collect()
 }
+
+static INIT_CREDS: Once = Once::new();
+/// Credentials for signing requests that never reach AWS, uses the environment when set
+pub fn test_creds() -> AwsCreds {
+    INIT_CREDS.call_once(|| {
+        if std::env::var("AWS_ACCESS_KEY_ID").is_err() {
+            unsafe { std::env::set_var("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE") }
+            unsafe {
+                std::env::set_var(
+                    "AWS_SECRET_ACCESS_KEY",
+                    "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                )
+            }
+        }
+    });
+    AwsCreds::from_env()
+}
+
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+/// Held by tests that change the process environment, so they do not observe each other's
+/// variables. A test that failed while holding it does not poison it for the rest.
+pub fn env_lock() -> MutexGuard<'static, ()> {
+    ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sets an environment variable, restoring its previous value when dropped so it does not
+/// leak into later tests, even when an assertion fails
+pub struct ScopedEnv {
+    name: &'static str,
+    prev: Option<OsString>,
+}
+
+impl ScopedEnv {
+    pub fn set(name: &'static str, value: &str) -> Self {
+        let prev = std::env::var_os(name);
+        unsafe { std::env::set_var(name, value) }
+        Self { name, prev }
+    }
+}
+
+impl Drop for ScopedEnv {
+    fn drop(&mut self) {
+        match &self.prev {
+            Some(prev) => unsafe { std::env::set_var(self.name, prev) },
+            None => unsafe { std::env::remove_var(self.name) },
+        }
+    }
+}
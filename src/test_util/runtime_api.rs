@@ -0,0 +1,241 @@
+// Mock of the Lambda Runtime API endpoints the extension calls: register, next event and
+// telemetry subscribe. It also accepts OTLP/HTTP exports, so a test can point the agent's
+// exporter at it and see when telemetry is flushed.
+//
+// This is compiled into the binary's tests, which can not reach the library's test modules.
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+
+const REGISTER_PATH: &str = "/2020-01-01/extension/register";
+const NEXT_PATH: &str = "/2020-01-01/extension/event/next";
+const TELEMETRY_PATH: &str = "/2022-07-01/telemetry";
+
+// Time the extension is given to handle each event
+const EVENT_DEADLINE_MILLIS: u64 = 3_000;
+
+/// Events returned from the next endpoint, in order. Once they run out, next requests block
+/// as they do while Lambda has the sandbox frozen.
+pub enum MockEvent {
+    /// An invocation with the given request id. Once it is delivered, the platform.start,
+    /// function log and platform.runtimeDone records are posted to the subscribed destination.
+    Invoke(String),
+    Shutdown,
+}
+
+/// Requests the mock has received, in the order they arrived
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockCall {
+    Register,
+    Subscribe,
+    Next,
+    Telemetry,
+    /// An OTLP/HTTP export to the given path
+    Export(String),
+}
+
+struct MockState {
+    events: VecDeque<MockEvent>,
+    destination: Option<String>,
+    calls: Vec<MockCall>,
+    subscribe_fails: bool,
+}
+
+pub struct MockRuntimeApi {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockRuntimeApi {
+    pub async fn start(events: Vec<MockEvent>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(MockState {
+            events: events.into(),
+            destination: None,
+            calls: vec![],
+            subscribe_fails: false,
+        }));
+
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let svc = service_fn(move |req| handle(state.clone(), req));
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), svc)
+                        .await;
+                });
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    /// Address to use as AWS_LAMBDA_RUNTIME_API, also serves the OTLP/HTTP endpoints
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Reject telemetry subscriptions, as Lambda does when the subscription is invalid
+    pub fn fail_subscribe(&self) {
+        self.state.lock().unwrap().subscribe_fails = true;
+    }
+
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<MockState>>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map(|b| b.to_bytes())
+        .unwrap_or_default();
+
+    let resp = match (parts.method, parts.uri.path()) {
+        (Method::POST, REGISTER_PATH) => {
+            record(&state, MockCall::Register);
+            Response::builder()
+                .header("Lambda-Extension-Identifier", "mock-extension-id")
+                .body(Full::from(
+                    r#"{"functionName":"mock-function","functionVersion":"$LATEST","handler":"index.handler"}"#,
+                ))
+        }
+        (Method::PUT, TELEMETRY_PATH) => {
+            let sub: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+            let mut s = state.lock().unwrap();
+            s.calls.push(MockCall::Subscribe);
+            if s.subscribe_fails {
+                Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Full::from(
+                        r#"{"errorMessage":"Invalid subscription","errorType":"ValidationError"}"#,
+                    ))
+            } else {
+                s.destination = sub["destination"]["URI"].as_str().map(String::from);
+                Response::builder().body(Full::from("OK"))
+            }
+        }
+        (Method::GET, NEXT_PATH) => {
+            let (event, destination) = {
+                let mut s = state.lock().unwrap();
+                s.calls.push(MockCall::Next);
+                (s.events.pop_front(), s.destination.clone())
+            };
+
+            let body = match event {
+                Some(MockEvent::Invoke(request_id)) => {
+                    if let Some(destination) = destination {
+                        tokio::spawn(post_telemetry(
+                            state.clone(),
+                            destination,
+                            invocation_telemetry(&request_id),
+                        ));
+                    }
+                    serde_json::json!({
+                        "eventType": "INVOKE",
+                        "deadlineMs": deadline_ms(),
+                        "requestId": request_id,
+                        "invokedFunctionArn": "arn:aws:lambda:us-east-1:123456789012:function:mock-function",
+                        "tracing": {
+                            "type": "X-Amzn-Trace-Id",
+                            "value": "Root=1-5f35ae12-0c0fec141ab77a00bc047aa2"
+                        }
+                    })
+                }
+                Some(MockEvent::Shutdown) => serde_json::json!({
+                    "eventType": "SHUTDOWN",
+                    "shutdownReason": "spindown",
+                    "deadlineMs": deadline_ms(),
+                }),
+                None => std::future::pending().await,
+            };
+            Response::builder().body(Full::from(body.to_string()))
+        }
+        (Method::POST, path) if path.starts_with("/v1/") => {
+            record(&state, MockCall::Export(path.to_string()));
+            Response::builder()
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .body(Full::default())
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::default()),
+    };
+
+    Ok(resp.unwrap())
+}
+
+// Lambda delivers to sandbox.localdomain, which only resolves inside the sandbox
+async fn post_telemetry(
+    state: Arc<Mutex<MockState>>,
+    destination: String,
+    telemetry: serde_json::Value,
+) {
+    record(&state, MockCall::Telemetry);
+
+    let uri = destination.replace("sandbox.localdomain", "127.0.0.1");
+    let req = Request::post(uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(telemetry.to_string()))
+        .unwrap();
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+    let resp = client.request(req).await.unwrap();
+    assert_eq!(StatusCode::OK, resp.status());
+}
+
+fn invocation_telemetry(request_id: &str) -> serde_json::Value {
+    serde_json::json!([
+        {
+            "time": "2022-10-12T00:00:00.000Z",
+            "type": "platform.start",
+            "record": {"requestId": request_id, "version": "$LATEST"}
+        },
+        {
+            "time": "2022-10-12T00:00:00.100Z",
+            "type": "function",
+            "record": "hello from the function"
+        },
+        {
+            "time": "2022-10-12T00:00:00.200Z",
+            "type": "platform.runtimeDone",
+            "record": {
+                "requestId": request_id,
+                "status": "success",
+                "metrics": {"durationMs": 200.0}
+            }
+        }
+    ])
+}
+
+fn record(state: &Arc<Mutex<MockState>>, call: MockCall) {
+    state.lock().unwrap().calls.push(call);
+}
+
+fn deadline_ms() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    now + EVENT_DEADLINE_MILLIS
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// Parse a duration option such as `500ms`, `2s`, `1m` or `1h`. A bare number is taken as
+/// milliseconds, so the `ROTEL_*_MS` options keep accepting plain values.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+
+    let value = value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration '{}', expected a number and unit", s))?;
+
+    match unit {
+        "" | "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value.saturating_mul(60))),
+        "h" => Ok(Duration::from_secs(value.saturating_mul(60 * 60))),
+        _ => Err(format!(
+            "invalid duration unit '{}' in '{}', expected ms, s, m or h",
+            unit, s
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Ok(Duration::from_millis(500)), parse_duration("500ms"));
+        assert_eq!(Ok(Duration::from_secs(2)), parse_duration("2s"));
+        assert_eq!(Ok(Duration::from_secs(60)), parse_duration("1m"));
+        assert_eq!(Ok(Duration::from_secs(3600)), parse_duration("1h"));
+        assert_eq!(Ok(Duration::from_millis(250)), parse_duration("250"));
+        assert_eq!(Ok(Duration::ZERO), parse_duration("0s"));
+
+        let err = parse_duration("2x").unwrap_err();
+        assert!(err.contains("invalid duration unit 'x'"), "{}", err);
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("1.5s").is_err());
+    }
+}
@@ -1,7 +1,18 @@
+use http::HeaderValue;
 use http_body_util::BodyExt;
 use hyper::body::Incoming;
 use tower::BoxError;
 
+pub const DEFAULT_USER_AGENT: &str = concat!("rotel-lambda-extension/", env!("CARGO_PKG_VERSION"));
+
+/// Parse the User-Agent sent on Runtime API and AWS requests, a blank value keeps the default
+pub fn parse_user_agent(value: &str) -> Result<HeaderValue, String> {
+    if value.trim().is_empty() {
+        return Ok(HeaderValue::from_static(DEFAULT_USER_AGENT));
+    }
+    HeaderValue::try_from(value).map_err(|e| format!("invalid user agent: {}", e))
+}
+
 pub async fn response_string(body: Incoming) -> Result<String, BoxError> {
     Ok(body
         .collect()
@@ -0,0 +1,157 @@
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// Wraps a connection's stream to record when it was last read from or written to
+pub struct IdleIo<S> {
+    inner: S,
+    tracker: IdleTracker,
+}
+
+impl<S> IdleIo<S> {
+    pub fn new(inner: S) -> (Self, IdleTracker) {
+        let tracker = IdleTracker::new();
+        (
+            Self {
+                inner,
+                tracker: tracker.clone(),
+            },
+            tracker,
+        )
+    }
+}
+
+/// Activity of a connection. A connection is busy from the time a request is read until
+/// its response is written, and idle otherwise.
+#[derive(Clone)]
+pub struct IdleTracker {
+    state: Arc<Mutex<IdleState>>,
+}
+
+struct IdleState {
+    last_activity: Instant,
+    busy: bool,
+}
+
+impl IdleTracker {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(IdleState {
+                last_activity: Instant::now(),
+                busy: false,
+            })),
+        }
+    }
+
+    fn record(&self, busy: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.last_activity = Instant::now();
+        state.busy = busy;
+    }
+
+    /// Resolves once the connection has gone `timeout` without a request in progress
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline = {
+                let state = self.state.lock().unwrap();
+                match state.busy {
+                    true => Instant::now() + timeout,
+                    false => state.last_activity + timeout,
+                }
+            };
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+
+            let state = self.state.lock().unwrap();
+            if !state.busy && state.last_activity + timeout <= Instant::now() {
+                return;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleIo<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            if buf.filled().len() > filled {
+                self.tracker.record(true);
+            }
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleIo<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = res {
+            self.tracker.record(false);
+        }
+        res
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(_)) = res {
+            self.tracker.record(false);
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_tracker() {
+        let tracker = IdleTracker::new();
+        let timeout = Duration::from_millis(50);
+
+        // A request in progress keeps the connection from going idle
+        tracker.record(true);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), tracker.idle(timeout))
+                .await
+                .is_err()
+        );
+
+        // Once the response is written, it is idle after the timeout
+        tracker.record(false);
+        let start = Instant::now();
+        tracker.idle(timeout).await;
+        assert!(start.elapsed() >= timeout);
+    }
+}
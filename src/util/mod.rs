@@ -1 +1,4 @@
+pub mod duration;
 pub mod http;
+pub mod idle;
+pub mod retry;
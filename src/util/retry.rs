@@ -0,0 +1,164 @@
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+/// Controls how many times, and how quickly, a failed operation is retried. Delays grow
+/// exponentially from `base_delay` and are capped at `max_delay`.
+pub struct RetryPolicy<E> {
+    /// Total attempts, including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay, between 0.0 and 1.0, that may be randomly subtracted
+    pub jitter: f64,
+    /// Whether an error should be retried, non-retryable errors are returned immediately
+    pub retryable: fn(&E) -> bool,
+}
+
+impl<E> RetryPolicy<E> {
+    /// Delay to wait after the given failed attempt, starting at zero
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+
+        delay.mul_f64(1.0 - jitter * random_fraction())
+    }
+}
+
+/// Run `f` until it succeeds, returns a non-retryable error or runs out of attempts.
+pub async fn retry<T, E, F, Fut>(policy: &RetryPolicy<E>, mut f: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                if attempt + 1 >= policy.max_attempts || !(policy.retryable)(&e) {
+                    return Err(e);
+                }
+
+                let delay = policy.backoff(attempt);
+                debug!(attempt, ?delay, "Retrying failed request");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// A random delay between zero and `max`, used to spread out requests from instances that
+/// start at the same time
+pub fn random_delay(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+
+    max.mul_f64(random_fraction())
+}
+
+// Good enough for spreading out retries, avoids pulling in a rand dependency
+fn random_fraction() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    (nanos % 1_000_000) as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy(max_attempts: u32) -> RetryPolicy<&'static str> {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+            retryable: |e| *e == "retry",
+        }
+    }
+
+    #[test]
+    fn test_backoff_sequence() {
+        let p = policy(10);
+        let delays: Vec<u64> = (0..6).map(|i| p.backoff(i).as_millis() as u64).collect();
+        assert_eq!(vec![10, 20, 40, 50, 50, 50], delays);
+
+        // Large attempt counts must not overflow
+        assert_eq!(Duration::from_millis(50), p.backoff(100));
+    }
+
+    #[test]
+    fn test_backoff_jitter_bounds() {
+        let mut p = policy(10);
+        p.jitter = 0.5;
+        for i in 0..5 {
+            let d = p.backoff(i);
+            let max = policy(10).backoff(i);
+            assert!(d <= max);
+            assert!(d >= max / 2);
+        }
+    }
+
+    #[test]
+    fn test_random_delay_bounds() {
+        let max = Duration::from_millis(200);
+        for _ in 0..100 {
+            assert!(random_delay(max) <= max);
+        }
+
+        assert_eq!(Duration::ZERO, random_delay(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let calls = Cell::new(0);
+        let res = retry(&policy(3), || {
+            calls.set(calls.get() + 1);
+            let n = calls.get();
+            async move { if n < 3 { Err("retry") } else { Ok(n) } }
+        })
+        .await;
+
+        assert_eq!(Ok(3), res);
+        assert_eq!(3, calls.get());
+    }
+
+    #[tokio::test]
+    async fn test_retry_exhausts_attempts() {
+        let calls = Cell::new(0);
+        let res: Result<(), _> = retry(&policy(3), || {
+            calls.set(calls.get() + 1);
+            async { Err("retry") }
+        })
+        .await;
+
+        assert_eq!(Err("retry"), res);
+        assert_eq!(3, calls.get());
+    }
+
+    #[tokio::test]
+    async fn test_retry_skips_non_retryable() {
+        let calls = Cell::new(0);
+        let res: Result<(), _> = retry(&policy(3), || {
+            calls.set(calls.get() + 1);
+            async { Err("fatal") }
+        })
+        .await;
+
+        assert_eq!(Err("fatal"), res);
+        assert_eq!(1, calls.get());
+    }
+}